@@ -1,7 +1,17 @@
-use moving::clipboard::{self, mime};
+use moving::{
+    clipboard::{self, mime},
+    event_loop::EventLoop,
+};
 
 fn main() {
-    if let Some(paste) = clipboard::load(mime::TEXT_PLAIN).expect("Failed to paste") {
+    let event_loop = match EventLoop::new() {
+        Ok(event_loop) => event_loop,
+        Err(e) => {
+            eprintln!("Failed to connect to the display server: {}", e);
+            return;
+        }
+    };
+    if let Some(paste) = clipboard::load(&event_loop, mime::TEXT_PLAIN).expect("Failed to paste") {
         println!("Clipboard: {}", String::from_utf8_lossy(&paste));
     } else {
         println!("The clipboard is empty");
@@ -6,25 +6,27 @@ use moving::{
 use std::time::Instant;
 
 fn main() {
-    let event_loop = EventLoop::new();
+    let event_loop = EventLoop::new().unwrap();
     let window = WindowBuilder::new()
         .with_title("H-Tree")
         .build(&event_loop)
         .unwrap();
     let start = Instant::now();
-    event_loop.run(move |event, control_flow| {
-        *control_flow = ControlFlow::Wait;
+    event_loop
+        .run(move |event, control_flow| {
+            *control_flow = ControlFlow::Wait;
 
-        let event = match event {
-            Event::WindowEvent { event, .. } => event,
-            _ => {
-                return;
+            let event = match event {
+                Event::WindowEvent { event, .. } => event,
+                _ => {
+                    return;
+                }
+            };
+            match event {
+                WindowEvent::CloseRequested => *control_flow = ControlFlow::Exit,
+                WindowEvent::MouseMove { x, y } => {}
+                _ => {}
             }
-        };
-        match event {
-            WindowEvent::CloseRequested => *control_flow = ControlFlow::Exit,
-            WindowEvent::MouseMove { x, y } => {}
-            _ => {}
-        }
-    });
+        })
+        .expect("The event loop failed");
 }
@@ -229,7 +229,7 @@ impl Maze {
 }
 
 fn main() {
-    let event_loop = EventLoop::new();
+    let event_loop = EventLoop::new().unwrap();
     let window = WindowBuilder::new()
         .with_title("Maze(Moving example)")
         .build(&event_loop)
@@ -243,92 +243,94 @@ fn main() {
     let mut random = Random::new();
     let mut traveling_path = Vec::new();
     let mut traveling_path_cells = HashSet::new();
-    event_loop.run(move |event, control_flow| {
-        *control_flow = ControlFlow::Poll;
+    event_loop
+        .run(move |event, control_flow| {
+            *control_flow = ControlFlow::Poll;
 
-        match event {
-            Event::WindowEvent {
-                event: WindowEvent::CloseRequested,
-                ..
-            } => {
-                println!("The close button was pressed; stopping");
-                *control_flow = ControlFlow::Exit
-            }
-            Event::MainEventsCleared => {
-                if last_frame.elapsed() >= fps {
-                    last_frame = Instant::now();
-                } else {
-                    return;
+            match event {
+                Event::WindowEvent {
+                    event: WindowEvent::CloseRequested,
+                    ..
+                } => {
+                    println!("The close button was pressed; stopping");
+                    *control_flow = ControlFlow::Exit
                 }
-                let surface = window.surface();
-                let w = surface.width() as usize;
-                let h = surface.height() as usize;
-                let frame_buffer = window.frame_buffer();
-                //frame_buffer.iter_mut().for_each(|p| *p = 255);
-                let maze_cell = 1;
-                let maze_x = (w - (maze.cols() * maze_cell)) / 2;
-                let maze_y = (h - (maze.rows() * maze_cell)) / 2;
+                Event::MainEventsCleared => {
+                    if last_frame.elapsed() >= fps {
+                        last_frame = Instant::now();
+                    } else {
+                        return;
+                    }
+                    let surface = window.surface();
+                    let mut frame = surface.lock();
+                    let w = frame.width() as usize;
+                    let h = frame.height() as usize;
+                    let maze_cell = 1;
+                    let maze_x = (w - (maze.cols() * maze_cell)) / 2;
+                    let maze_y = (h - (maze.rows() * maze_cell)) / 2;
 
-                let maze_cols = maze.cols();
+                    let maze_cols = maze.cols();
 
-                let mut x = 0;
-                let mut y = 0;
-                for cell in maze.cells().copied() {
-                    let px = maze_x + x * maze_cell;
-                    let py = maze_y + y * maze_cell;
-                    if px + 30 < w {
-                        for y in py..py + maze_cell {
-                            if y >= h {
-                                break;
-                            }
-                            for x in px..px+maze_cell {
-                                let color;
-                                if cell {
-                                    color = 0xff000000;
-                                }
-                                else if traveling_path_cells.contains(&(x, y)) {
-                                    color = 0xff00ffff;
+                    let mut x = 0;
+                    let mut y = 0;
+                    for cell in maze.cells().copied() {
+                        let px = maze_x + x * maze_cell;
+                        let py = maze_y + y * maze_cell;
+                        if px + 30 < w {
+                            for y in py..py + maze_cell {
+                                if y >= h {
+                                    break;
                                 }
-                                else {
-                                    color = 0xffffffff;
+                                for x in px..px + maze_cell {
+                                    let color;
+                                    if cell {
+                                        color = 0xff000000;
+                                    } else if traveling_path_cells.contains(&(x, y)) {
+                                        color = 0xff00ffff;
+                                    } else {
+                                        color = 0xffffffff;
+                                    }
+                                    if frame.put_pixel(x as u32, y as u32, color).is_err() {
+                                        return;
+                                    }
                                 }
-                                surface.put_u32_pixel(x, y, color);
                             }
                         }
-                    }
-                    x += 1;
-                    if x >= maze_cols {
-                        x = 0;
-                        y += 1;
-                    }
-                }
-                if last_move.elapsed() >= Duration::from_secs(2) {
-                    last_move = Instant::now();
-                    let mut to_x = 0;
-                    let mut to_y = 0;
-                    // Work-around because of the maze bugs
-                    loop {
-                        to_x = random.range(2..maze.cols() - 1);
-                        to_y = random.range(2..maze.rows() - 1);
-                        if !maze.cell(to_x, to_y) {
-                            break;
+                        x += 1;
+                        if x >= maze_cols {
+                            x = 0;
+                            y += 1;
                         }
-                        if let Some(new_pos) = maze.moves(to_x, to_y).first().copied() {
-                            to_x = new_pos.0;
-                            to_y = new_pos.1;
+                    }
+                    if last_move.elapsed() >= Duration::from_secs(2) {
+                        last_move = Instant::now();
+                        let mut to_x = 0;
+                        let mut to_y = 0;
+                        // Work-around because of the maze bugs
+                        loop {
+                            to_x = random.range(2..maze.cols() - 1);
+                            to_y = random.range(2..maze.rows() - 1);
+                            if !maze.cell(to_x, to_y) {
+                                break;
+                            }
+                            if let Some(new_pos) = maze.moves(to_x, to_y).first().copied() {
+                                to_x = new_pos.0;
+                                to_y = new_pos.1;
+                            }
                         }
+                        traveling_path = maze.trace_path(traveler_location, (to_x, to_y));
+                        traveling_path_cells.clear();
+                        traveling_path_cells.reserve(traveling_path.len());
+                        traveling_path.iter().copied().for_each(|c| {
+                            traveling_path_cells.insert(c);
+                        });
+                        traveler_location = (to_x, to_y);
                     }
-                    traveling_path = maze.trace_path(traveler_location, (to_x, to_y));
-                    traveling_path_cells.clear();
-                    traveling_path_cells.reserve(traveling_path.len());
-                    traveling_path.iter().copied().for_each(|c| {
-                        traveling_path_cells.insert(c);
-                    });
-                    traveler_location = (to_x, to_y);
+                    drop(frame);
+                    window.redraw().expect("Failed to redraw");
                 }
-                window.redraw();
+                _ => (),
             }
-            _ => (),
-        }
-    });
+        })
+        .expect("The event loop failed");
 }
@@ -0,0 +1,63 @@
+//! Input synthesis for end-to-end tests, only supported on X11 through the XTEST extension
+//!
+//! The input is handled by the server like the one of a real device, so the resulting events
+//! are delivered to the windows by the event loop. Call [`sync`] before polling to be sure that
+//! the server has already processed it.
+
+use crate::{error::OSError, event::MouseButton, event_loop::EventLoop, window::Window};
+
+/// Moves the pointer to (x, y) in the coordinates of the screen
+pub fn move_pointer<T: 'static>(event_loop: &EventLoop<T>, x: i16, y: i16) -> Result<(), OSError> {
+    event_loop.connection().fake_pointer_motion(None, x, y)
+}
+
+/// Moves the pointer to (x, y) in the coordinates of `window`
+pub fn move_pointer_to(window: &Window, x: i16, y: i16) -> Result<(), OSError> {
+    window
+        .connection
+        .fake_pointer_motion(Some(window.id()), x, y)
+}
+
+pub fn press_button<T: 'static>(
+    event_loop: &EventLoop<T>,
+    button: MouseButton,
+) -> Result<(), OSError> {
+    event_loop.connection().fake_button(button, true)
+}
+
+pub fn release_button<T: 'static>(
+    event_loop: &EventLoop<T>,
+    button: MouseButton,
+) -> Result<(), OSError> {
+    event_loop.connection().fake_button(button, false)
+}
+
+/// Presses and releases `button`
+pub fn click<T: 'static>(event_loop: &EventLoop<T>, button: MouseButton) -> Result<(), OSError> {
+    press_button(event_loop, button)?;
+    release_button(event_loop, button)
+}
+
+/// Presses the key with the hardware `keycode` of the server, the focused window receives a
+/// `WindowEvent::Key` with the same code
+pub fn press_key<T: 'static>(event_loop: &EventLoop<T>, keycode: u8) -> Result<(), OSError> {
+    event_loop.connection().fake_key(keycode, true)
+}
+
+pub fn release_key<T: 'static>(event_loop: &EventLoop<T>, keycode: u8) -> Result<(), OSError> {
+    event_loop.connection().fake_key(keycode, false)
+}
+
+/// Presses and releases each key of `keycodes` in order
+pub fn type_keys<T: 'static>(event_loop: &EventLoop<T>, keycodes: &[u8]) -> Result<(), OSError> {
+    for &keycode in keycodes {
+        press_key(event_loop, keycode)?;
+        release_key(event_loop, keycode)?;
+    }
+    Ok(())
+}
+
+/// Waits until the display server has processed all the synthesized input
+pub fn sync<T: 'static>(event_loop: &EventLoop<T>) -> Result<(), OSError> {
+    event_loop.connection().sync()
+}
@@ -1,11 +1,320 @@
-use crate::{error::OSError, CONNECTION};
+use crate::{error::OSError, event_loop::EventLoop};
+#[cfg(feature = "async")]
+use crate::{
+    event_stream::Watcher,
+    platform::{ClipboardLoad, Connection},
+};
+use std::{collections::BTreeMap, fmt, sync::Arc};
+#[cfg(feature = "async")]
+use std::{
+    future::Future,
+    pin::Pin,
+    task::{Context, Poll},
+};
 
 pub use mime;
 
-pub fn load(media_type: mime::Mime) -> Result<Option<Vec<u8>>, OSError> {
-    CONNECTION.load_from_clipboard(media_type)
+/// The selections through which data is exchanged with other clients
+#[derive(Debug, Copy, Clone, Ord, PartialOrd, Eq, PartialEq, Hash)]
+#[cfg_attr(feature = "record", derive(serde::Serialize, serde::Deserialize))]
+pub enum Selection {
+    /// Filled by explicit copy actions
+    Clipboard,
+    /// The last selected text, pasted with the middle button. Some Wayland compositors don't
+    /// have it, storing on it fails and loading finds nothing
+    Primary,
 }
 
-pub fn store(media_type: mime::Mime, data: &[u8]) -> Result<(), OSError> {
-    CONNECTION.store_on_clipboard(media_type, data)
+/// Produces the content of a selection when another client asks for it, given to `store_lazy`
+pub trait ClipboardProvider: Send + Sync {
+    /// The media types the content can be produced in
+    fn media_types(&self) -> Vec<mime::Mime>;
+
+    /// Returns the content in `media_type`, one of `media_types`, or `None` to refuse the
+    /// request
+    fn provide(&self, media_type: &mime::Mime) -> Option<Vec<u8>>;
+}
+
+/// What this application put on a selection, cheap to clone so that the data can be produced
+/// without holding a lock
+#[derive(Clone)]
+pub(crate) enum Content {
+    /// Given to `store`, each call adds a media type
+    Data(Arc<BTreeMap<mime::Mime, Vec<u8>>>),
+    Provider(Arc<dyn ClipboardProvider>),
+}
+
+impl Content {
+    pub fn media_types(&self) -> Vec<mime::Mime> {
+        match self {
+            Self::Data(data) => data.keys().cloned().collect(),
+            Self::Provider(provider) => provider.media_types(),
+        }
+    }
+
+    /// Returns the content in `media_type`, one of `media_types`
+    pub fn provide(&self, media_type: &mime::Mime) -> Option<Vec<u8>> {
+        match self {
+            Self::Data(data) => data.get(media_type).cloned(),
+            Self::Provider(provider) => provider.provide(media_type),
+        }
+    }
+
+    /// Adds data in `media_type`, the content of a provider is replaced
+    pub fn insert(&mut self, media_type: mime::Mime, data: Vec<u8>) {
+        if let Self::Provider(_) = self {
+            *self = Self::default();
+        }
+        if let Self::Data(stored) = self {
+            Arc::make_mut(stored).insert(media_type, data);
+        }
+    }
+}
+
+impl Default for Content {
+    fn default() -> Self {
+        Self::Data(Arc::new(BTreeMap::new()))
+    }
+}
+
+impl fmt::Debug for Content {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Data(data) => f.debug_list().entries(data.keys()).finish(),
+            Self::Provider(_) => f.write_str("Provider"),
+        }
+    }
+}
+
+pub fn load<T: 'static>(
+    event_loop: &EventLoop<T>,
+    media_type: mime::Mime,
+) -> Result<Option<Vec<u8>>, OSError> {
+    load_from(event_loop, Selection::Clipboard, media_type)
+}
+
+pub fn store<T: 'static>(
+    event_loop: &EventLoop<T>,
+    media_type: mime::Mime,
+    data: &[u8],
+) -> Result<(), OSError> {
+    store_to(event_loop, Selection::Clipboard, media_type, data)
+}
+
+/// Puts the content of `provider` on the clipboard, it's only produced when another client asks
+/// for it
+pub fn store_lazy<T: 'static, P: ClipboardProvider + 'static>(
+    event_loop: &EventLoop<T>,
+    provider: P,
+) -> Result<(), OSError> {
+    store_lazy_to(event_loop, Selection::Clipboard, provider)
+}
+
+/// Like `store_lazy` for any selection
+pub fn store_lazy_to<T: 'static, P: ClipboardProvider + 'static>(
+    event_loop: &EventLoop<T>,
+    selection: Selection,
+    provider: P,
+) -> Result<(), OSError> {
+    event_loop
+        .connection()
+        .store_provider_on_clipboard(selection, Arc::new(provider))
+}
+
+/// Returns the media types under which the content of the clipboard can be loaded
+pub fn available_types<T: 'static>(event_loop: &EventLoop<T>) -> Result<Vec<mime::Mime>, OSError> {
+    available_types_in(event_loop, Selection::Clipboard)
+}
+
+/// Like `available_types` for any selection
+pub fn available_types_in<T: 'static>(
+    event_loop: &EventLoop<T>,
+    selection: Selection,
+) -> Result<Vec<mime::Mime>, OSError> {
+    event_loop.connection().available_clipboard_types(selection)
+}
+
+/// Like `load` for any selection
+pub fn load_from<T: 'static>(
+    event_loop: &EventLoop<T>,
+    selection: Selection,
+    media_type: mime::Mime,
+) -> Result<Option<Vec<u8>>, OSError> {
+    event_loop
+        .connection()
+        .load_from_clipboard(selection, media_type)
+}
+
+/// Like `store` for any selection
+pub fn store_to<T: 'static>(
+    event_loop: &EventLoop<T>,
+    selection: Selection,
+    media_type: mime::Mime,
+    data: &[u8],
+) -> Result<(), OSError> {
+    event_loop
+        .connection()
+        .store_on_clipboard(selection, media_type, data)
+}
+
+/// Text in Latin-1, the encoding of the `STRING` target of the ICCCM
+#[cfg(any(feature = "x11", feature = "wayland"))]
+pub(crate) fn text_plain_latin1() -> mime::Mime {
+    "text/plain;charset=iso-8859-1".parse().unwrap()
+}
+
+/// Text without a charset is taken as UTF-8
+#[cfg(any(feature = "x11", feature = "wayland"))]
+// `Option::is_none_or` needs Rust 1.82
+#[allow(clippy::unnecessary_map_or)]
+pub(crate) fn is_utf8_text(media_type: &mime::Mime) -> bool {
+    media_type.type_() == mime::TEXT
+        && media_type.subtype() == mime::PLAIN
+        && media_type
+            .get_param(mime::CHARSET)
+            .map_or(true, |charset| charset == "utf-8")
+}
+
+#[cfg(any(feature = "x11", feature = "wayland"))]
+pub(crate) fn is_latin1_text(media_type: &mime::Mime) -> bool {
+    media_type.type_() == mime::TEXT
+        && media_type.subtype() == mime::PLAIN
+        && media_type
+            .get_param(mime::CHARSET)
+            .is_some_and(|charset| charset == "iso-8859-1")
+}
+
+/// Converts UTF-8 text for the clients asking for `STRING`, the characters that Latin-1 lacks
+/// become `?`
+#[cfg(any(feature = "x11", feature = "wayland"))]
+pub(crate) fn utf8_to_latin1(text: &[u8]) -> Vec<u8> {
+    String::from_utf8_lossy(text)
+        .chars()
+        .map(|c| if (c as u32) <= 0xff { c as u8 } else { b'?' })
+        .collect()
+}
+
+/// Returns the media types of the targets offered by another client, without duplicates
+///
+/// X11 clients name text after the atoms of the ICCCM, `TEXT` is left out since the owner picks
+/// its encoding when the data is requested.
+#[cfg(any(feature = "x11", feature = "wayland"))]
+pub(crate) fn media_types<I>(targets: I) -> Vec<mime::Mime>
+where
+    I: IntoIterator,
+    I::Item: AsRef<str>,
+{
+    let mut media_types = Vec::new();
+    for target in targets {
+        let media_type = match target.as_ref() {
+            "UTF8_STRING" => mime::TEXT_PLAIN_UTF_8,
+            "STRING" => text_plain_latin1(),
+            "TEXT" => continue,
+            target => match target.parse::<mime::Mime>() {
+                Ok(media_type) => media_type,
+                Err(_) => continue,
+            },
+        };
+        // The equality of `Mime` doesn't always tell charsets apart
+        let same = |known: &mime::Mime| {
+            known.essence_str() == media_type.essence_str()
+                && known.get_param(mime::CHARSET) == media_type.get_param(mime::CHARSET)
+        };
+        if !media_types.iter().any(same) {
+            media_types.push(media_type);
+        }
+    }
+    media_types
+}
+
+/// Like `load_from` but the task is free while the owner of the selection answers, the
+/// request is sent right away and the transfer goes on whenever the owner sends something
+#[cfg(feature = "async")]
+pub fn load_async<T: 'static>(
+    event_loop: &EventLoop<T>,
+    selection: Selection,
+    media_type: mime::Mime,
+) -> impl Future<Output = Result<Option<Vec<u8>>, OSError>> {
+    let connection = event_loop.connection().clone();
+    let load = event_loop.watcher().and_then(|watcher| {
+        Ok(AsyncLoad {
+            load: connection.start_clipboard_load(selection, media_type)?,
+            key: watcher.register(),
+            watcher,
+            connection,
+        })
+    });
+    async move { load?.await }
+}
+
+/// Like `store_to`, the data is handed to other clients by the event loop so there is nothing
+/// to wait for
+#[cfg(feature = "async")]
+pub fn store_async<T: 'static>(
+    event_loop: &EventLoop<T>,
+    selection: Selection,
+    media_type: mime::Mime,
+    data: &[u8],
+) -> impl Future<Output = Result<(), OSError>> {
+    let result = store_to(event_loop, selection, media_type, data);
+    async move { result }
+}
+
+/// A load whose task is woken by the watcher of the event loop when the owner sends something
+#[cfg(feature = "async")]
+struct AsyncLoad {
+    connection: Arc<Connection>,
+    load: ClipboardLoad,
+    watcher: Arc<Watcher>,
+    key: u64,
+}
+
+#[cfg(feature = "async")]
+impl Future for AsyncLoad {
+    type Output = Result<Option<Vec<u8>>, OSError>;
+
+    fn poll(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Self::Output> {
+        let this = self.get_mut();
+        match this.connection.poll_clipboard_load(&mut this.load) {
+            Ok(Poll::Ready(data)) => Poll::Ready(Ok(data)),
+            Ok(Poll::Pending) => match this.connection.clipboard_load_pollfds(&this.load) {
+                Ok((fds, timeout)) => {
+                    this.watcher
+                        .watch(this.key, fds, timeout, cx.waker().clone());
+                    Poll::Pending
+                }
+                Err(e) => Poll::Ready(Err(e)),
+            },
+            Err(e) => Poll::Ready(Err(e)),
+        }
+    }
+}
+
+#[cfg(feature = "async")]
+impl Drop for AsyncLoad {
+    fn drop(&mut self) {
+        self.watcher.forget(self.key);
+    }
+}
+
+#[cfg(all(test, any(feature = "x11", feature = "wayland")))]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn targets_become_media_types() {
+        let targets = [
+            "TARGETS",
+            "UTF8_STRING",
+            "text/plain;charset=utf-8",
+            "STRING",
+            "TEXT",
+            "text/html",
+            "MULTIPLE",
+        ];
+        assert_eq!(
+            media_types(targets),
+            vec![mime::TEXT_PLAIN_UTF_8, text_plain_latin1(), mime::TEXT_HTML]
+        );
+    }
 }
@@ -1,3 +1,4 @@
+use crate::surface;
 use std::io;
 use thiserror::Error;
 
@@ -9,8 +10,61 @@ pub enum OSError {
     Parse,
     #[error("out of memory")]
     InsufficientMemory,
+    #[error("the pixel format {0:?} is not supported")]
+    UnsupportedFormat(surface::Format),
+    #[error("the surface was resized while it was locked")]
+    SurfaceResized,
+    #[error("the window is closed")]
+    WindowClosed,
+    /// Another client stopped sending the data it was asked for
+    #[error("the transfer timed out")]
+    Timeout,
+    /// The X11 server rejected the request with the given opcodes and sequence number
+    #[error(
+        "X11 error {error_code} for the request {major_opcode}.{minor_opcode} (sequence {sequence})"
+    )]
+    X11Protocol {
+        major_opcode: u8,
+        minor_opcode: u16,
+        error_code: u8,
+        sequence: u16,
+    },
+    /// The compositor rejected a request made on an object, the connection is unusable after it
+    #[error("Wayland error {code} on {interface}@{object_id}: {message}")]
+    WaylandProtocol {
+        interface: String,
+        object_id: u32,
+        code: u32,
+        message: String,
+    },
+    #[error("no display server to connect to")]
+    NoDisplay,
+    #[error("the backend is not available")]
+    BackendUnavailable,
+    #[error("the operation is not supported by the backend")]
+    Unsupported,
+    /// The display server lacks an extension the request needs
+    #[error("the {0} extension is not supported by the display server")]
+    UnsupportedExtension(&'static str),
+    /// The display server closed the connection or crashed
+    #[error("the connection to the display server was lost")]
+    DisplayLost,
     #[error("IO error")]
     IO(#[from] io::Error),
     #[error("`{0}`")]
     Other(String),
 }
+
+impl OSError {
+    /// Converts an error of the socket connected to the display server, a closed socket means
+    /// the server is gone
+    pub(crate) fn from_display_io(e: io::Error) -> Self {
+        match e.kind() {
+            io::ErrorKind::UnexpectedEof
+            | io::ErrorKind::BrokenPipe
+            | io::ErrorKind::ConnectionReset
+            | io::ErrorKind::ConnectionAborted => OSError::DisplayLost,
+            _ => OSError::IO(e),
+        }
+    }
+}
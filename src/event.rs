@@ -1,7 +1,8 @@
-use crate::platform::WindowId;
+use crate::{clipboard::Selection, event_loop::SourceToken, platform::WindowId};
 
 #[cfg(feature = "windows")]
 #[derive(Debug, Copy, Clone, Ord, PartialOrd, Eq, PartialEq, Hash)]
+#[cfg_attr(feature = "record", derive(serde::Serialize, serde::Deserialize))]
 pub enum MouseButton {
     Left,
     Right,
@@ -12,23 +13,92 @@ pub enum MouseButton {
 
 #[cfg(feature = "windows")]
 #[derive(Debug, Copy, Clone, Ord, PartialOrd, Eq, PartialEq, Hash)]
+#[cfg_attr(feature = "record", derive(serde::Serialize, serde::Deserialize))]
 pub enum ButtonState {
     Released,
     Pressed,
 }
 
+/// Why the event loop resumed, delivered with `Event::NewEvents`
+#[derive(Debug, Copy, Clone, Ord, PartialOrd, Eq, PartialEq, Hash)]
+pub enum StartCause {
+    /// The instant given to `ControlFlow::WaitUntil` was reached
+    ResumeTimeReached,
+    /// The wait ended before its deadline, if any, because of new events
+    WaitCancelled,
+    /// The control flow was `ControlFlow::Poll`
+    Poll,
+}
+
+/// `T` is the type of the events sent through an `EventLoopProxy`, the backends only produce
+/// `Event<()>`
 #[derive(Debug, Copy, Clone, PartialEq)]
-pub enum Event {
+pub enum Event<T = ()> {
+    /// Starts every iteration of the event loop after the first one
+    NewEvents(StartCause),
     #[cfg(feature = "windows")]
     WindowEvent {
         window: WindowId,
         event: WindowEvent,
     },
     MainEventsCleared,
+    UserEvent(T),
+    /// A file descriptor registered with `EventLoop::register_fd` is ready
+    FdReady {
+        token: SourceToken,
+        readable: bool,
+        writable: bool,
+    },
+    /// Another client put new content on `selection`. On Wayland it's also sent when the
+    /// application gets the keyboard focus
+    ClipboardChanged {
+        selection: Selection,
+    },
+    /// Another client took `selection` from this application, the content stored on it is
+    /// dropped
+    ClipboardOwnershipLost {
+        selection: Selection,
+    },
+    /// The connection to the display server failed, `EventLoop::take_error` tells why. It's the
+    /// last event, the loop exits after it
+    BackendError,
+    /// The display server went away, like `BackendError` it's the last event. The windows are
+    /// destroyed once the loop exits
+    DisplayLost,
+}
+
+impl Event {
+    /// Converts an event of the backends to the event type of an event loop, `UserEvent(())`
+    /// has no meaning there so it's dropped
+    pub(crate) fn with_user_event<T>(self) -> Option<Event<T>> {
+        match self {
+            #[cfg(feature = "windows")]
+            Event::WindowEvent { window, event } => Some(Event::WindowEvent { window, event }),
+            Event::NewEvents(cause) => Some(Event::NewEvents(cause)),
+            Event::MainEventsCleared => Some(Event::MainEventsCleared),
+            Event::UserEvent(()) => None,
+            Event::ClipboardChanged { selection } => Some(Event::ClipboardChanged { selection }),
+            Event::ClipboardOwnershipLost { selection } => {
+                Some(Event::ClipboardOwnershipLost { selection })
+            }
+            Event::BackendError => Some(Event::BackendError),
+            Event::DisplayLost => Some(Event::DisplayLost),
+            Event::FdReady {
+                token,
+                readable,
+                writable,
+            } => Some(Event::FdReady {
+                token,
+                readable,
+                writable,
+            }),
+        }
+    }
 }
 
 #[cfg(feature = "windows")]
 #[derive(Debug, Copy, Clone, PartialEq)]
+#[cfg_attr(feature = "record", derive(serde::Serialize, serde::Deserialize))]
 pub enum WindowEvent {
     CloseRequested,
     Dirted,
@@ -55,4 +125,9 @@ pub enum WindowEvent {
         x: f64,
         y: f64,
     },
+    /// `keycode` is the code of the key on the display server, it doesn't follow the layout
+    Key {
+        keycode: u32,
+        state: ButtonState,
+    },
 }
@@ -1,76 +1,664 @@
-use crate::{error::OSError, event::*, platform::*, window::*, CONNECTION};
-use parking_lot::RwLock;
-use std::{cell::RefCell, collections::BTreeMap, sync::Arc, thread};
+#[cfg(feature = "async")]
+use crate::event_stream::Watcher;
+#[cfg(feature = "record")]
+use crate::record::{Recordable, Recorder, Replayer};
+use crate::{error::OSError, event::*, platform::*, window::*};
+#[cfg(feature = "record")]
+use std::path::Path;
+use std::{
+    cell::{Cell, RefCell},
+    collections::{BTreeSet, VecDeque},
+    fmt,
+    marker::PhantomData,
+    str::FromStr,
+    sync::Arc,
+    time::{Duration, Instant},
+};
+#[cfg(unix)]
+use std::{collections::BTreeMap, os::unix::io::RawFd};
+use thiserror::Error;
 
 #[derive(Debug, Copy, Clone, Ord, PartialOrd, Eq, PartialEq, Hash)]
 pub enum ControlFlow {
+    /// Sleeps until new events arrive
     Wait,
+    /// Sleeps until new events arrive or the instant is reached
+    WaitUntil(Instant),
+    /// Starts a new iteration as soon as the events are handled
     Poll,
     Exit,
 }
 
+/// A display server backend, see `EventLoopBuilder::with_backend`
+#[derive(Debug, Copy, Clone, Ord, PartialOrd, Eq, PartialEq, Hash)]
+pub enum Backend {
+    X11,
+    Wayland,
+    /// Windows in memory, see [`crate::headless`]
+    Headless,
+}
+
+impl FromStr for Backend {
+    type Err = OSError;
+
+    /// Parses the names accepted by `MOVING_BACKEND`: `x11`, `wayland` and `headless`
+    fn from_str(name: &str) -> Result<Self, OSError> {
+        match name {
+            "x11" => Ok(Self::X11),
+            "wayland" => Ok(Self::Wayland),
+            "headless" => Ok(Self::Headless),
+            _ => Err(OSError::Other(format!("unknown backend `{}`", name))),
+        }
+    }
+}
+
+/// Returned by `EventLoop::pump_events`
+#[derive(Debug, Copy, Clone, Ord, PartialOrd, Eq, PartialEq, Hash)]
+pub enum PumpStatus {
+    Continue,
+    /// The handler set `ControlFlow::Exit`
+    Exit,
+}
+
+/// The readiness that a file descriptor registered with `EventLoop::register_fd` is watched for
+#[derive(Debug, Copy, Clone, Eq, PartialEq, Hash)]
+pub struct Interest {
+    pub readable: bool,
+    pub writable: bool,
+}
+
+impl Interest {
+    pub const READABLE: Interest = Interest {
+        readable: true,
+        writable: false,
+    };
+    pub const WRITABLE: Interest = Interest {
+        readable: false,
+        writable: true,
+    };
+    pub const BOTH: Interest = Interest {
+        readable: true,
+        writable: true,
+    };
+}
+
+/// Identifies a file descriptor registered in an event loop
+#[derive(Debug, Copy, Clone, Ord, PartialOrd, Eq, PartialEq, Hash)]
+pub struct SourceToken(u64);
+
+/// Returned when sending an event to an event loop that no longer exists
+#[derive(Error, Debug, Copy, Clone, PartialEq)]
+#[error("the event loop no longer exists")]
+pub struct EventLoopClosed<T>(pub T);
+
+/// A handle to the event loop that can be sent to other threads
+pub struct EventLoopProxy<T: 'static = ()> {
+    user_events: flume::Sender<T>,
+    connection: Arc<Connection>,
+}
+
+impl<T: 'static> EventLoopProxy<T> {
+    /// Delivers `event` as an `Event::UserEvent`, waking up the event loop if it's waiting
+    pub fn send_event(&self, event: T) -> Result<(), EventLoopClosed<T>> {
+        self.user_events
+            .send(event)
+            .map_err(|flume::SendError(event)| EventLoopClosed(event))?;
+        // The event is already queued, if the wakeup fails the loop still gets it later
+        let _ = self.connection.wakeup();
+        Ok(())
+    }
+
+    /// Queues `event` with the ones received from the display server, it goes through the same
+    /// handling, so an injected `WindowEvent::Destroy` destroys the window
+    pub fn inject(&self, event: Event) -> Result<(), OSError> {
+        self.connection.inject_event(event)?;
+        self.connection.wakeup()
+    }
+}
+
+impl<T: 'static> Clone for EventLoopProxy<T> {
+    fn clone(&self) -> Self {
+        Self {
+            user_events: self.user_events.clone(),
+            connection: self.connection.clone(),
+        }
+    }
+}
+
+impl<T: 'static> fmt::Debug for EventLoopProxy<T> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("EventLoopProxy").finish()
+    }
+}
+
+pub struct EventLoop<T: 'static = ()> {
+    connection: Arc<Connection>,
+    // The windows created by this loop, some may have been closed since
+    windows: RefCell<BTreeSet<WindowId>>,
+    user_events: (flume::Sender<T>, flume::Receiver<T>),
+    #[cfg(unix)]
+    fd_sources: RefCell<BTreeMap<SourceToken, (RawFd, Interest)>>,
+    #[cfg(unix)]
+    next_source_token: Cell<u64>,
+    // `Event::FdReady` found by the last wait, delivered before anything else
+    ready_sources: RefCell<VecDeque<Event<T>>>,
+    // The first error of the connection, kept until `take_error`
+    error: RefCell<Option<OSError>>,
+    error_state: Cell<ErrorState>,
+    // The windows in creation order, recordings refer to them by their index here
+    #[cfg(feature = "record")]
+    window_order: RefCell<Vec<WindowId>>,
+    #[cfg(feature = "record")]
+    recorder: RefCell<Option<Recorder>>,
+    #[cfg(feature = "record")]
+    replayer: RefCell<Option<Replayer>>,
+    // Started by the first stream or asynchronous clipboard load
+    #[cfg(feature = "async")]
+    watcher: RefCell<Option<Arc<Watcher>>>,
+}
+
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+enum ErrorState {
+    Connected,
+    /// `Event::BackendError` is delivered next
+    Failed,
+    /// `Event::DisplayLost` is delivered next
+    Lost,
+    /// No events come after the one reporting the error
+    Reported,
+}
+
+impl<T: 'static> fmt::Debug for EventLoop<T> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("EventLoop")
+            .field("windows", &self.windows)
+            .finish()
+    }
+}
+
+impl EventLoop<()> {
+    /// Connects to the display server, see `EventLoopBuilder` for the choice of the backend
+    pub fn new() -> Result<EventLoop<()>, OSError> {
+        Self::with_user_event()
+    }
+}
+
+/// Creates an event loop with options that apply to the connection to the display server
 #[derive(Debug)]
-pub struct EventLoop {
-    windows: RefCell<BTreeMap<WindowId, Arc<RwLock<WindowPlatformData>>>>,
+pub struct EventLoopBuilder<T: 'static = ()> {
+    backend: Option<Backend>,
+    clipboard_thread: bool,
+    _user_event: PhantomData<T>,
+}
+
+impl EventLoopBuilder<()> {
+    pub fn new() -> Self {
+        Self::with_user_event()
+    }
+}
+
+impl Default for EventLoopBuilder<()> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl<T: 'static> EventLoopBuilder<T> {
+    /// Like `EventLoop::with_user_event`
+    pub fn with_user_event() -> Self {
+        Self {
+            backend: None,
+            clipboard_thread: false,
+            _user_event: PhantomData,
+        }
+    }
+
+    /// Uses `backend` instead of the first one available, this takes precedence over
+    /// `MOVING_BACKEND`
+    pub fn with_backend(mut self, backend: Backend) -> Self {
+        self.backend = Some(backend);
+        self
+    }
+
+    /// Serves the selections stored by the application from a connection and a thread of their
+    /// own on X11, so that other clients get the content while the event loop does not run. It
+    /// has no effect on the other backends
+    pub fn with_clipboard_thread(mut self) -> Self {
+        self.clipboard_thread = true;
+        self
+    }
+
+    /// Connects to the display server, fails with `OSError::NoDisplay` if there is none to
+    /// connect to and `OSError::BackendUnavailable` if the backend was not compiled in
+    pub fn build(self) -> Result<EventLoop<T>, OSError> {
+        let connection = Connection::new(self.backend, self.clipboard_thread)?;
+        Ok(EventLoop::with_connection(Arc::new(connection)))
+    }
 }
 
-impl EventLoop {
-    pub fn new() -> EventLoop {
+impl<T: 'static> EventLoop<T> {
+    /// Creates an event loop that delivers the events of type `T` sent through its proxies
+    pub fn with_user_event() -> Result<EventLoop<T>, OSError> {
+        EventLoopBuilder::with_user_event().build()
+    }
+
+    fn with_connection(connection: Arc<Connection>) -> EventLoop<T> {
         EventLoop {
-            windows: RefCell::new(BTreeMap::new()),
+            connection,
+            windows: RefCell::new(BTreeSet::new()),
+            user_events: flume::unbounded(),
+            #[cfg(unix)]
+            fd_sources: RefCell::new(BTreeMap::new()),
+            #[cfg(unix)]
+            next_source_token: Cell::new(0),
+            ready_sources: RefCell::new(VecDeque::new()),
+            error: RefCell::new(None),
+            error_state: Cell::new(ErrorState::Connected),
+            #[cfg(feature = "record")]
+            window_order: RefCell::new(Vec::new()),
+            #[cfg(feature = "record")]
+            recorder: RefCell::new(None),
+            #[cfg(feature = "record")]
+            replayer: RefCell::new(None),
+            #[cfg(feature = "async")]
+            watcher: RefCell::new(None),
+        }
+    }
+
+    /// Returns the backend in use
+    pub fn backend(&self) -> Backend {
+        self.connection.backend()
+    }
+
+    pub(crate) fn connection(&self) -> &Arc<Connection> {
+        &self.connection
+    }
+
+    /// Returns the error announced by `Event::BackendError` or `Event::DisplayLost`, `None`
+    /// while the connection works
+    pub fn take_error(&self) -> Option<OSError> {
+        self.error.borrow_mut().take()
+    }
+
+    /// Keeps the first error of the connection, the loop stops using it
+    pub(crate) fn fail(&self, error: OSError) {
+        if self.error_state.get() == ErrorState::Connected {
+            self.error_state.set(match error {
+                OSError::DisplayLost => ErrorState::Lost,
+                _ => ErrorState::Failed,
+            });
+            self.error.replace(Some(error));
+        }
+    }
+
+    /// Whether the error of the connection was delivered, nothing follows it
+    #[cfg(feature = "async")]
+    pub(crate) fn is_closed(&self) -> bool {
+        self.error_state.get() == ErrorState::Reported
+    }
+
+    /// Returns the helper thread that waits for the file descriptors of the streams and the
+    /// asynchronous clipboard loads of this loop
+    #[cfg(feature = "async")]
+    pub(crate) fn watcher(&self) -> Result<Arc<Watcher>, OSError> {
+        let mut watcher = self.watcher.borrow_mut();
+        if let Some(watcher) = &*watcher {
+            return Ok(watcher.clone());
+        }
+        let spawned = Arc::new(Watcher::spawn()?);
+        *watcher = Some(spawned.clone());
+        Ok(spawned)
+    }
+
+    /// Writes every event from the display server delivered by `run` to `path`, see
+    /// [`Recordable`]. The timestamps are relative to this call and a failure to write ends the
+    /// loop like a failure of the connection
+    #[cfg(feature = "record")]
+    pub fn record_to(&self, path: impl AsRef<Path>) -> Result<(), OSError> {
+        self.recorder.replace(Some(Recorder::create(path)?));
+        Ok(())
+    }
+
+    /// Delivers the events recorded at `path` with their original timing, counted from the
+    /// start of `run`, along with the ones coming from the display server
+    #[cfg(feature = "record")]
+    pub fn replay_from(&self, path: impl AsRef<Path>) -> Result<(), OSError> {
+        self.replayer.replace(Some(Replayer::open(path)?));
+        Ok(())
+    }
+
+    /// Watches `fd` along with the display connection, `run` delivers an `Event::FdReady` with
+    /// the returned token on every iteration where it's ready. The file descriptor must stay
+    /// open until it's unregistered
+    #[cfg(unix)]
+    pub fn register_fd(&self, fd: RawFd, interest: Interest) -> SourceToken {
+        let token = SourceToken(self.next_source_token.get());
+        self.next_source_token.set(token.0 + 1);
+        self.fd_sources.borrow_mut().insert(token, (fd, interest));
+        token
+    }
+
+    /// Stops watching a file descriptor, returns `false` if the token was already unregistered
+    #[cfg(unix)]
+    pub fn unregister_fd(&self, token: SourceToken) -> bool {
+        self.ready_sources.borrow_mut().retain(|event| match event {
+            Event::FdReady { token: ready, .. } => *ready != token,
+            _ => true,
+        });
+        self.fd_sources.borrow_mut().remove(&token).is_some()
+    }
+
+    pub fn create_proxy(&self) -> EventLoopProxy<T> {
+        EventLoopProxy {
+            user_events: self.user_events.0.clone(),
+            connection: self.connection.clone(),
         }
     }
 
     #[cfg(feature = "windows")]
     pub(crate) fn create_window(&self, builder: WindowBuilder) -> Result<Window, OSError> {
-        let window = CONNECTION.create_window(builder)?;
+        let window = self.connection.create_window(builder)?;
 
-        self.windows
-            .borrow_mut()
-            .insert(window.id, window.platform_data.clone());
+        let mut windows = self.windows.borrow_mut();
+        // Forgets the windows closed through `Window::close` or by dropping them
+        windows.retain(|&id| self.connection.has_window(id));
+        windows.insert(window.id);
+        drop(windows);
+        #[cfg(feature = "record")]
+        self.window_order.borrow_mut().push(window.id);
 
         Ok(window)
     }
 
-    pub fn run<H>(&self, mut event_handler: H)
-    where
-        H: 'static + FnMut(Event, &mut ControlFlow),
-    {
-        let mut cf = ControlFlow::Poll;
-        let mut there_was_an_event_before = false;
-        while cf != ControlFlow::Exit {
-            let event = CONNECTION.poll_event().unwrap();
-            if let Some(event) = event {
-                #[cfg(feature = "windows")]
-                if let Event::WindowEvent { window, ref event } = event {
-                    match event {
-                        WindowEvent::Destroy => {
-                            if let Some(platform_data) = self.windows.borrow_mut().remove(&window) {
-                                CONNECTION
-                                    .destroy_window(&mut platform_data.write())
-                                    .unwrap();
-                            }
-                        }
-                        _ => {}
+    fn next_event(&self) -> Option<Event<T>> {
+        match self.error_state.get() {
+            ErrorState::Connected => {}
+            ErrorState::Failed => {
+                self.error_state.set(ErrorState::Reported);
+                return Some(Event::BackendError);
+            }
+            ErrorState::Lost => {
+                self.error_state.set(ErrorState::Reported);
+                return Some(Event::DisplayLost);
+            }
+            ErrorState::Reported => return None,
+        }
+        #[cfg(feature = "record")]
+        if let Some(event) = self.next_replayed_event() {
+            return Some(event);
+        }
+        if let Some(event) = self.ready_sources.borrow_mut().pop_front() {
+            return Some(event);
+        }
+        if let Ok(event) = self.user_events.1.try_recv() {
+            return Some(Event::UserEvent(event));
+        }
+        loop {
+            match self.connection.poll_event() {
+                Ok(event) => {
+                    if let Some(event) = event?.with_user_event() {
+                        return Some(event);
                     }
                 }
-                event_handler(event, &mut cf);
-                there_was_an_event_before = true;
-            } else {
-                if let ControlFlow::Poll = cf {
-                    event_handler(Event::MainEventsCleared, &mut cf);
-                } else {
-                    if there_was_an_event_before {
-                        event_handler(Event::MainEventsCleared, &mut cf);
+                Err(e) => {
+                    self.fail(e);
+                    return self.next_event();
+                }
+            }
+        }
+    }
+
+    #[cfg(feature = "record")]
+    fn next_replayed_event(&self) -> Option<Event<T>> {
+        let mut replayer = self.replayer.borrow_mut();
+        while let Some(recorded) = replayer.as_mut()?.next_due() {
+            match recorded.event {
+                Recordable::Window { window, event } => {
+                    // Events of windows that this session never created are dropped
+                    if let Some(&window) = self.window_order.borrow().get(window) {
+                        return Some(Event::WindowEvent { window, event });
                     }
                 }
-                there_was_an_event_before = false;
+                Recordable::ClipboardChanged { selection } => {
+                    return Some(Event::ClipboardChanged { selection })
+                }
+                Recordable::ClipboardOwnershipLost { selection } => {
+                    return Some(Event::ClipboardOwnershipLost { selection })
+                }
+            }
+        }
+        None
+    }
+
+    #[cfg(feature = "record")]
+    fn record_event(&self, event: &Event<T>) {
+        let mut recorder = self.recorder.borrow_mut();
+        let recorder = match recorder.as_mut() {
+            Some(recorder) => recorder,
+            None => return,
+        };
+        let recorded = match *event {
+            Event::WindowEvent { window, event } => {
+                let window_order = self.window_order.borrow();
+                match window_order.iter().position(|id| *id == window) {
+                    Some(window) => Recordable::Window { window, event },
+                    None => return,
+                }
+            }
+            Event::ClipboardChanged { selection } => Recordable::ClipboardChanged { selection },
+            Event::ClipboardOwnershipLost { selection } => {
+                Recordable::ClipboardOwnershipLost { selection }
             }
-            thread::yield_now();
+            _ => return,
+        };
+        if let Err(e) = recorder.record(recorded) {
+            self.fail(e);
+        }
+    }
+
+    /// Shortens `timeout` so a wait doesn't delay the pending user events or the next replayed
+    /// event
+    pub(crate) fn wait_timeout(&self, timeout: Option<Duration>) -> Option<Duration> {
+        let timeout = if self.user_events.1.is_empty() {
+            timeout
+        } else {
+            Some(Duration::from_secs(0))
+        };
+        #[cfg(feature = "record")]
+        let timeout = match self.replayer.borrow_mut().as_mut() {
+            Some(replayer) => match (timeout, replayer.time_until_next()) {
+                (Some(timeout), Some(next)) => Some(timeout.min(next)),
+                (timeout, next) => timeout.or(next),
+            },
+            None => timeout,
+        };
+        timeout
+    }
+
+    /// Returns the registered file descriptors in the order of their tokens
+    #[cfg(unix)]
+    pub(crate) fn source_pollfds(&self) -> Vec<libc::pollfd> {
+        self.fd_sources
+            .borrow()
+            .values()
+            .map(|&(fd, interest)| {
+                let mut events = 0;
+                if interest.readable {
+                    events |= libc::POLLIN;
+                }
+                if interest.writable {
+                    events |= libc::POLLOUT;
+                }
+                libc::pollfd {
+                    fd,
+                    events,
+                    revents: 0,
+                }
+            })
+            .collect()
+    }
+
+    /// Blocks until there are new events or `timeout` expires, returns at once when the
+    /// connection failed
+    pub(crate) fn wait(&self, timeout: Option<Duration>) {
+        if self.error_state.get() != ErrorState::Connected {
+            return;
+        }
+        let timeout = self.wait_timeout(timeout);
+        #[cfg(unix)]
+        self.wait_with_sources(timeout);
+        #[cfg(not(unix))]
+        if let Err(e) = self.connection.wait(timeout) {
+            self.fail(e);
         }
+    }
+
+    /// Waits on the connection and the registered file descriptors, the ready ones are queued
+    /// as `Event::FdReady`
+    #[cfg(unix)]
+    fn wait_with_sources(&self, timeout: Option<Duration>) {
+        let mut fds = self.source_pollfds();
+        if let Err(e) = self.connection.wait(timeout, &mut fds) {
+            self.fail(e);
+            return;
+        }
+
+        let sources = self.fd_sources.borrow();
+        let mut ready_sources = self.ready_sources.borrow_mut();
+        for (&token, fd) in sources.keys().zip(&fds) {
+            if fd.revents == 0 {
+                continue;
+            }
+            // Errors and hang ups are reported as readiness so the next operation reveals them
+            let failed = fd.revents & (libc::POLLERR | libc::POLLHUP | libc::POLLNVAL) != 0;
+            ready_sources.push_back(Event::FdReady {
+                token,
+                readable: fd.revents & libc::POLLIN != 0 || failed,
+                writable: fd.revents & libc::POLLOUT != 0 || failed,
+            });
+        }
+    }
+
+    /// Returns the next event once the loop took note of it, destroyed windows are forgotten
+    pub(crate) fn next_dispatched_event(&self) -> Option<Event<T>> {
+        let event = self.next_event()?;
+        #[cfg(feature = "record")]
+        self.record_event(&event);
+        #[cfg(feature = "windows")]
+        if let Event::WindowEvent {
+            window,
+            event: WindowEvent::Destroy,
+        } = event
+        {
+            self.windows.borrow_mut().remove(&window);
+            if let Err(e) = self.connection.destroy_window(window) {
+                self.fail(e);
+            }
+        }
+        Some(event)
+    }
+
+    /// Delivers the pending events followed by `Event::MainEventsCleared`, stops early if the
+    /// handler exits or the connection failed
+    fn dispatch_events<H>(&self, event_handler: &mut H, cf: &mut ControlFlow)
+    where
+        H: FnMut(Event<T>, &mut ControlFlow),
+    {
+        while *cf != ControlFlow::Exit {
+            match self.next_dispatched_event() {
+                Some(event @ Event::BackendError) | Some(event @ Event::DisplayLost) => {
+                    event_handler(event, cf);
+                    *cf = ControlFlow::Exit;
+                }
+                Some(event) => event_handler(event, cf),
+                None => break,
+            }
+        }
+        if *cf != ControlFlow::Exit {
+            event_handler(Event::MainEventsCleared, cf);
+        }
+    }
+
+    /// Waits as requested by `cf` and returns why the wait ended, `None` on `ControlFlow::Exit`
+    fn wait_for(&self, cf: ControlFlow) -> Option<StartCause> {
+        let cause = match cf {
+            ControlFlow::Exit => return None,
+            ControlFlow::Poll => {
+                self.wait(Some(Duration::from_secs(0)));
+                StartCause::Poll
+            }
+            ControlFlow::Wait => {
+                self.wait(None);
+                StartCause::WaitCancelled
+            }
+            ControlFlow::WaitUntil(deadline) => {
+                let now = Instant::now();
+                if now < deadline {
+                    self.wait(Some(deadline - now));
+                }
+                if Instant::now() >= deadline {
+                    StartCause::ResumeTimeReached
+                } else {
+                    StartCause::WaitCancelled
+                }
+            }
+        };
+        Some(cause)
+    }
+
+    /// Destroys the windows that are still alive, the loop is over
+    fn destroy_windows(&self) {
         #[cfg(feature = "windows")]
-        for window in self.windows.borrow().values() {
-            CONNECTION.destroy_window(&mut window.write()).unwrap();
+        for window in std::mem::take(&mut *self.windows.borrow_mut()) {
+            // A failed connection has nothing left to destroy
+            let _ = self.connection.destroy_window(window);
+        }
+    }
+
+    /// Delivers the events until the handler sets `ControlFlow::Exit`, then destroys the
+    /// remaining windows. Fails with the error of the connection after an `Event::BackendError`
+    /// or `Event::DisplayLost`
+    pub fn run<H>(&self, mut event_handler: H) -> Result<(), OSError>
+    where
+        H: 'static + FnMut(Event<T>, &mut ControlFlow),
+    {
+        let mut cf = ControlFlow::Poll;
+        self.dispatch_events(&mut event_handler, &mut cf);
+        while let Some(cause) = self.wait_for(cf) {
+            event_handler(Event::NewEvents(cause), &mut cf);
+            self.dispatch_events(&mut event_handler, &mut cf);
+        }
+        self.destroy_windows();
+        match self.take_error() {
+            Some(error) => Err(error),
+            None => Ok(()),
+        }
+    }
+
+    /// Runs a single iteration of the event loop for applications that own the main loop
+    ///
+    /// Waits up to `timeout` for events, `None` waits until there are some and a zero timeout
+    /// doesn't wait at all, then delivers `Event::NewEvents`, the events and
+    /// `Event::MainEventsCleared`. Only `ControlFlow::Exit` is taken into account, when it's set
+    /// the remaining windows are destroyed like at the end of `run`.
+    pub fn pump_events<H>(&self, timeout: Option<Duration>, mut event_handler: H) -> PumpStatus
+    where
+        H: FnMut(Event<T>, &mut ControlFlow),
+    {
+        let mut cf = match timeout {
+            Some(timeout) if timeout == Duration::from_secs(0) => ControlFlow::Poll,
+            Some(timeout) => ControlFlow::WaitUntil(Instant::now() + timeout),
+            None => ControlFlow::Wait,
+        };
+        if let Some(cause) = self.wait_for(cf) {
+            event_handler(Event::NewEvents(cause), &mut cf);
+        }
+        self.dispatch_events(&mut event_handler, &mut cf);
+        if cf == ControlFlow::Exit {
+            self.destroy_windows();
+            PumpStatus::Exit
+        } else {
+            PumpStatus::Continue
         }
     }
 }
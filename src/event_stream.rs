@@ -0,0 +1,249 @@
+//! Delivery of the events of an [`EventLoop`] through a [`Stream`], for applications driven by
+//! an async executor instead of `EventLoop::run`
+//!
+//! While there are no events a helper thread waits for the file descriptors of the display
+//! connection and of the sources registered with `EventLoop::register_fd`, then wakes the task
+//! that polls the stream. No reactor is needed so any executor works. The thread is started
+//! with the first stream or asynchronous clipboard load of the event loop and shared by all of
+//! them.
+
+use crate::{error::OSError, event::Event, event_loop::EventLoop, platform::*};
+pub use futures_core::Stream;
+use parking_lot::{Condvar, Mutex, MutexGuard};
+use std::{
+    collections::BTreeMap,
+    pin::Pin,
+    sync::Arc,
+    task::{Context, Poll, Waker},
+    thread::{self, JoinHandle},
+    time::{Duration, Instant},
+};
+
+/// The events of an event loop, returned by `EventLoop::events`
+///
+/// Only the events themselves are delivered, there are no `Event::NewEvents` or
+/// `Event::MainEventsCleared` since the executor decides when the application runs. The stream
+/// ends after an `Event::BackendError` or `Event::DisplayLost`.
+pub struct EventStream<'a, T: 'static> {
+    event_loop: &'a EventLoop<T>,
+    watcher: Arc<Watcher>,
+    key: u64,
+}
+
+impl<T: 'static> EventLoop<T> {
+    /// Returns a stream of the events of this loop, it replaces `run` and `pump_events`
+    pub fn events(&self) -> Result<EventStream<'_, T>, OSError> {
+        let watcher = self.watcher()?;
+        Ok(EventStream {
+            event_loop: self,
+            key: watcher.register(),
+            watcher,
+        })
+    }
+}
+
+impl<T: 'static> Stream for EventStream<'_, T> {
+    type Item = Event<T>;
+
+    fn poll_next(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Event<T>>> {
+        let this = self.get_mut();
+        if this.event_loop.is_closed() {
+            return Poll::Ready(None);
+        }
+        if let Some(event) = this.event_loop.next_dispatched_event() {
+            return Poll::Ready(Some(event));
+        }
+        // Collects what became ready since the last poll, registered sources only report their
+        // readiness through a wait
+        this.event_loop.wait(Some(Duration::from_secs(0)));
+        if let Some(event) = this.event_loop.next_dispatched_event() {
+            return Poll::Ready(Some(event));
+        }
+
+        let mut fds = match this.event_loop.connection().pollfds() {
+            Ok(fds) => fds,
+            Err(e) => {
+                this.event_loop.fail(e);
+                return Poll::Ready(this.event_loop.next_dispatched_event());
+            }
+        };
+        fds.extend(this.event_loop.source_pollfds());
+        let timeout = this.event_loop.wait_timeout(None);
+        this.watcher
+            .watch(this.key, fds, timeout, cx.waker().clone());
+        Poll::Pending
+    }
+}
+
+impl<T: 'static> Drop for EventStream<'_, T> {
+    fn drop(&mut self) {
+        self.watcher.forget(self.key);
+    }
+}
+
+/// Polls file descriptors on a helper thread and wakes the tasks that wait for them, shared by
+/// the streams and the asynchronous clipboard loads of an event loop
+pub(crate) struct Watcher {
+    shared: Arc<Shared>,
+    thread: Option<JoinHandle<()>>,
+}
+
+struct Shared {
+    state: Mutex<WatchState>,
+    changed: Condvar,
+    /// Interrupts the poll when the state changes
+    interrupt: WakeupFd,
+}
+
+#[derive(Default)]
+struct WatchState {
+    /// Removed when their task is woken, the thread sleeps while there are none
+    registrations: BTreeMap<u64, Registration>,
+    next_key: u64,
+    generation: u64,
+    polling: bool,
+    closed: bool,
+}
+
+struct Registration {
+    fds: Vec<libc::pollfd>,
+    deadline: Option<Instant>,
+    waker: Waker,
+    /// Tells a registration replaced during a poll from the one that was polled
+    generation: u64,
+}
+
+impl Watcher {
+    pub fn spawn() -> Result<Self, OSError> {
+        let shared = Arc::new(Shared {
+            state: Mutex::new(WatchState::default()),
+            changed: Condvar::new(),
+            interrupt: WakeupFd::new()?,
+        });
+        let thread = {
+            let shared = shared.clone();
+            thread::Builder::new()
+                .name("moving-watcher".to_owned())
+                .spawn(move || shared.run())?
+        };
+        Ok(Self {
+            shared,
+            thread: Some(thread),
+        })
+    }
+
+    /// Returns the key of a new registration, a task waiting for something passes it to
+    /// `watch` and `forget`
+    pub fn register(&self) -> u64 {
+        let mut state = self.shared.state.lock();
+        state.next_key += 1;
+        state.next_key
+    }
+
+    /// Wakes `waker` once one of `fds` is ready or `timeout` expires, replaces the previous
+    /// registration of `key`
+    pub fn watch(&self, key: u64, fds: Vec<libc::pollfd>, timeout: Option<Duration>, waker: Waker) {
+        let mut state = self.shared.state.lock();
+        state.generation += 1;
+        let registration = Registration {
+            fds,
+            deadline: timeout.map(|timeout| Instant::now() + timeout),
+            waker,
+            generation: state.generation,
+        };
+        state.registrations.insert(key, registration);
+        self.shared.notify(&state);
+    }
+
+    /// Stops waiting for the registration of `key`
+    pub fn forget(&self, key: u64) {
+        let mut state = self.shared.state.lock();
+        if state.registrations.remove(&key).is_some() {
+            self.shared.notify(&state);
+        }
+    }
+}
+
+impl Drop for Watcher {
+    fn drop(&mut self) {
+        let mut state = self.shared.state.lock();
+        state.closed = true;
+        self.shared.notify(&state);
+        drop(state);
+        if let Some(thread) = self.thread.take() {
+            // A task woken by the thread may drop the last reference to the watcher
+            if thread.thread().id() != thread::current().id() {
+                let _ = thread.join();
+            }
+        }
+    }
+}
+
+impl Shared {
+    fn notify(&self, state: &WatchState) {
+        if state.polling {
+            // A failure leaves the thread polling the old registrations, which wakes a task
+            // spuriously at worst
+            let _ = self.interrupt.wake();
+        }
+        self.changed.notify_one();
+    }
+
+    fn run(&self) {
+        let mut state = self.state.lock();
+        loop {
+            if state.closed {
+                return;
+            }
+            if state.registrations.is_empty() {
+                self.changed.wait(&mut state);
+                continue;
+            }
+            let now = Instant::now();
+            let timeout = state
+                .registrations
+                .values()
+                .filter_map(|registration| registration.deadline)
+                .min()
+                .map(|deadline| deadline.saturating_duration_since(now));
+            let mut fds = vec![self.interrupt.pollfd()];
+            let mut polled = Vec::with_capacity(state.registrations.len());
+            for (key, registration) in &state.registrations {
+                let range = fds.len()..fds.len() + registration.fds.len();
+                polled.push((*key, registration.generation, range));
+                fds.extend_from_slice(&registration.fds);
+            }
+
+            state.polling = true;
+            let result = MutexGuard::unlocked(&mut state, || poll_fds(&mut fds, timeout));
+            state.polling = false;
+
+            if fds[0].revents != 0 {
+                self.interrupt.clear();
+            }
+            if state.closed {
+                continue;
+            }
+            // A failed poll wakes everyone, the tasks find out what is wrong when they look
+            let now = Instant::now();
+            let mut wakers = Vec::new();
+            for (key, generation, range) in polled {
+                let woken = state.registrations.get(&key).is_some_and(|registration| {
+                    registration.generation == generation
+                        && (result.is_err()
+                            || fds[range].iter().any(|fd| fd.revents != 0)
+                            || registration
+                                .deadline
+                                .is_some_and(|deadline| deadline <= now))
+                });
+                if woken {
+                    if let Some(registration) = state.registrations.remove(&key) {
+                        wakers.push(registration.waker);
+                    }
+                }
+            }
+            // The woken tasks may register again right away
+            MutexGuard::unlocked(&mut state, || wakers.into_iter().for_each(Waker::wake));
+        }
+    }
+}
@@ -0,0 +1,118 @@
+//! Control over the in-memory backend, used to test applications without a display server
+//!
+//! The backend is only selected explicitly, with `MOVING_BACKEND=headless` or
+//! `EventLoopBuilder::with_backend(Backend::Headless)`. Events are only delivered when pushed
+//! here and every call to `Window::redraw` records a [`Frame`] instead of presenting the surface.
+
+use crate::{
+    clipboard::{mime::Mime, Selection},
+    error::OSError,
+    event::Event,
+    event_loop::{Backend, EventLoop},
+    platform::Connection,
+    surface,
+};
+#[cfg(feature = "windows")]
+use crate::{event::WindowEvent, window::Window};
+
+/// A copy of the surface of a window taken when it was redrawn
+#[derive(Debug, Clone, PartialEq)]
+pub struct Frame {
+    pub(crate) format: surface::Format,
+    pub(crate) width: u32,
+    pub(crate) height: u32,
+    pub(crate) data: Vec<u8>,
+}
+
+impl Frame {
+    pub fn format(&self) -> surface::Format {
+        self.format
+    }
+
+    pub fn width(&self) -> u32 {
+        self.width
+    }
+
+    pub fn height(&self) -> u32 {
+        self.height
+    }
+
+    /// The pixels, row by row without padding
+    pub fn data(&self) -> &[u8] {
+        &self.data
+    }
+
+    /// Returns the pixel at (x, y) encoded in the format of the frame
+    pub fn pixel(&self, x: u32, y: u32) -> Option<u32> {
+        if x >= self.width || y >= self.height {
+            return None;
+        }
+        let bpp = self.format.bytes_per_pixel();
+        let offset = (y * self.width + x) as usize * bpp;
+        Some(surface::read_pixel(&self.data[offset..offset + bpp]))
+    }
+}
+
+/// Returns `true` if `event_loop` runs on the headless backend
+pub fn is_active<T: 'static>(event_loop: &EventLoop<T>) -> bool {
+    event_loop.backend() == Backend::Headless
+}
+
+/// Queues an event to be delivered by `event_loop`
+pub fn push_event<T: 'static>(event_loop: &EventLoop<T>, event: Event) -> Result<(), OSError> {
+    push_to(event_loop.connection(), event)
+}
+
+/// Queues an event for `window`, a `WindowEvent::Resize` also resizes its surface when delivered
+#[cfg(feature = "windows")]
+pub fn push_window_event(window: &Window, event: WindowEvent) -> Result<(), OSError> {
+    push_to(
+        &window.connection,
+        Event::WindowEvent {
+            window: window.id,
+            event,
+        },
+    )
+}
+
+/// Returns the frames recorded by `window` since the last call, oldest first
+#[cfg(feature = "windows")]
+pub fn take_frames(window: &Window) -> Result<Vec<Frame>, OSError> {
+    window
+        .connection
+        .headless()
+        .ok_or(OSError::BackendUnavailable)?
+        .take_frames(window.id)
+}
+
+/// Makes `event_loop` lose its connection like when a display server crashes, it delivers the
+/// events pushed until now then `Event::DisplayLost`
+pub fn disconnect<T: 'static>(event_loop: &EventLoop<T>) -> Result<(), OSError> {
+    event_loop
+        .connection()
+        .headless()
+        .ok_or(OSError::BackendUnavailable)?
+        .disconnect()
+}
+
+/// Stores `data` on `selection` as if another application copied it, the application that
+/// owned the selection gets `Event::ClipboardOwnershipLost` then `Event::ClipboardChanged`
+pub fn store_as_other_client<T: 'static>(
+    event_loop: &EventLoop<T>,
+    selection: Selection,
+    media_type: Mime,
+    data: &[u8],
+) -> Result<(), OSError> {
+    event_loop
+        .connection()
+        .headless()
+        .ok_or(OSError::BackendUnavailable)?
+        .store_as_other_client(selection, media_type, data)
+}
+
+fn push_to(connection: &Connection, event: Event) -> Result<(), OSError> {
+    connection
+        .headless()
+        .ok_or(OSError::BackendUnavailable)?
+        .push_event(event)
+}
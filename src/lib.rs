@@ -1,7 +1,7 @@
-use lazy_static::lazy_static;
-
 mod platform;
 
+#[cfg(all(feature = "automation", target_os = "linux"))]
+pub mod automation;
 pub mod clipboard;
 #[cfg(feature = "dnd")]
 pub mod dnd;
@@ -9,7 +9,14 @@ pub mod dpi;
 pub mod error;
 pub mod event;
 pub mod event_loop;
+#[cfg(feature = "async")]
+pub mod event_stream;
+#[cfg(feature = "headless")]
+pub mod headless;
+#[cfg(feature = "record")]
+pub mod record;
 pub mod surface;
+pub mod text;
 #[cfg(feature = "windows")]
 pub mod window;
 
@@ -21,11 +28,6 @@ pub fn rect(x: f64, y: f64, w: f64, h: f64) -> Rect {
     euclid::rect(x, y, w, h)
 }
 
-lazy_static! {
-    static ref CONNECTION: crate::platform::Connection =
-        crate::platform::Connection::new().unwrap();
-}
-
 #[cfg(test)]
 mod tests {
     #[test]
@@ -0,0 +1,259 @@
+//! A backend without a display server, windows are plain memory buffers and events are injected
+//! by the application through [`crate::headless`]
+
+use super::{poll_fds, WakeupFd, WindowId, WindowPlatformData};
+use crate::{
+    clipboard::{ClipboardProvider, Content, Selection},
+    dpi::LogicalSize,
+    error::OSError,
+    event::*,
+    headless::Frame,
+    surface::{FrameBuffer, Surface},
+    window as mwin,
+};
+use atomic::Atomic;
+use mime::Mime;
+use parking_lot::{Mutex, RwLock};
+use std::{
+    collections::{BTreeMap, BTreeSet, VecDeque},
+    sync::{
+        atomic::{AtomicBool, AtomicU32, Ordering},
+        Arc,
+    },
+    time::Duration,
+};
+
+#[derive(Debug)]
+pub struct Connection {
+    events_queue: Mutex<VecDeque<Event>>,
+    wakeup_fd: WakeupFd,
+    clipboard_data: Mutex<BTreeMap<Selection, Content>>,
+    /// The selections whose content was stored by the application
+    owned_selections: Mutex<BTreeSet<Selection>>,
+    pub(super) windows: RwLock<BTreeMap<WindowId, Arc<RwLock<WindowPlatformData>>>>,
+    next_window_id: AtomicU32,
+    /// Set by `disconnect`, the queued events are still delivered
+    lost: AtomicBool,
+}
+
+#[derive(Debug)]
+pub struct Window {
+    surface: Surface,
+    frames: Mutex<Vec<Frame>>,
+}
+
+impl Connection {
+    pub fn new() -> Result<Self, OSError> {
+        Ok(Self {
+            events_queue: Mutex::new(VecDeque::new()),
+            wakeup_fd: WakeupFd::new()?,
+            clipboard_data: Mutex::new(BTreeMap::new()),
+            owned_selections: Mutex::new(BTreeSet::new()),
+            windows: RwLock::new(BTreeMap::new()),
+            next_window_id: AtomicU32::new(1),
+            lost: AtomicBool::new(false),
+        })
+    }
+
+    /// Fails like a connection to a display server that went away
+    pub fn disconnect(&self) -> Result<(), OSError> {
+        self.lost.store(true, Ordering::SeqCst);
+        self.wakeup()
+    }
+
+    fn check_connected(&self) -> Result<(), OSError> {
+        if self.lost.load(Ordering::SeqCst) {
+            Err(OSError::DisplayLost)
+        } else {
+            Ok(())
+        }
+    }
+
+    pub fn push_event(&self, event: Event) -> Result<(), OSError> {
+        self.events_queue.lock().push_back(event);
+        self.wakeup()
+    }
+
+    pub fn wakeup(&self) -> Result<(), OSError> {
+        self.wakeup_fd.wake()
+    }
+
+    /// Blocks until an event is pushed, one of `sources` is ready, `timeout` expires or
+    /// `wakeup` is called
+    pub fn wait(
+        &self,
+        timeout: Option<Duration>,
+        sources: &mut [libc::pollfd],
+    ) -> Result<(), OSError> {
+        if !self.events_queue.lock().is_empty() {
+            return Ok(());
+        }
+        let mut fds = vec![self.wakeup_fd.pollfd()];
+        fds.extend_from_slice(sources);
+        poll_fds(&mut fds, timeout)?;
+        sources.copy_from_slice(&fds[1..]);
+        Ok(())
+    }
+
+    #[cfg(feature = "async")]
+    pub fn pollfds(&self) -> Vec<libc::pollfd> {
+        vec![self.wakeup_fd.pollfd()]
+    }
+
+    pub fn poll_event(&self) -> Result<Option<Event>, OSError> {
+        // A wakeup from before this poll has nothing left to interrupt
+        self.wakeup_fd.clear();
+        let event = self.events_queue.lock().pop_front();
+        if event.is_none() {
+            self.check_connected()?;
+        }
+        if let Some(Event::WindowEvent {
+            window,
+            event: WindowEvent::Resize { width, height },
+        }) = event
+        {
+            // Like a real display server a resize gives the window a new buffer
+            if let Some(platform_data) = self.windows.read().get(&window) {
+                let platform_data = platform_data.read();
+                let surface = &platform_data.headless().surface;
+                let (width, height) = (width as u32, height as u32);
+                if surface.size() != (width, height) {
+                    let len = (width * height) as usize * surface.format().bytes_per_pixel();
+                    surface.replace_buffer(FrameBuffer::alloc(len), width, height);
+                }
+            }
+        }
+        Ok(event)
+    }
+
+    /// Returns the frames presented by `window` since the last call
+    pub fn take_frames(&self, window: WindowId) -> Result<Vec<Frame>, OSError> {
+        let windows = self.windows.read();
+        let platform_data = windows
+            .get(&window)
+            .ok_or_else(|| OSError::Other(format!("unknown window {:?}", window)))?;
+        let frames = std::mem::take(&mut *platform_data.read().headless().frames.lock());
+        Ok(frames)
+    }
+
+    // Clipboard
+    pub fn load_from_clipboard(
+        &self,
+        selection: Selection,
+        media_type: Mime,
+    ) -> Result<Option<Vec<u8>>, OSError> {
+        let content = match self.clipboard_data.lock().get(&selection) {
+            Some(content) => content.clone(),
+            None => return Ok(None),
+        };
+        let stored = content
+            .media_types()
+            .into_iter()
+            .find(|stored| stored.essence_str() == media_type.essence_str());
+        Ok(stored.and_then(|stored| content.provide(&stored)))
+    }
+
+    pub fn available_clipboard_types(&self, selection: Selection) -> Result<Vec<Mime>, OSError> {
+        Ok(self
+            .clipboard_data
+            .lock()
+            .get(&selection)
+            .map_or_else(Vec::new, Content::media_types))
+    }
+
+    pub fn store_on_clipboard(
+        &self,
+        selection: Selection,
+        media_type: Mime,
+        data: &[u8],
+    ) -> Result<(), OSError> {
+        let mut content = Content::default();
+        content.insert(media_type, data.to_vec());
+        self.clipboard_data.lock().insert(selection, content);
+        self.owned_selections.lock().insert(selection);
+        Ok(())
+    }
+
+    /// Stores `data` on `selection` like another client would, the application is told through
+    /// the events of the loop
+    pub fn store_as_other_client(
+        &self,
+        selection: Selection,
+        media_type: Mime,
+        data: &[u8],
+    ) -> Result<(), OSError> {
+        let mut content = Content::default();
+        content.insert(media_type, data.to_vec());
+        self.clipboard_data.lock().insert(selection, content);
+        if self.owned_selections.lock().remove(&selection) {
+            self.push_event(Event::ClipboardOwnershipLost { selection })?;
+        }
+        self.push_event(Event::ClipboardChanged { selection })
+    }
+
+    pub fn store_provider_on_clipboard(
+        &self,
+        selection: Selection,
+        provider: Arc<dyn ClipboardProvider>,
+    ) -> Result<(), OSError> {
+        self.clipboard_data
+            .lock()
+            .insert(selection, Content::Provider(provider));
+        self.owned_selections.lock().insert(selection);
+        Ok(())
+    }
+}
+
+#[cfg(feature = "windows")]
+impl Connection {
+    pub fn create_window(
+        &self,
+        builder: mwin::WindowBuilder,
+        connection: Arc<super::Connection>,
+    ) -> Result<mwin::Window, OSError> {
+        let id = WindowId::from_headless(self.next_window_id.fetch_add(1, Ordering::SeqCst));
+        let (width, height) = (builder.width as u32, builder.height as u32);
+        let len = (width * height) as usize * builder.surface_format.bytes_per_pixel();
+        let surface = Surface::new(
+            builder.surface_format,
+            FrameBuffer::alloc(len),
+            width,
+            height,
+        );
+
+        let window = Arc::new(RwLock::new(WindowPlatformData::Headless(Window {
+            surface: surface.clone(),
+            frames: Mutex::new(Vec::new()),
+        })));
+        self.windows.write().insert(id, window.clone());
+        Ok(mwin::Window {
+            id,
+            surface,
+            logical_size: Arc::new(Atomic::new(LogicalSize {
+                w: builder.width,
+                h: builder.height,
+            })),
+            dpi: Arc::new(Atomic::new(1.0)),
+            platform_data: window,
+            connection,
+        })
+    }
+
+    pub fn redraw_window(&self, window: &Window) -> Result<(), OSError> {
+        self.check_connected()?;
+        let guard = window.surface.lock();
+        let frame = Frame {
+            format: guard.format(),
+            width: guard.width(),
+            height: guard.height(),
+            data: guard.bytes().to_vec(),
+        };
+        drop(guard);
+        window.frames.lock().push(frame);
+        Ok(())
+    }
+
+    pub fn destroy_window(&self, _window: &mut Window) -> Result<(), OSError> {
+        Ok(())
+    }
+}
@@ -1,38 +1,161 @@
+#[cfg(feature = "headless")]
+#[path = "../headless.rs"]
+pub(crate) mod headless;
+#[cfg(feature = "wayland")]
 mod wayland;
+#[cfg(feature = "x11")]
 mod xcb;
 
-use crate::error::OSError;
+#[cfg(not(any(feature = "x11", feature = "wayland", feature = "headless")))]
+compile_error!("At least one of the `x11`, `wayland` and `headless` features is needed");
+
+use crate::{
+    clipboard::{ClipboardProvider, Selection},
+    error::OSError,
+    event_loop::Backend,
+};
+#[cfg(feature = "automation")]
+use crate::event::MouseButton;
 use crate::{event::Event, window::*};
 use mime::Mime;
+#[cfg(feature = "windows")]
+use parking_lot::RwLock;
+#[cfg(feature = "windows")]
+use std::collections::BTreeMap;
+#[cfg(any(feature = "wayland", feature = "headless", feature = "async"))]
+use std::os::unix::io::RawFd;
+use std::{fmt, io, sync::Arc, task::Poll, time::Duration};
 
 #[derive(Debug, Copy, Clone, Ord, PartialOrd, Eq, PartialEq, Hash)]
 pub struct WindowId(u32);
 
+/// Waits until one of `fds` is ready or `timeout` expires, `None` waits forever, and returns
+/// the number of ready file descriptors
+pub(crate) fn poll_fds(fds: &mut [libc::pollfd], timeout: Option<Duration>) -> Result<usize, OSError> {
+    // Rounded up so a wait never ends before the deadline
+    let timeout = timeout.map_or(-1, |timeout| {
+        let millis = timeout.as_nanos().div_ceil(1_000_000);
+        millis.min(i32::MAX as u128) as i32
+    });
+    loop {
+        let ready = unsafe { libc::poll(fds.as_mut_ptr(), fds.len() as _, timeout) };
+        if ready >= 0 {
+            return Ok(ready as usize);
+        }
+        let error = io::Error::last_os_error();
+        if error.kind() != io::ErrorKind::Interrupted {
+            return Err(error.into());
+        }
+    }
+}
+
+/// An eventfd that becomes readable when woken, used to interrupt a wait for events
+#[cfg(any(feature = "wayland", feature = "headless", feature = "async"))]
+#[derive(Debug)]
+pub(crate) struct WakeupFd(RawFd);
+
+#[cfg(any(feature = "wayland", feature = "headless", feature = "async"))]
+impl WakeupFd {
+    pub fn new() -> Result<Self, OSError> {
+        let fd = unsafe { libc::eventfd(0, libc::EFD_CLOEXEC | libc::EFD_NONBLOCK) };
+        if fd < 0 {
+            return Err(io::Error::last_os_error().into());
+        }
+        Ok(Self(fd))
+    }
+
+    pub fn wake(&self) -> Result<(), OSError> {
+        let value = 1u64.to_ne_bytes();
+        if unsafe { libc::write(self.0, value.as_ptr() as *const _, value.len()) } < 0 {
+            let error = io::Error::last_os_error();
+            // The counter is saturated so a wakeup is already pending
+            if error.kind() != io::ErrorKind::WouldBlock {
+                return Err(error.into());
+            }
+        }
+        Ok(())
+    }
+
+    pub fn clear(&self) {
+        let mut value = [0u8; 8];
+        unsafe { libc::read(self.0, value.as_mut_ptr() as *mut _, value.len()) };
+    }
+
+    pub fn pollfd(&self) -> libc::pollfd {
+        libc::pollfd {
+            fd: self.0,
+            events: libc::POLLIN,
+            revents: 0,
+        }
+    }
+}
+
+#[cfg(any(feature = "wayland", feature = "headless", feature = "async"))]
+impl Drop for WakeupFd {
+    fn drop(&mut self) {
+        unsafe { libc::close(self.0) };
+    }
+}
+
+/// Releases the surface buffers mapped with `mmap`
+#[cfg(any(feature = "x11", feature = "wayland"))]
+pub(crate) unsafe fn munmap_buffer(ptr: *mut u8, len: usize) {
+    libc::munmap(ptr as *mut _, len);
+}
+
 impl WindowId {
+    #[cfg(feature = "x11")]
     fn from_x11(x11: u32) -> Self {
         Self(x11)
     }
 
-    /*fn to_x11(&self) -> u32 {
+    #[cfg(feature = "automation")]
+    fn to_x11(self) -> u32 {
         self.0
-    }*/
+    }
 
+    #[cfg(feature = "wayland")]
     fn from_wayland(wayland: u32) -> Self {
         Self(wayland)
     }
 
+    #[cfg(feature = "wayland")]
     fn to_wayland(&self) -> u32 {
         self.0
     }
+
+    #[cfg(feature = "headless")]
+    fn from_headless(id: u32) -> Self {
+        Self(id)
+    }
+}
+
+/// A transfer of the content of a selection from its owner
+#[derive(Debug)]
+pub enum ClipboardLoad {
+    /// The result was known when the load started
+    Ready(Option<Vec<u8>>),
+    #[cfg(feature = "x11")]
+    Xcb(xcb::ClipboardLoad),
+    #[cfg(feature = "wayland")]
+    Wayland(wayland::ClipboardLoad),
 }
 
 #[derive(Debug)]
 pub enum WindowPlatformData {
+    #[cfg(feature = "x11")]
     Xcb(xcb::Window),
-    Wayland(wayland::Window),
+    // The proxies of the surfaces, their roles and buffers make it much larger than the others
+    #[cfg(feature = "wayland")]
+    Wayland(Box<wayland::Window>),
+    #[cfg(feature = "headless")]
+    Headless(headless::Window),
 }
 
+// With a single backend the fallback arms are unreachable
+#[allow(unreachable_patterns)]
 impl WindowPlatformData {
+    #[cfg(feature = "wayland")]
     fn wayland(&self) -> &wayland::Window {
         match self {
             Self::Wayland(ref w) => w,
@@ -40,6 +163,7 @@ impl WindowPlatformData {
         }
     }
 
+    #[cfg(feature = "wayland")]
     fn wayland_mut(&mut self) -> &mut wayland::Window {
         match self {
             Self::Wayland(ref mut w) => w,
@@ -47,6 +171,7 @@ impl WindowPlatformData {
         }
     }
 
+    #[cfg(feature = "x11")]
     fn xcb(&self) -> &xcb::Window {
         match self {
             Self::Xcb(ref x) => x,
@@ -54,74 +179,397 @@ impl WindowPlatformData {
         }
     }
 
+    #[cfg(feature = "x11")]
     fn xcb_mut(&mut self) -> &mut xcb::Window {
         match self {
             Self::Xcb(ref mut x) => x,
             _ => unreachable!(),
         }
     }
+
+    #[cfg(feature = "headless")]
+    fn headless(&self) -> &headless::Window {
+        match self {
+            Self::Headless(ref h) => h,
+            _ => unreachable!(),
+        }
+    }
+
+    #[cfg(feature = "headless")]
+    fn headless_mut(&mut self) -> &mut headless::Window {
+        match self {
+            Self::Headless(ref mut h) => h,
+            _ => unreachable!(),
+        }
+    }
 }
 
 unsafe impl Sync for WindowPlatformData {}
 unsafe impl Send for WindowPlatformData {}
 
 pub enum Connection {
+    #[cfg(feature = "wayland")]
     Wayland(wayland::Connection),
+    #[cfg(feature = "x11")]
     Xcb(xcb::Connection),
+    #[cfg(feature = "headless")]
+    Headless(headless::Connection),
+}
+
+impl fmt::Debug for Connection {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_tuple("Connection").field(&self.backend()).finish()
+    }
 }
 
 impl Connection {
-    pub fn new() -> Result<Self, OSError> {
-        match wayland_client::Display::connect_to_env() {
-            Ok(display) => Ok(Self::Wayland(wayland::Connection::from_display(display)?)),
-            // The error is not important the fact is that we need to make this work so fallback to X11
-            Err(_) => Ok(Self::Xcb(xcb::Connection::new()?)),
+    /// Connects to `backend`, or to the one named by `MOVING_BACKEND`, otherwise to the first
+    /// available among Wayland and X11. Headless is only used when it's chosen explicitly.
+    /// `clipboard_thread` is described by `EventLoopBuilder::with_clipboard_thread`
+    pub fn new(backend: Option<Backend>, clipboard_thread: bool) -> Result<Self, OSError> {
+        let backend = match backend {
+            Some(backend) => Some(backend),
+            None => match std::env::var("MOVING_BACKEND") {
+                Ok(name) => Some(name.parse()?),
+                Err(_) => None,
+            },
+        };
+        if let Some(backend) = backend {
+            return Self::connect(backend, clipboard_thread);
+        }
+        let mut error = OSError::BackendUnavailable;
+        for &backend in &[Backend::Wayland, Backend::X11] {
+            match Self::connect(backend, clipboard_thread) {
+                Ok(connection) => return Ok(connection),
+                // Backends left out of the build don't hide why the others failed
+                Err(OSError::BackendUnavailable) => {}
+                Err(e) => error = e,
+            }
+        }
+        Err(error)
+    }
+
+    #[cfg_attr(not(feature = "x11"), allow(unused_variables))]
+    fn connect(backend: Backend, clipboard_thread: bool) -> Result<Self, OSError> {
+        match backend {
+            #[cfg(feature = "wayland")]
+            Backend::Wayland => {
+                let display = wayland_client::Display::connect_to_env().map_err(|e| match e {
+                    wayland_client::ConnectError::NoWaylandLib => OSError::BackendUnavailable,
+                    _ => OSError::NoDisplay,
+                })?;
+                wayland::Connection::from_display(display).map(Self::Wayland)
+            }
+            #[cfg(feature = "x11")]
+            Backend::X11 => {
+                let connection = xcb::Connection::new()?;
+                if clipboard_thread {
+                    connection.with_clipboard_thread().map(Self::Xcb)
+                } else {
+                    Ok(Self::Xcb(connection))
+                }
+            }
+            #[cfg(feature = "headless")]
+            Backend::Headless => headless::Connection::new().map(Self::Headless),
+            #[allow(unreachable_patterns)]
+            _ => Err(OSError::BackendUnavailable),
+        }
+    }
+
+    pub fn backend(&self) -> Backend {
+        match self {
+            #[cfg(feature = "wayland")]
+            Self::Wayland(_) => Backend::Wayland,
+            #[cfg(feature = "x11")]
+            Self::Xcb(_) => Backend::X11,
+            #[cfg(feature = "headless")]
+            Self::Headless(_) => Backend::Headless,
+        }
+    }
+
+    #[cfg(feature = "headless")]
+    #[allow(unreachable_patterns)]
+    pub fn headless(&self) -> Option<&headless::Connection> {
+        match self {
+            Self::Headless(headless) => Some(headless),
+            _ => None,
         }
     }
 
     pub fn poll_event(&self) -> Result<Option<Event>, OSError> {
         match self {
+            #[cfg(feature = "wayland")]
             Self::Wayland(wl) => wl.poll_event(),
+            #[cfg(feature = "x11")]
             Self::Xcb(xcb) => xcb.poll_event(),
+            #[cfg(feature = "headless")]
+            Self::Headless(headless) => headless.poll_event(),
+        }
+    }
+
+    pub fn inject_event(&self, event: Event) -> Result<(), OSError> {
+        match self {
+            #[cfg(feature = "wayland")]
+            Self::Wayland(wl) => wl.inject_event(event),
+            #[cfg(feature = "x11")]
+            Self::Xcb(xcb) => xcb.inject_event(event),
+            #[cfg(feature = "headless")]
+            Self::Headless(headless) => headless.push_event(event),
+        }
+    }
+
+    /// Blocks until there are events to poll, one of `sources` is ready, `timeout` expires or
+    /// `wakeup` is called, the readiness of the sources is written to their `revents`
+    pub fn wait(
+        &self,
+        timeout: Option<Duration>,
+        sources: &mut [libc::pollfd],
+    ) -> Result<(), OSError> {
+        match self {
+            #[cfg(feature = "wayland")]
+            Self::Wayland(wl) => wl.wait(timeout, sources),
+            #[cfg(feature = "x11")]
+            Self::Xcb(xcb) => xcb.wait(timeout, sources),
+            #[cfg(feature = "headless")]
+            Self::Headless(headless) => headless.wait(timeout, sources),
+        }
+    }
+
+    /// Interrupts a wait for events of the event loop
+    pub fn wakeup(&self) -> Result<(), OSError> {
+        match self {
+            #[cfg(feature = "wayland")]
+            Self::Wayland(wl) => wl.wakeup(),
+            #[cfg(feature = "x11")]
+            Self::Xcb(xcb) => xcb.wakeup(),
+            #[cfg(feature = "headless")]
+            Self::Headless(headless) => headless.wakeup(),
+        }
+    }
+
+    /// Returns the file descriptors that become readable when there are events to poll or
+    /// `wakeup` is called
+    #[cfg(feature = "async")]
+    pub fn pollfds(&self) -> Result<Vec<libc::pollfd>, OSError> {
+        match self {
+            #[cfg(feature = "wayland")]
+            Self::Wayland(wl) => wl.pollfds(),
+            #[cfg(feature = "x11")]
+            Self::Xcb(xcb) => xcb.pollfds(),
+            #[cfg(feature = "headless")]
+            Self::Headless(headless) => Ok(headless.pollfds()),
         }
     }
 
     // Clipboard
-    pub fn load_from_clipboard(&self, media_type: Mime) -> Result<Option<Vec<u8>>, OSError> {
+    pub fn load_from_clipboard(
+        &self,
+        selection: Selection,
+        media_type: Mime,
+    ) -> Result<Option<Vec<u8>>, OSError> {
+        let mut load = self.start_clipboard_load(selection, media_type)?;
+        loop {
+            if let Poll::Ready(data) = self.poll_clipboard_load(&mut load)? {
+                return Ok(data);
+            }
+            let (mut fds, timeout) = self.clipboard_load_pollfds(&load)?;
+            poll_fds(&mut fds, timeout)?;
+        }
+    }
+
+    /// Asks the owner of `selection` for its content, the transfer advances with
+    /// `poll_clipboard_load`
+    pub fn start_clipboard_load(
+        &self,
+        selection: Selection,
+        media_type: Mime,
+    ) -> Result<ClipboardLoad, OSError> {
+        match self {
+            #[cfg(feature = "wayland")]
+            Self::Wayland(wl) => wl.start_clipboard_load(selection, media_type),
+            #[cfg(feature = "x11")]
+            Self::Xcb(xcb) => xcb.start_clipboard_load(selection, media_type),
+            #[cfg(feature = "headless")]
+            Self::Headless(headless) => Ok(ClipboardLoad::Ready(
+                headless.load_from_clipboard(selection, media_type)?,
+            )),
+        }
+    }
+
+    /// Reads what the owner of the selection sent without waiting for more
+    #[allow(unreachable_patterns)]
+    pub fn poll_clipboard_load(
+        &self,
+        load: &mut ClipboardLoad,
+    ) -> Result<Poll<Option<Vec<u8>>>, OSError> {
+        match (self, load) {
+            (_, ClipboardLoad::Ready(data)) => Ok(Poll::Ready(data.take())),
+            #[cfg(feature = "wayland")]
+            (Self::Wayland(_), ClipboardLoad::Wayland(load)) => load.poll(),
+            #[cfg(feature = "x11")]
+            (Self::Xcb(xcb), ClipboardLoad::Xcb(load)) => xcb.poll_clipboard_load(load),
+            _ => unreachable!("a load goes on with the connection that started it"),
+        }
+    }
+
+    /// Returns the file descriptors that become readable when the owner of the selection sends
+    /// something, and how long it may stay silent
+    #[allow(unreachable_patterns)]
+    pub fn clipboard_load_pollfds(
+        &self,
+        load: &ClipboardLoad,
+    ) -> Result<(Vec<libc::pollfd>, Option<Duration>), OSError> {
+        match (self, load) {
+            (_, ClipboardLoad::Ready(_)) => Ok((Vec::new(), Some(Duration::from_secs(0)))),
+            #[cfg(feature = "wayland")]
+            (Self::Wayland(_), ClipboardLoad::Wayland(load)) => Ok(load.pollfds()),
+            #[cfg(feature = "x11")]
+            (Self::Xcb(xcb), ClipboardLoad::Xcb(load)) => xcb.clipboard_load_pollfds(load),
+            _ => unreachable!("a load goes on with the connection that started it"),
+        }
+    }
+
+    pub fn available_clipboard_types(&self, selection: Selection) -> Result<Vec<Mime>, OSError> {
+        match self {
+            #[cfg(feature = "wayland")]
+            Self::Wayland(wl) => wl.available_clipboard_types(selection),
+            #[cfg(feature = "x11")]
+            Self::Xcb(xcb) => xcb.available_clipboard_types(selection),
+            #[cfg(feature = "headless")]
+            Self::Headless(headless) => headless.available_clipboard_types(selection),
+        }
+    }
+
+    pub fn store_on_clipboard(
+        &self,
+        selection: Selection,
+        media_type: mime::Mime,
+        data: &[u8],
+    ) -> Result<(), OSError> {
         match self {
-            Self::Wayland(_) => todo!(),
-            Self::Xcb(xcb) => xcb.load_from_clipboard(media_type),
+            #[cfg(feature = "wayland")]
+            Self::Wayland(wl) => wl.store_on_clipboard(selection, media_type, data),
+            #[cfg(feature = "x11")]
+            Self::Xcb(xcb) => xcb.store_on_clipboard(selection, media_type, data),
+            #[cfg(feature = "headless")]
+            Self::Headless(headless) => headless.store_on_clipboard(selection, media_type, data),
         }
     }
 
-    pub fn store_on_clipboard(&self, media_type: mime::Mime, data: &[u8]) -> Result<(), OSError> {
+    pub fn store_provider_on_clipboard(
+        &self,
+        selection: Selection,
+        provider: Arc<dyn ClipboardProvider>,
+    ) -> Result<(), OSError> {
         match self {
-            Self::Wayland(_) => todo!(),
-            Self::Xcb(xcb) => xcb.store_on_clipboard(media_type, data),
+            #[cfg(feature = "wayland")]
+            Self::Wayland(wl) => wl.store_provider_on_clipboard(selection, provider),
+            #[cfg(feature = "x11")]
+            Self::Xcb(xcb) => xcb.store_provider_on_clipboard(selection, provider),
+            #[cfg(feature = "headless")]
+            Self::Headless(headless) => headless.store_provider_on_clipboard(selection, provider),
         }
     }
 }
 
-#[cfg(feature = "windows")]
+#[cfg(feature = "automation")]
+#[allow(unreachable_patterns)]
 impl Connection {
-    pub fn create_window(&self, builder: WindowBuilder) -> Result<Window, OSError> {
+    pub fn fake_pointer_motion(
+        &self,
+        window: Option<WindowId>,
+        x: i16,
+        y: i16,
+    ) -> Result<(), OSError> {
+        match self {
+            Self::Xcb(xcb) => xcb.fake_pointer_motion(window, x, y),
+            _ => Err(OSError::BackendUnavailable),
+        }
+    }
+
+    pub fn fake_button(&self, button: MouseButton, pressed: bool) -> Result<(), OSError> {
+        match self {
+            Self::Xcb(xcb) => xcb.fake_button(button, pressed),
+            _ => Err(OSError::BackendUnavailable),
+        }
+    }
+
+    pub fn fake_key(&self, keycode: u8, pressed: bool) -> Result<(), OSError> {
+        match self {
+            Self::Xcb(xcb) => xcb.fake_key(keycode, pressed),
+            _ => Err(OSError::BackendUnavailable),
+        }
+    }
+
+    pub fn sync(&self) -> Result<(), OSError> {
         match self {
-            Self::Wayland(wl) => wl.create_window(builder),
-            Self::Xcb(xcb) => xcb.create_window(builder),
+            Self::Xcb(xcb) => xcb.sync(),
+            _ => Err(OSError::BackendUnavailable),
+        }
+    }
+}
+
+#[cfg(feature = "windows")]
+impl Connection {
+    /// The window keeps `self` alive to be redrawn
+    pub fn create_window(self: &Arc<Self>, builder: WindowBuilder) -> Result<Window, OSError> {
+        let connection = self.clone();
+        match &**self {
+            #[cfg(feature = "wayland")]
+            Self::Wayland(wl) => wl.create_window(builder, connection),
+            #[cfg(feature = "x11")]
+            Self::Xcb(xcb) => xcb.create_window(builder, connection),
+            #[cfg(feature = "headless")]
+            Self::Headless(headless) => headless.create_window(builder, connection),
         }
     }
 
-    pub fn destroy_window(&self, window: &mut WindowPlatformData) -> Result<(), OSError> {
+    /// Destroys the window unless it already was, both the window and the event loop may try
+    pub fn destroy_window(&self, id: WindowId) -> Result<(), OSError> {
+        let platform_data = match self.windows().write().remove(&id) {
+            Some(platform_data) => platform_data,
+            None => return Ok(()),
+        };
+        let mut window = platform_data.write();
         match self {
+            #[cfg(feature = "wayland")]
             Self::Wayland(wl) => wl.destroy_window(window.wayland_mut()),
+            #[cfg(feature = "x11")]
             Self::Xcb(xcb) => xcb.destroy_window(window.xcb_mut()),
+            #[cfg(feature = "headless")]
+            Self::Headless(headless) => headless.destroy_window(window.headless_mut()),
         }
     }
 
-    pub fn redraw_window(&self, window: &Window) {
+    /// Whether the window was created on this connection and is not destroyed yet
+    pub fn has_window(&self, id: WindowId) -> bool {
+        self.windows().read().contains_key(&id)
+    }
+
+    fn windows(&self) -> &RwLock<BTreeMap<WindowId, Arc<RwLock<WindowPlatformData>>>> {
         match self {
+            #[cfg(feature = "wayland")]
+            Self::Wayland(wl) => &wl.windows,
+            #[cfg(feature = "x11")]
+            Self::Xcb(xcb) => &xcb.windows,
+            #[cfg(feature = "headless")]
+            Self::Headless(headless) => &headless.windows,
+        }
+    }
+
+    pub fn redraw_window(&self, window: &Window) -> Result<(), OSError> {
+        if !self.has_window(window.id) {
+            return Err(OSError::WindowClosed);
+        }
+        match self {
+            #[cfg(feature = "wayland")]
             Self::Wayland(wl) => wl.redraw_window(window.platform_data.read().wayland()),
+            #[cfg(feature = "x11")]
             Self::Xcb(xcb) => xcb.redraw_window(window.platform_data.read().xcb()),
+            #[cfg(feature = "headless")]
+            Self::Headless(headless) => {
+                headless.redraw_window(window.platform_data.read().headless())
+            }
         }
     }
-}
\ No newline at end of file
+}
@@ -1,51 +1,576 @@
-use std::{cell::RefCell, cmp::Ordering, rc::Rc};
+use super::Connection;
+use crate::{
+    clipboard::{self, ClipboardProvider, Content, Selection},
+    error::OSError,
+    event::Event,
+    platform,
+};
 use mime::Mime;
-use wayland_client::{protocol::wl_data_offer::WlDataOffer, Main};
+use parking_lot::Mutex;
+use std::{
+    fs::File,
+    io::{self, Read, Write},
+    mem,
+    os::unix::io::{AsRawFd, FromRawFd, RawFd},
+    sync::{atomic::Ordering, Arc},
+    task::Poll,
+    time::{Duration, Instant},
+};
+use wayland_client::{
+    protocol::{
+        wl_data_offer::{self, WlDataOffer},
+        wl_data_source::{self, WlDataSource},
+    },
+    Main,
+};
+use wayland_protocols::unstable::primary_selection::v1::client::{
+    zwp_primary_selection_offer_v1::{self, ZwpPrimarySelectionOfferV1},
+    zwp_primary_selection_source_v1::{self, ZwpPrimarySelectionSourceV1},
+};
 
+/// How long the owner of the clipboard may stay silent before a load fails, lazy sources may
+/// produce the data when it's asked for
+const TRANSFER_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// How long another client may stop reading the content of a selection it asked for
+const SEND_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// Other names of UTF-8 `text/plain`, some clients only know the ones of X11. The ones asking
+/// for `STRING` get the text in Latin-1
+const TEXT_ALIASES: &[&str] = &["text/plain;charset=utf-8", "UTF8_STRING", "STRING", "TEXT"];
+
+/// The clipboard goes through `wl_data_device`, the primary selection through its own protocol
+#[derive(Debug)]
+enum OfferProxy {
+    Clipboard(Main<WlDataOffer>),
+    Primary(Main<ZwpPrimarySelectionOfferV1>),
+}
+
+/// The types offered by another client for a selection, destroyed when dropped
 #[derive(Debug)]
 pub struct DataOffer {
-    wl_data_offer: Main<WlDataOffer>,
-    mime_types: Rc<RefCell<Vec<Mime>>>
+    proxy: OfferProxy,
+    mime_types: Arc<Mutex<Vec<String>>>,
 }
 
 impl DataOffer {
     pub fn from_wl(wl_data_offer: Main<WlDataOffer>) -> Self {
-        let mime_types = Rc::new(RefCell::new(Vec::<Mime>::new()));
+        let mime_types = Arc::new(Mutex::new(Vec::new()));
         let events_mime_types = mime_types.clone();
         wl_data_offer.quick_assign(move |_, event, _| {
-            use wayland_client::protocol::wl_data_offer::Event;
-            match event {
-                Event::Offer { mime_type } => {
-                    println!("{}", mime_type);
-                    let mime_type = match mime_type.parse() {
-                        Ok(mime_type) => mime_type,
-                        Err(_) => {
-                            return;
-                        }
-                    };
-                    events_mime_types.borrow_mut().push(mime_type);
-                },
-                _ => {}
+            if let wl_data_offer::Event::Offer { mime_type } = event {
+                events_mime_types.lock().push(mime_type);
+            }
+        });
+        Self {
+            proxy: OfferProxy::Clipboard(wl_data_offer),
+            mime_types,
+        }
+    }
+
+    pub fn from_primary(offer: Main<ZwpPrimarySelectionOfferV1>) -> Self {
+        let mime_types = Arc::new(Mutex::new(Vec::new()));
+        let events_mime_types = mime_types.clone();
+        offer.quick_assign(move |_, event, _| {
+            if let zwp_primary_selection_offer_v1::Event::Offer { mime_type } = event {
+                events_mime_types.lock().push(mime_type);
+            }
+        });
+        Self {
+            proxy: OfferProxy::Primary(offer),
+            mime_types,
+        }
+    }
+
+    /// Asks the owner of the selection to write the data to `fd`
+    fn receive(&self, mime_type: String, fd: RawFd) {
+        match &self.proxy {
+            OfferProxy::Clipboard(offer) => offer.receive(mime_type, fd),
+            OfferProxy::Primary(offer) => offer.receive(mime_type, fd),
+        }
+    }
+
+    /// Returns the offered type under which `media_type` can be received
+    fn find_type(&self, media_type: &Mime) -> Option<String> {
+        let mime_types = self.mime_types.lock();
+        let same_essence = mime_types.iter().find(|offered| {
+            offered
+                .parse::<Mime>()
+                .is_ok_and(|offered| offered.essence_str() == media_type.essence_str())
+        });
+        if let Some(offered) = same_essence {
+            return Some(offered.clone());
+        }
+        // `STRING` is Latin-1 and the encoding of `TEXT` is unknown
+        let alias = if clipboard::is_utf8_text(media_type) {
+            "UTF8_STRING"
+        } else if clipboard::is_latin1_text(media_type) {
+            "STRING"
+        } else {
+            return None;
+        };
+        mime_types.iter().find(|offered| *offered == alias).cloned()
+    }
+}
+
+impl Drop for DataOffer {
+    fn drop(&mut self) {
+        match &self.proxy {
+            OfferProxy::Clipboard(offer) => offer.destroy(),
+            OfferProxy::Primary(offer) => offer.destroy(),
+        }
+    }
+}
+
+/// The source through which this connection owns a selection
+#[derive(Debug)]
+enum DataSource {
+    Clipboard(Main<WlDataSource>),
+    Primary(Main<ZwpPrimarySelectionSourceV1>),
+}
+
+impl DataSource {
+    fn id(&self) -> u32 {
+        match self {
+            Self::Clipboard(source) => source.as_ref().id(),
+            Self::Primary(source) => source.as_ref().id(),
+        }
+    }
+
+    fn offer(&self, mime_type: String) {
+        match self {
+            Self::Clipboard(source) => source.offer(mime_type),
+            Self::Primary(source) => source.offer(mime_type),
+        }
+    }
+}
+
+/// Data for another client that did not fit in its pipe, the rest is written by the event loop
+/// once the client reads
+#[derive(Debug)]
+pub struct PendingSend {
+    /// Non-blocking so a slow client doesn't hold up the events, closing it ends the data
+    file: File,
+    data: Vec<u8>,
+    written: usize,
+    /// The client is given up on if it doesn't read until then
+    deadline: Instant,
+}
+
+impl PendingSend {
+    fn new(file: File, data: Vec<u8>) -> Result<Self, OSError> {
+        set_nonblocking(&file)?;
+        Ok(Self {
+            file,
+            data,
+            written: 0,
+            deadline: Instant::now() + SEND_TIMEOUT,
+        })
+    }
+
+    /// Writes what the pipe takes, returns whether the transfer is over
+    fn write(&mut self) -> bool {
+        while self.written < self.data.len() {
+            match self.file.write(&self.data[self.written..]) {
+                Ok(n) => {
+                    self.written += n;
+                    self.deadline = Instant::now() + SEND_TIMEOUT;
+                }
+                Err(e) if e.kind() == io::ErrorKind::Interrupted => {}
+                Err(e) if e.kind() == io::ErrorKind::WouldBlock => {
+                    return Instant::now() >= self.deadline;
+                }
+                // The receiver may give up, it's not our concern
+                Err(_) => return true,
+            }
+        }
+        true
+    }
+
+    fn pollfd(&self) -> libc::pollfd {
+        libc::pollfd {
+            fd: self.file.as_raw_fd(),
+            events: libc::POLLOUT,
+            revents: 0,
+        }
+    }
+}
+
+/// What this connection knows of one of the selections
+#[derive(Debug)]
+pub struct SelectionState {
+    selection: Selection,
+    events_sender: flume::Sender<Event>,
+    /// Shared by the selections of the connection
+    sends: Arc<Mutex<Vec<PendingSend>>>,
+    /// The offer of the current content, `None` when it's empty
+    offer: Mutex<Option<DataOffer>>,
+    /// Set while this connection owns the selection
+    source: Mutex<Option<DataSource>>,
+    content: Mutex<Content>,
+}
+
+impl SelectionState {
+    pub fn new(
+        selection: Selection,
+        events_sender: flume::Sender<Event>,
+        sends: Arc<Mutex<Vec<PendingSend>>>,
+    ) -> Self {
+        Self {
+            selection,
+            events_sender,
+            sends,
+            offer: Mutex::new(None),
+            source: Mutex::new(None),
+            content: Mutex::new(Content::default()),
+        }
+    }
+
+    /// Takes the offer of the selection announced by the compositor
+    pub fn set_offer(&self, offer: Option<DataOffer>) {
+        // The previous offer is destroyed when dropped
+        *self.offer.lock() = offer;
+        // The selection set by this connection comes back to it
+        if self.source.lock().is_none() {
+            let _ = self.events_sender.send(Event::ClipboardChanged {
+                selection: self.selection,
+            });
+        }
+    }
+
+    /// Answers another client that asked for the data, what doesn't fit in the pipe is left to
+    /// the event loop
+    fn send(&self, mime_type: &str, fd: RawFd) {
+        // Closing the file tells the receiver that all the data was sent
+        let file = unsafe { File::from_raw_fd(fd) };
+        // A provider produces the data without holding the lock
+        let content = self.content.lock().clone();
+        let data = match stored_data(&content, mime_type) {
+            Some(data) => data,
+            None => return,
+        };
+        if let Ok(mut send) = PendingSend::new(file, data) {
+            if !send.write() {
+                self.sends.lock().push(send);
             }
+        }
+    }
+
+    /// Another client owns the selection now, unless `source_id` was replaced by this connection
+    fn cancel(&self, source_id: u32) {
+        let mut source = self.source.lock();
+        if source.as_ref().map(DataSource::id) != Some(source_id) {
+            return;
+        }
+        *source = None;
+        *self.content.lock() = Content::default();
+        let _ = self.events_sender.send(Event::ClipboardOwnershipLost {
+            selection: self.selection,
         });
-        Self { wl_data_offer, mime_types }
     }
 }
 
-impl Ord for DataOffer {
-    fn cmp(&self, other: &DataOffer) -> Ordering {
-        self.wl_data_offer.as_ref().id().cmp(&other.wl_data_offer.as_ref().id())
+/// Returns the stored data that a client asking for `mime_type` gets, UTF-8 text is converted
+/// for the ones asking for Latin-1
+fn stored_data(content: &Content, mime_type: &str) -> Option<Vec<u8>> {
+    let requested = match mime_type {
+        "UTF8_STRING" | "TEXT" => mime::TEXT_PLAIN_UTF_8,
+        "STRING" => clipboard::text_plain_latin1(),
+        mime_type => mime_type.parse::<Mime>().ok()?,
+    };
+    let media_types = content.media_types();
+    let find = |matches: &dyn Fn(&Mime) -> bool| media_types.iter().find(|stored| matches(stored));
+    if clipboard::is_utf8_text(&requested) {
+        return content.provide(find(&clipboard::is_utf8_text)?);
+    }
+    if clipboard::is_latin1_text(&requested) {
+        if let Some(latin1) = find(&clipboard::is_latin1_text) {
+            return content.provide(latin1);
+        }
+        let text = content.provide(find(&clipboard::is_utf8_text)?)?;
+        return Some(clipboard::utf8_to_latin1(&text));
     }
+    let same_essence = find(&|stored| stored.essence_str() == requested.essence_str())?;
+    content.provide(same_essence)
 }
-impl PartialOrd for DataOffer {
-    fn partial_cmp(&self, other: &DataOffer) -> Option<Ordering> {
-        self.wl_data_offer.as_ref().id().partial_cmp(&other.wl_data_offer.as_ref().id())
+
+fn pipe() -> Result<(File, File), OSError> {
+    let mut fds = [0; 2];
+    if unsafe { libc::pipe2(fds.as_mut_ptr(), libc::O_CLOEXEC) } != 0 {
+        return Err(io::Error::last_os_error().into());
     }
+    Ok(unsafe { (File::from_raw_fd(fds[0]), File::from_raw_fd(fds[1])) })
 }
 
-impl Eq for DataOffer {}
-impl PartialEq for DataOffer {
-    fn eq(&self, other: &DataOffer) -> bool {
-        self.wl_data_offer.as_ref().id() == other.wl_data_offer.as_ref().id()
+fn set_nonblocking(file: &File) -> Result<(), OSError> {
+    let fd = file.as_raw_fd();
+    let flags = unsafe { libc::fcntl(fd, libc::F_GETFL) };
+    if flags < 0 || unsafe { libc::fcntl(fd, libc::F_SETFL, flags | libc::O_NONBLOCK) } < 0 {
+        return Err(io::Error::last_os_error().into());
     }
-}
\ No newline at end of file
+    Ok(())
+}
+
+/// The data written by the owner of a selection to a pipe, it ends when the owner closes it
+#[derive(Debug)]
+pub struct ClipboardLoad {
+    /// Non-blocking so a load never waits for the owner
+    reader: File,
+    data: Vec<u8>,
+    /// The load fails if the owner stays silent until then
+    deadline: Instant,
+}
+
+impl ClipboardLoad {
+    pub fn poll(&mut self) -> Result<Poll<Option<Vec<u8>>>, OSError> {
+        let mut buffer = [0; 4096];
+        loop {
+            match self.reader.read(&mut buffer) {
+                Ok(0) => return Ok(Poll::Ready(Some(mem::take(&mut self.data)))),
+                Ok(n) => {
+                    self.data.extend_from_slice(&buffer[..n]);
+                    self.deadline = Instant::now() + TRANSFER_TIMEOUT;
+                }
+                Err(e) if e.kind() == io::ErrorKind::Interrupted => {}
+                Err(e) if e.kind() == io::ErrorKind::WouldBlock => {
+                    // The owner of the clipboard does not give us its data, what came is
+                    // incomplete
+                    if Instant::now() >= self.deadline {
+                        return Err(OSError::Timeout);
+                    }
+                    return Ok(Poll::Pending);
+                }
+                Err(e) => return Err(e.into()),
+            }
+        }
+    }
+
+    pub fn pollfds(&self) -> (Vec<libc::pollfd>, Option<Duration>) {
+        let fd = libc::pollfd {
+            fd: self.reader.as_raw_fd(),
+            events: libc::POLLIN,
+            revents: 0,
+        };
+        let timeout = self.deadline.saturating_duration_since(Instant::now());
+        (vec![fd], Some(timeout))
+    }
+}
+
+impl Connection {
+    /// Writes what the clients that asked for the selections read since the last call
+    pub(super) fn continue_sends(&self) {
+        self.sends.lock().retain_mut(|send| !send.write());
+    }
+
+    /// Returns the file descriptors that become writable when a client reads what was sent
+    pub(super) fn sends_pollfds(&self) -> Vec<libc::pollfd> {
+        self.sends.lock().iter().map(PendingSend::pollfd).collect()
+    }
+
+    fn selection_state(&self, selection: Selection) -> &Arc<SelectionState> {
+        match selection {
+            Selection::Clipboard => &self.clipboard,
+            Selection::Primary => &self.primary,
+        }
+    }
+
+    pub fn start_clipboard_load(
+        &self,
+        selection: Selection,
+        media_type: Mime,
+    ) -> Result<platform::ClipboardLoad, OSError> {
+        // Takes note of the selection changes announced since the last dispatch
+        self.read_events(Some(Duration::from_secs(0)), &mut [])?;
+        let state = self.selection_state(selection);
+        // The compositor would ask this connection for the data while nothing dispatches its
+        // events
+        if state.source.lock().is_some() {
+            let content = state.content.lock().clone();
+            let data = stored_data(&content, media_type.as_ref());
+            return Ok(platform::ClipboardLoad::Ready(data));
+        }
+
+        let current_offer = state.offer.lock();
+        let offer = match &*current_offer {
+            Some(offer) => offer,
+            None => return Ok(platform::ClipboardLoad::Ready(None)),
+        };
+        let mime_type = match offer.find_type(&media_type) {
+            Some(mime_type) => mime_type,
+            None => return Ok(platform::ClipboardLoad::Ready(None)),
+        };
+        let (reader, writer) = pipe()?;
+        set_nonblocking(&reader)?;
+        offer.receive(mime_type, writer.as_raw_fd());
+        drop(current_offer);
+        self.flush()?;
+        // The owner of the selection has its own copy, the end of the data is when it closes it
+        drop(writer);
+        Ok(platform::ClipboardLoad::Wayland(ClipboardLoad {
+            reader,
+            data: Vec::new(),
+            deadline: Instant::now() + TRANSFER_TIMEOUT,
+        }))
+    }
+
+    pub fn available_clipboard_types(&self, selection: Selection) -> Result<Vec<Mime>, OSError> {
+        self.read_events(Some(Duration::from_secs(0)), &mut [])?;
+        let state = self.selection_state(selection);
+        if state.source.lock().is_some() {
+            return Ok(state.content.lock().media_types());
+        }
+        Ok(match &*state.offer.lock() {
+            Some(offer) => clipboard::media_types(offer.mime_types.lock().iter()),
+            None => Vec::new(),
+        })
+    }
+
+    pub fn store_on_clipboard(
+        &self,
+        selection: Selection,
+        media_type: Mime,
+        data: &[u8],
+    ) -> Result<(), OSError> {
+        let source = self.create_source(selection)?;
+        let state = self.selection_state(selection);
+        state.content.lock().insert(media_type, data.to_owned());
+        self.offer_selection(state, source)
+    }
+
+    pub fn store_provider_on_clipboard(
+        &self,
+        selection: Selection,
+        provider: Arc<dyn ClipboardProvider>,
+    ) -> Result<(), OSError> {
+        let source = self.create_source(selection)?;
+        let state = self.selection_state(selection);
+        *state.content.lock() = Content::Provider(provider);
+        self.offer_selection(state, source)
+    }
+
+    fn create_source(&self, selection: Selection) -> Result<DataSource, OSError> {
+        match selection {
+            Selection::Clipboard => Ok(DataSource::Clipboard(
+                self.data_device_manager.create_data_source(),
+            )),
+            Selection::Primary => match &self.primary_device {
+                Some((manager, _)) => Ok(DataSource::Primary(manager.create_source())),
+                None => Err(OSError::Other(
+                    "the compositor has no primary selection".to_owned(),
+                )),
+            },
+        }
+    }
+
+    /// Makes `source` the selection, it offers the content stored in `state`
+    fn offer_selection(
+        &self,
+        state: &Arc<SelectionState>,
+        source: DataSource,
+    ) -> Result<(), OSError> {
+        for media_type in state.content.lock().media_types() {
+            source.offer(media_type.to_string());
+            if clipboard::is_utf8_text(&media_type) {
+                for alias in TEXT_ALIASES {
+                    source.offer(alias.to_string());
+                }
+            }
+        }
+
+        let serial = self.input_serial.load(Ordering::SeqCst);
+        let events_state = state.clone();
+        match &source {
+            DataSource::Clipboard(source) => {
+                source.quick_assign(move |source, event, _| match event {
+                    wl_data_source::Event::Send { mime_type, fd } => {
+                        events_state.send(&mime_type, fd)
+                    }
+                    wl_data_source::Event::Cancelled => {
+                        events_state.cancel(source.as_ref().id());
+                        source.destroy();
+                    }
+                    _ => {}
+                });
+                self.data_device.set_selection(Some(source), serial);
+            }
+            DataSource::Primary(source) => {
+                source.quick_assign(move |source, event, _| match event {
+                    zwp_primary_selection_source_v1::Event::Send { mime_type, fd } => {
+                        events_state.send(&mime_type, fd)
+                    }
+                    zwp_primary_selection_source_v1::Event::Cancelled => {
+                        events_state.cancel(source.as_ref().id());
+                        source.destroy();
+                    }
+                    _ => {}
+                });
+                if let Some((_, device)) = &self.primary_device {
+                    device.set_selection(Some(source), serial);
+                }
+            }
+        }
+        // The previous source is cancelled by the compositor
+        *state.source.lock() = Some(source);
+        self.flush()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn stored_text_answers_its_aliases() {
+        let mut content = Content::default();
+        content.insert(mime::TEXT_PLAIN, "text é€".into());
+        content.insert(mime::IMAGE_PNG, b"png".to_vec());
+        for name in [
+            "text/plain",
+            "text/plain;charset=utf-8",
+            "UTF8_STRING",
+            "TEXT",
+        ] {
+            assert_eq!(stored_data(&content, name), Some("text é€".into()));
+        }
+        for name in ["text/plain;charset=iso-8859-1", "STRING"] {
+            assert_eq!(stored_data(&content, name), Some(b"text \xe9?".to_vec()));
+        }
+        assert_eq!(stored_data(&content, "image/png"), Some(b"png".to_vec()));
+        assert_eq!(stored_data(&content, "text/html"), None);
+    }
+
+    #[test]
+    fn sends_go_on_when_the_receiver_reads() {
+        let (mut reader, writer) = pipe().unwrap();
+        // Larger than the buffer of a pipe
+        let data = (0..1 << 20).map(|i| i as u8).collect::<Vec<_>>();
+        let mut send = PendingSend::new(writer, data.clone()).unwrap();
+        assert!(!send.write());
+
+        let mut received = Vec::new();
+        let mut buffer = [0; 4096];
+        while !send.write() {
+            let n = reader.read(&mut buffer).unwrap();
+            received.extend_from_slice(&buffer[..n]);
+        }
+        drop(send);
+        reader.read_to_end(&mut received).unwrap();
+        assert_eq!(received, data);
+    }
+
+    #[test]
+    fn silent_owners_fail_the_load() {
+        let (reader, mut writer) = pipe().unwrap();
+        set_nonblocking(&reader).unwrap();
+        let mut load = ClipboardLoad {
+            reader,
+            data: Vec::new(),
+            deadline: Instant::now() + TRANSFER_TIMEOUT,
+        };
+        writer.write_all(b"partial").unwrap();
+        assert!(matches!(load.poll(), Ok(Poll::Pending)));
+
+        load.deadline = Instant::now();
+        assert!(matches!(load.poll(), Err(OSError::Timeout)));
+    }
+}
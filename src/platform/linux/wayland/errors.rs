@@ -0,0 +1,23 @@
+use crate::error::*;
+use std::io;
+use wayland_client::{Display, GlobalError};
+
+impl From<GlobalError> for OSError {
+    fn from(e: GlobalError) -> Self {
+        OSError::Other(format!("unusable Wayland global: {}", e))
+    }
+}
+
+/// Gives the reason why reading from or writing to the compositor failed, the protocol error it
+/// sent if any
+pub(super) fn dispatch_error(display: &Display, e: io::Error) -> OSError {
+    match display.protocol_error() {
+        Some(error) => OSError::WaylandProtocol {
+            interface: error.object_interface.to_owned(),
+            object_id: error.object_id,
+            code: error.code,
+            message: error.message,
+        },
+        None => OSError::from_display_io(e),
+    }
+}
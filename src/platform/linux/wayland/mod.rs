@@ -4,26 +4,44 @@ mod window;
 pub use window::*;
 
 mod data_exchange;
+mod errors;
 use crate::{
+    clipboard::Selection,
     error::OSError,
     event::*,
-    platform::{WindowId, WindowPlatformData},
+    platform::{poll_fds, WakeupFd, WindowId, WindowPlatformData},
+    surface,
 };
-use data_exchange::DataOffer;
 use atomic::Atomic;
-use mime::Mime;
+pub use data_exchange::ClipboardLoad;
+use data_exchange::{DataOffer, PendingSend, SelectionState};
 use parking_lot::{Mutex, RwLock};
-use std::{collections::BTreeMap, sync::Arc, rc::Rc, cell::RefCell};
+use std::{
+    collections::{BTreeMap, VecDeque},
+    io,
+    sync::{atomic::AtomicU32, Arc},
+    time::Duration,
+};
 use wayland_client::{
     event_enum,
     protocol::{
-        wl_compositor::WlCompositor, wl_pointer, wl_seat::WlSeat, wl_shm::WlShm,
-        wl_subcompositor::WlSubcompositor, wl_data_device_manager::WlDataDeviceManager,
-        wl_data_offer::WlDataOffer
+        wl_compositor::WlCompositor,
+        wl_data_device::WlDataDevice,
+        wl_data_device_manager::WlDataDeviceManager,
+        wl_pointer,
+        wl_seat::WlSeat,
+        wl_shm::{self, WlShm},
+        wl_subcompositor::WlSubcompositor,
     },
     Display, EventQueue, Filter, GlobalManager, Main,
 };
-use wayland_protocols::xdg_shell::client::xdg_wm_base::XdgWmBase;
+use wayland_protocols::{
+    unstable::primary_selection::v1::client::{
+        zwp_primary_selection_device_manager_v1::ZwpPrimarySelectionDeviceManagerV1,
+        zwp_primary_selection_device_v1::{self, ZwpPrimarySelectionDeviceV1},
+    },
+    xdg_shell::client::xdg_wm_base::XdgWmBase,
+};
 
 event_enum!(
     Events |
@@ -31,19 +49,41 @@ event_enum!(
 );
 
 pub struct Connection {
+    display: Display,
     event_queue: Mutex<EventQueue>,
     events_sender: flume::Sender<Event>,
     events_receiver: flume::Receiver<Event>,
+    /// Events queued by `EventLoopProxy::inject`, delivered as they are
+    injected_events: Mutex<VecDeque<Event>>,
     shm: Main<WlShm>,
+    shm_formats: Arc<RwLock<Vec<wl_shm::Format>>>,
     compositor: Main<WlCompositor>,
     subcompositor: Main<WlSubcompositor>,
     xdg_wm_base: Main<XdgWmBase>,
-    windows: RwLock<BTreeMap<WindowId, Arc<RwLock<WindowPlatformData>>>>,
+    pub(super) windows: RwLock<BTreeMap<WindowId, Arc<RwLock<WindowPlatformData>>>>,
     mouse_on_surface: Atomic<Option<(u32, f64, f64)>>,
-    data_offers: Rc<RefCell<BTreeMap<u32, DataOffer>>>
+    data_device_manager: Main<WlDataDeviceManager>,
+    data_device: Main<WlDataDevice>,
+    /// `None` when the compositor has no primary selection
+    primary_device: Option<(
+        Main<ZwpPrimarySelectionDeviceManagerV1>,
+        Main<ZwpPrimarySelectionDeviceV1>,
+    )>,
+    clipboard: Arc<SelectionState>,
+    primary: Arc<SelectionState>,
+    /// The answers to other clients that wait for them to read
+    sends: Arc<Mutex<Vec<PendingSend>>>,
+    /// The serial of the last input event, the compositor only gives the selection to the
+    /// client that the user interacts with
+    input_serial: Arc<AtomicU32>,
+    // Written by `wakeup` to interrupt a thread waiting for events
+    wakeup_fd: WakeupFd,
 }
 
 impl Connection {
+    // The state shared with the callbacks holds proxies, they are only used under the lock of
+    // the event queue or of the state itself
+    #[allow(clippy::arc_with_non_send_sync)]
     pub fn from_display(display: Display) -> Result<Self, OSError> {
         let mut event_queue = display.create_event_queue();
 
@@ -57,12 +97,20 @@ impl Connection {
         // sent us all available globals.
         event_queue
             .sync_roundtrip(&mut (), |_, _, _| unreachable!())
-            .unwrap();
+            .map_err(|e| errors::dispatch_error(&display, e))?;
 
-        let shm = globals.instantiate_exact::<WlShm>(1).unwrap();
-        let compositor = globals.instantiate_exact::<WlCompositor>(1).unwrap();
-        let subcompositor = globals.instantiate_exact::<WlSubcompositor>(1).unwrap();
-        let xdg_wm_base = globals.instantiate_exact::<XdgWmBase>(1).unwrap();
+        let shm = globals.instantiate_exact::<WlShm>(1)?;
+        let compositor = globals.instantiate_exact::<WlCompositor>(1)?;
+        let subcompositor = globals.instantiate_exact::<WlSubcompositor>(1)?;
+        let xdg_wm_base = globals.instantiate_exact::<XdgWmBase>(1)?;
+
+        let shm_formats = Arc::new(RwLock::new(Vec::new()));
+        let events_shm_formats = shm_formats.clone();
+        shm.quick_assign(move |_, event, _| {
+            if let wl_shm::Event::Format { format } = event {
+                events_shm_formats.write().push(format);
+            }
+        });
 
         xdg_wm_base.quick_assign(|xdg_wm_base, event, _| {
             use wayland_protocols::xdg_shell::client::xdg_wm_base::Event;
@@ -75,15 +123,18 @@ impl Connection {
 
         let (events_sender, events_receiver) = flume::unbounded();
         let filter_events_sender = events_sender.clone();
+        let input_serial = Arc::new(AtomicU32::new(0));
+        let filter_input_serial = input_serial.clone();
         // initialize a seat to retrieve pointer & keyboard events
         let common_filter = Filter::new(move |event, _, _| match event {
             Events::Pointer { event, .. } => match event {
                 wl_pointer::Event::Enter {
+                    serial,
                     surface,
                     surface_x,
                     surface_y,
-                    ..
                 } => {
+                    filter_input_serial.store(serial, atomic::Ordering::SeqCst);
                     filter_events_sender
                         .send(Event::WindowEvent {
                             window: WindowId::from_wayland(surface.as_ref().id()),
@@ -117,7 +168,13 @@ impl Connection {
                         })
                         .unwrap();
                 }
-                wl_pointer::Event::Button { button, state, .. } => {
+                wl_pointer::Event::Button {
+                    serial,
+                    button,
+                    state,
+                    ..
+                } => {
+                    filter_input_serial.store(serial, atomic::Ordering::SeqCst);
                     if button & 0x110 != 0x110 {
                         return;
                     }
@@ -155,8 +212,8 @@ impl Connection {
         });
 
         let mut pointer_created = false;
-        let seat = globals.instantiate_exact::<WlSeat>(1).unwrap();
-        
+        let seat = globals.instantiate_exact::<WlSeat>(1)?;
+
         seat.quick_assign(move |seat, event, _| {
             use wayland_client::protocol::wl_seat::{Capability, Event as SeatEvent};
 
@@ -169,45 +226,224 @@ impl Connection {
             }
         });
 
-        let data_dev_mngr = globals.instantiate_exact::<WlDataDeviceManager>(1).unwrap();
-        let data_dev = data_dev_mngr.get_data_device(&seat);
-        let data_offers = Rc::new(RefCell::new(BTreeMap::new()));
-        let dev_data_offers = data_offers.clone();
-        data_dev.quick_assign(move |_, event, _| {
+        let data_device_manager = globals.instantiate_exact::<WlDataDeviceManager>(1)?;
+        let data_device = data_device_manager.get_data_device(&seat);
+        // The offers introduced until the compositor tells what they are for
+        let mut data_offers = BTreeMap::new();
+        let sends = Arc::new(Mutex::new(Vec::new()));
+        let clipboard = Arc::new(SelectionState::new(
+            Selection::Clipboard,
+            events_sender.clone(),
+            sends.clone(),
+        ));
+        let device_clipboard = clipboard.clone();
+        data_device.quick_assign(move |_, event, _| {
             use wayland_client::protocol::wl_data_device::Event;
             match event {
                 Event::DataOffer { id: data_offer } => {
                     let id = data_offer.as_ref().id();
-                    dev_data_offers.borrow_mut().insert(id, DataOffer::from_wl(data_offer));
-                },
-                Event::Selection { id: Some(data_offer) } => {
-                    // This kind of data offer don't interest us for now
-                    dev_data_offers.borrow_mut().remove(&data_offer.as_ref().id());
-                },
+                    data_offers.insert(id, DataOffer::from_wl(data_offer));
+                }
+                Event::Selection { id: data_offer } => {
+                    device_clipboard.set_offer(
+                        data_offer
+                            .and_then(|data_offer| data_offers.remove(&data_offer.as_ref().id())),
+                    );
+                }
+                Event::Enter {
+                    id: Some(data_offer),
+                    ..
+                } => {
+                    // Drag and drop is not supported, the offer is declined
+                    data_offers.remove(&data_offer.as_ref().id());
+                }
                 _ => {}
             }
         });
 
+        let primary = Arc::new(SelectionState::new(
+            Selection::Primary,
+            events_sender.clone(),
+            sends.clone(),
+        ));
+        let primary_device = globals
+            .instantiate_exact::<ZwpPrimarySelectionDeviceManagerV1>(1)
+            .ok()
+            .map(|manager| {
+                let device = manager.get_device(&seat);
+                let mut primary_offers = BTreeMap::new();
+                let device_primary = primary.clone();
+                device.quick_assign(move |_, event, _| {
+                    use zwp_primary_selection_device_v1::Event;
+                    match event {
+                        Event::DataOffer { offer } => {
+                            let id = offer.as_ref().id();
+                            primary_offers.insert(id, DataOffer::from_primary(offer));
+                        }
+                        Event::Selection { id: offer } => {
+                            device_primary.set_offer(
+                                offer.and_then(|offer| primary_offers.remove(&offer.as_ref().id())),
+                            );
+                        }
+                        _ => {}
+                    }
+                });
+                (manager, device)
+            });
+
+        // Receive the formats announced by `wl_shm`
+        event_queue
+            .sync_roundtrip(&mut (), |_, _, _| { /* we ignore unfiltered messages */ })
+            .map_err(|e| errors::dispatch_error(&display, e))?;
+
+        let wakeup_fd = WakeupFd::new()?;
+
         Ok(Self {
+            display,
             event_queue: Mutex::new(event_queue),
             events_sender,
             events_receiver,
+            injected_events: Mutex::new(VecDeque::new()),
             shm,
+            shm_formats,
             compositor,
             subcompositor,
             xdg_wm_base,
             windows: RwLock::new(BTreeMap::new()),
             mouse_on_surface: Atomic::new(None),
-            data_offers
+            data_device_manager,
+            data_device,
+            primary_device,
+            clipboard,
+            primary,
+            sends,
+            input_serial,
+            wakeup_fd,
         })
     }
 
+    /// Returns the `wl_shm` format equivalent to `format` if the compositor supports it
+    fn shm_format(&self, format: surface::Format) -> Result<wl_shm::Format, OSError> {
+        let shm_format = match format {
+            surface::Format::Argb8888 => wl_shm::Format::Argb8888,
+            surface::Format::Xrgb8888 => wl_shm::Format::Xrgb8888,
+            surface::Format::Rgb565 => wl_shm::Format::Rgb565,
+            surface::Format::Abgr8888 => wl_shm::Format::Abgr8888,
+            surface::Format::Argb2101010 => wl_shm::Format::Argb2101010,
+        };
+        if self.shm_formats.read().contains(&shm_format) {
+            Ok(shm_format)
+        } else {
+            Err(OSError::UnsupportedFormat(format))
+        }
+    }
+
+    /// Queues an event as if it was received from the compositor
+    ///
+    /// It skips the pointer tracking of `poll_event`, which would otherwise move it to the
+    /// surface under the real pointer.
+    pub fn inject_event(&self, event: Event) -> Result<(), OSError> {
+        self.injected_events.lock().push_back(event);
+        Ok(())
+    }
+
+    /// Makes the wakeup file descriptor readable so a thread waiting on it wakes up
+    pub fn wakeup(&self) -> Result<(), OSError> {
+        self.wakeup_fd.wake()
+    }
+
+    /// Blocks until the compositor sends something, one of `sources` is ready, `timeout`
+    /// expires or `wakeup` is called
+    pub fn wait(
+        &self,
+        timeout: Option<Duration>,
+        sources: &mut [libc::pollfd],
+    ) -> Result<(), OSError> {
+        if !self.events_receiver.is_empty() || !self.injected_events.lock().is_empty() {
+            return Ok(());
+        }
+        self.read_events(timeout, sources)
+    }
+
+    #[cfg(feature = "async")]
+    pub fn pollfds(&self) -> Result<Vec<libc::pollfd>, OSError> {
+        self.flush()?;
+        let mut fds = vec![
+            libc::pollfd {
+                fd: self.display.get_connection_fd(),
+                events: libc::POLLIN,
+                revents: 0,
+            },
+            self.wakeup_fd.pollfd(),
+        ];
+        fds.extend(self.sends_pollfds());
+        Ok(fds)
+    }
+
+    fn flush(&self) -> Result<(), OSError> {
+        match self.display.flush() {
+            // The rest of the requests is sent on the next flush
+            Err(e) if e.kind() != io::ErrorKind::WouldBlock => {
+                Err(errors::dispatch_error(&self.display, e))
+            }
+            _ => Ok(()),
+        }
+    }
+
+    /// Reads and dispatches the events of the compositor, waiting up to `timeout` for them
+    fn read_events(
+        &self,
+        timeout: Option<Duration>,
+        sources: &mut [libc::pollfd],
+    ) -> Result<(), OSError> {
+        let mut event_queue = self.event_queue.lock();
+        event_queue
+            .dispatch_pending(&mut (), |_, _, _| { /* we ignore unfiltered messages */ })
+            .map_err(|e| errors::dispatch_error(&self.display, e))?;
+        self.flush()?;
+        // Events were dispatched to the queue before by another read
+        let timeout = if self.events_receiver.is_empty() {
+            timeout
+        } else {
+            Some(Duration::from_secs(0))
+        };
+        if let Some(guard) = event_queue.prepare_read() {
+            let mut fds = vec![
+                libc::pollfd {
+                    fd: self.display.get_connection_fd(),
+                    events: libc::POLLIN,
+                    revents: 0,
+                },
+                self.wakeup_fd.pollfd(),
+            ];
+            fds.extend_from_slice(sources);
+            fds.extend(self.sends_pollfds());
+            poll_fds(&mut fds, timeout)?;
+            sources.copy_from_slice(&fds[2..2 + sources.len()]);
+            if fds[0].revents != 0 {
+                if let Err(e) = guard.read_events() {
+                    if e.kind() != io::ErrorKind::WouldBlock {
+                        return Err(errors::dispatch_error(&self.display, e));
+                    }
+                }
+            } else {
+                guard.cancel();
+            }
+        }
+        event_queue
+            .dispatch_pending(&mut (), |_, _, _| { /* we ignore unfiltered messages */ })
+            .map_err(|e| errors::dispatch_error(&self.display, e))?;
+        // Includes the sends started by the events just dispatched
+        self.continue_sends();
+        Ok(())
+    }
+
     pub fn poll_event(&self) -> Result<Option<Event>, OSError> {
-        println!("Poll event");
-        self.event_queue
-            .lock()
-            .sync_roundtrip(&mut (), |_, _, _| { /* we ignore unfiltered messages */ })
-            .unwrap();
+        self.wakeup_fd.clear();
+        if let Some(event) = self.injected_events.lock().pop_front() {
+            return Ok(Some(event));
+        }
+        self.read_events(Some(Duration::from_secs(0)), &mut [])?;
         if let Ok(mut event) = self.events_receiver.try_recv() {
             match event {
                 Event::WindowEvent {
@@ -302,8 +538,7 @@ impl Connection {
                 }
                 ev @ _ => Ok(Some(ev)),
             }
-        }
-        else {
+        } else {
             Ok(None)
         }
     }
@@ -1,20 +1,32 @@
 use super::super::{Connection, WindowId, WindowPlatformData};
-use super::Window;
+use super::{ShmBuffer, Window};
 use crate::{
     dpi::LogicalSize,
     error::OSError,
+    rect,
     surface::{self, Surface},
     window as mwin,
 };
 use atomic::Atomic;
 use parking_lot::RwLock;
-use std::sync::{atomic::AtomicPtr, Arc};
-use wayland_client::{protocol::wl_surface::WlSurface, Main};
-use wayland_protocols::xdg_shell::client::xdg_toplevel::XdgToplevel;
+use std::sync::Arc;
+use wayland_client::{
+    protocol::{wl_subsurface::WlSubsurface, wl_surface::WlSurface},
+    Main,
+};
+use wayland_protocols::xdg_shell::client::{xdg_surface::XdgSurface, xdg_toplevel::XdgToplevel};
+
+const TITLE_PADDING: i32 = 8;
+const TITLE_COLOR: u32 = 0xff303030;
 
 #[derive(Debug)]
 pub struct Frame {
     pub wl_surface: Main<WlSurface>,
+    pub buffer: ShmBuffer,
+    /// Places the surface of the window under the header bar
+    pub subsurface: Main<WlSubsurface>,
+    /// Keeps the shared memory mapped while the compositor may read it
+    #[allow(dead_code)]
     pub surface: Surface,
     pub frame_width: i32,
     pub header_bar_height: i32,
@@ -24,25 +36,26 @@ impl Connection {
     pub fn build_framed_window(
         &self,
         builder: mwin::WindowBuilder,
+        xdg_surface: Main<XdgSurface>,
         xdg_toplevel: Main<XdgToplevel>,
         frame_wl_surface: Main<WlSurface>,
+        connection: Arc<crate::platform::Connection>,
     ) -> Result<mwin::Window, OSError> {
         let header_bar_height = 20;
 
         let frame_width = builder.width as i32;
         let frame_height = header_bar_height + builder.height as i32;
-        let (frame_buffer_ptr, frame_buffer_len) =
-            self.setup_surface(&frame_wl_surface, frame_width, frame_height);
-
-        let frame_buffer_ptr = AtomicPtr::new(frame_buffer_ptr);
-        let frame_shared_data = surface::SharedData {
-            buffer_len: frame_buffer_len,
-            width: frame_width as u32,
-            height: frame_height as u32,
-        };
+        let (frame_buffer, frame_shm_buffer) = self.setup_surface(
+            &frame_wl_surface,
+            frame_width,
+            frame_height,
+            surface::Format::Argb8888,
+        )?;
         let frame_surface = Surface::new(
             surface::Format::Argb8888,
-            Arc::new((frame_buffer_ptr, Atomic::new(frame_shared_data))),
+            frame_buffer,
+            frame_width as u32,
+            frame_height as u32,
         );
 
         let buffer_surface = self.compositor.create_surface();
@@ -51,42 +64,56 @@ impl Connection {
             .subcompositor
             .get_subsurface(&buffer_surface, &frame_wl_surface);
         buffer_subsurface.set_position(0, header_bar_height);
-        let (frame_buffer_ptr, frame_buffer_len) =
-            self.setup_surface(&buffer_surface, builder.width as i32, builder.height as i32);
+        let (frame_buffer, shm_buffer) = self.setup_surface(
+            &buffer_surface,
+            builder.width as i32,
+            builder.height as i32,
+            builder.surface_format,
+        )?;
 
-        for y in 0..header_bar_height as u32 {
-            for x in 0..frame_width as u32 {
-                frame_surface.put_u32_pixel(x, y, 0xffdadada);
-            }
+        let mut frame_guard = frame_surface.lock();
+        for row in frame_guard.rows_mut()?.take(header_bar_height as usize) {
+            row.iter_mut().for_each(|p| *p = 0xffdadada);
+        }
+        if let Some(ref font) = builder.title_font {
+            let title_y = (header_bar_height - font.line_height() as i32) / 2;
+            font.draw_clipped(
+                &mut frame_guard,
+                &builder.title,
+                TITLE_PADDING,
+                title_y,
+                TITLE_COLOR,
+                rect(0.0, 0.0, frame_width as f64, header_bar_height as f64),
+            )?;
         }
+        drop(frame_guard);
 
         let frame = Frame {
             wl_surface: frame_wl_surface,
+            buffer: frame_shm_buffer,
+            subsurface: buffer_subsurface,
             surface: frame_surface,
             frame_width,
             header_bar_height,
         };
 
-        let frame_buffer_ptr = AtomicPtr::new(frame_buffer_ptr);
-        let shared_data = surface::SharedData {
-            buffer_len: frame_buffer_len,
-            width: builder.width as u32,
-            height: builder.height as u32,
-        };
-
         let surface = Surface::new(
-            surface::Format::Argb8888,
-            Arc::new((frame_buffer_ptr, Atomic::new(shared_data))),
+            builder.surface_format,
+            frame_buffer,
+            builder.width as u32,
+            builder.height as u32,
         );
 
-        let window = Arc::new(RwLock::new(WindowPlatformData::Wayland(Window {
+        let window = Arc::new(RwLock::new(WindowPlatformData::Wayland(Box::new(Window {
             xdg_toplevel,
+            xdg_surface,
             surface: surface.clone(),
             wl_surface: buffer_surface,
+            buffer: shm_buffer,
             buf_x: builder.width as i32,
             buf_y: builder.height as i32,
             frame: Some(frame),
-        })));
+        }))));
         self.windows
             .write()
             .insert(WindowId::from_wayland(buffer_surface_id), window.clone());
@@ -99,6 +126,7 @@ impl Connection {
             })),
             dpi: Arc::new(Atomic::new(1.0)),
             platform_data: window,
+            connection,
         })
     }
 }
@@ -6,39 +6,56 @@ use crate::{
     dpi::LogicalSize,
     error::OSError,
     event::*,
-    platform::{WindowId, WindowPlatformData},
-    surface::{self, Surface},
+    platform::{munmap_buffer, WindowId, WindowPlatformData},
+    surface::{self, FrameBuffer, Surface},
     window as mwin,
 };
 use atomic::Atomic;
-use libc::{mmap, munmap, MAP_FAILED, MAP_SHARED, PROT_READ, PROT_WRITE};
+use libc::{mmap, MAP_FAILED, MAP_SHARED, PROT_READ, PROT_WRITE};
 use parking_lot::RwLock;
-use std::{
-    os::unix::io::AsRawFd,
-    ptr::null_mut,
-    sync::{
-        atomic::{AtomicPtr, Ordering},
-        Arc,
-    },
-};
+use std::{io, os::unix::io::AsRawFd, ptr::null_mut, sync::Arc};
 use wayland_client::{
-    protocol::{wl_shm::Format, wl_surface::WlSurface},
+    protocol::{wl_buffer::WlBuffer, wl_shm_pool::WlShmPool, wl_surface::WlSurface},
     Main,
 };
-use wayland_protocols::xdg_shell::client::xdg_toplevel::XdgToplevel;
+use wayland_protocols::xdg_shell::client::{xdg_surface::XdgSurface, xdg_toplevel::XdgToplevel};
+
+/// The buffer attached to a surface and the pool of shared memory it's taken from
+#[derive(Debug)]
+pub struct ShmBuffer {
+    pool: Main<WlShmPool>,
+    buffer: Main<WlBuffer>,
+}
+
+impl ShmBuffer {
+    fn destroy(&self) {
+        self.buffer.destroy();
+        self.pool.destroy();
+    }
+}
 
 #[derive(Debug)]
 pub struct Window {
     xdg_toplevel: Main<XdgToplevel>,
+    xdg_surface: Main<XdgSurface>,
     buf_x: i32,
     buf_y: i32,
+    /// Keeps the shared memory mapped while the compositor may read it
+    #[allow(dead_code)]
     surface: Surface,
     wl_surface: Main<WlSurface>,
+    buffer: ShmBuffer,
     frame: Option<Frame>,
 }
 
 impl Connection {
-    pub fn create_window(&self, builder: mwin::WindowBuilder) -> Result<mwin::Window, OSError> {
+    pub fn create_window(
+        &self,
+        builder: mwin::WindowBuilder,
+        connection: Arc<crate::platform::Connection>,
+    ) -> Result<mwin::Window, OSError> {
+        self.shm_format(builder.surface_format)?;
+
         let wl_surface = self.compositor.create_surface();
         let surface_id = wl_surface.as_ref().id();
         let xdg_surface = self.xdg_wm_base.get_xdg_surface(&wl_surface);
@@ -77,34 +94,38 @@ impl Connection {
         wl_surface.commit();
 
         if builder.decorations {
-            return self.build_framed_window(builder, xdg_toplevel, wl_surface);
+            return self.build_framed_window(
+                builder,
+                xdg_surface,
+                xdg_toplevel,
+                wl_surface,
+                connection,
+            );
         }
 
         let buf_x: i32 = builder.width as i32;
         let buf_y: i32 = builder.height as i32;
 
-        let (frame_buffer_ptr, frame_buffer_len) = self.setup_surface(&wl_surface, buf_x, buf_y);
-
-        let frame_buffer_ptr = AtomicPtr::new(frame_buffer_ptr);
-        let shared_data = surface::SharedData {
-            buffer_len: frame_buffer_len,
-            width: buf_x as u32,
-            height: buf_y as u32,
-        };
+        let (frame_buffer, buffer) =
+            self.setup_surface(&wl_surface, buf_x, buf_y, builder.surface_format)?;
 
         let surface = Surface::new(
-            surface::Format::Argb8888,
-            Arc::new((frame_buffer_ptr, Atomic::new(shared_data))),
+            builder.surface_format,
+            frame_buffer,
+            buf_x as u32,
+            buf_y as u32,
         );
 
-        let window = Arc::new(RwLock::new(WindowPlatformData::Wayland(Window {
+        let window = Arc::new(RwLock::new(WindowPlatformData::Wayland(Box::new(Window {
             xdg_toplevel,
+            xdg_surface,
             surface: surface.clone(),
             wl_surface,
+            buffer,
             buf_x,
             buf_y,
             frame: None,
-        })));
+        }))));
         self.windows
             .write()
             .insert(WindowId::from_wayland(surface_id), window.clone());
@@ -117,10 +138,11 @@ impl Connection {
             })),
             dpi: Arc::new(Atomic::new(1.0)),
             platform_data: window,
+            connection,
         })
     }
 
-    pub fn redraw_window(&self, window: &Window) {
+    pub fn redraw_window(&self, window: &Window) -> Result<(), OSError> {
         window.wl_surface.damage(0, 0, window.buf_x, window.buf_y);
         window.wl_surface.commit();
         if let Some(ref frame) = window.frame {
@@ -129,30 +151,23 @@ impl Connection {
                 .damage(0, 0, frame.frame_width, frame.header_bar_height);
             frame.wl_surface.commit();
         }
+        Ok(())
     }
 
+    /// Destroys the roles before the surfaces that have them, the memory of the buffers stays
+    /// mapped in this process until the `Surface` of the window is dropped
     pub fn destroy_window(&self, window: &mut Window) -> Result<(), OSError> {
         window.xdg_toplevel.destroy();
-        unsafe {
-            munmap(
-                window.surface.shared().0.load(Ordering::SeqCst) as *mut _,
-                window.surface.shared().1.load(Ordering::SeqCst).buffer_len,
-            );
+        window.xdg_surface.destroy();
+        if let Some(ref frame) = window.frame {
+            frame.subsurface.destroy();
+            frame.buffer.destroy();
         }
+        window.buffer.destroy();
         window.wl_surface.destroy();
         if let Some(ref frame) = window.frame {
-            unsafe {
-                munmap(
-                    frame.surface.shared().0.load(Ordering::SeqCst) as *mut _,
-                    frame.surface.shared().1.load(Ordering::SeqCst).buffer_len,
-                );
-            }
             frame.wl_surface.destroy();
         }
-        window.xdg_toplevel.destroy();
-        self.windows
-            .write()
-            .remove(&WindowId::from_wayland(window.wl_surface.as_ref().id()));
         Ok(())
     }
 
@@ -161,27 +176,30 @@ impl Connection {
         buffer_surface: &Main<WlSurface>,
         buf_width: i32,
         buf_height: i32,
-    ) -> (*mut u8, usize) {
-        let buf_len = (buf_width * buf_height) * 4;
-        let tmp = tempfile::tempfile().expect("Unable to create a tempfile.");
-        tmp.set_len(buf_len as u64).unwrap();
+        format: surface::Format,
+    ) -> Result<(FrameBuffer, ShmBuffer), OSError> {
+        let shm_format = self.shm_format(format)?;
+        let stride = buf_width * format.bytes_per_pixel() as i32;
+        let buf_len = stride * buf_height;
+        let tmp = tempfile::tempfile()?;
+        tmp.set_len(buf_len as u64)?;
 
         let pool = self.shm.create_pool(
             tmp.as_raw_fd(), // RawFd to the tempfile serving as shared memory
-            buf_len,         // size in bytes of the shared memory (4 bytes per pixel)
+            buf_len,         // size in bytes of the shared memory
         );
         let buffer = pool.create_buffer(
-            0,                      // Start of the buffer in the pool
-            buf_width,              // width of the buffer in pixels
-            buf_height,             // height of the buffer in pixels
-            (buf_width * 4) as i32, // number of bytes between the beginning of two consecutive lines
-            Format::Argb8888,       // chosen encoding for the data
+            0,          // Start of the buffer in the pool
+            buf_width,  // width of the buffer in pixels
+            buf_height, // height of the buffer in pixels
+            stride,     // number of bytes between the beginning of two consecutive lines
+            shm_format, // chosen encoding for the data
         );
 
         self.event_queue
             .lock()
             .sync_roundtrip(&mut (), |_, _, _| { /* we ignore unfiltered messages */ })
-            .unwrap();
+            .map_err(|e| super::errors::dispatch_error(&self.display, e))?;
 
         buffer_surface.attach(Some(&buffer), 0, 0);
         buffer_surface.commit();
@@ -189,7 +207,7 @@ impl Connection {
         self.event_queue
             .lock()
             .sync_roundtrip(&mut (), |_, _, _| { /* we ignore unfiltered messages */ })
-            .unwrap();
+            .map_err(|e| super::errors::dispatch_error(&self.display, e))?;
 
         let buf_len = buf_len as usize;
 
@@ -203,8 +221,12 @@ impl Connection {
                 0,
             )
         };
-        assert_ne!(in_memory_addr, MAP_FAILED);
+        if in_memory_addr == MAP_FAILED {
+            return Err(io::Error::last_os_error().into());
+        }
 
-        (in_memory_addr as *mut _, buf_len)
+        let frame_buffer =
+            unsafe { FrameBuffer::from_raw(in_memory_addr as *mut _, buf_len, munmap_buffer) };
+        Ok((frame_buffer, ShmBuffer { pool, buffer }))
     }
 }
@@ -0,0 +1,82 @@
+use super::Connection;
+use crate::{error::OSError, event::MouseButton, platform::WindowId};
+use x11rb::{
+    connection::{Connection as XConnection, RequestConnection},
+    protocol::{
+        xproto::{self, ConnectionExt},
+        xtest::{self, ConnectionExt as XTestConnectionExt},
+    },
+    CURRENT_TIME, NONE,
+};
+
+impl Connection {
+    /// Moves the pointer to (x, y), relative to `window` or to the root window
+    pub fn fake_pointer_motion(
+        &self,
+        window: Option<WindowId>,
+        x: i16,
+        y: i16,
+    ) -> Result<(), OSError> {
+        let root = self.root();
+        let (x, y) = match window {
+            Some(window) => {
+                let reply = self
+                    .conn
+                    .translate_coordinates(window.to_x11(), root, x, y)?
+                    .reply()?;
+                (reply.dst_x, reply.dst_y)
+            }
+            None => (x, y),
+        };
+        self.fake_input(xproto::MOTION_NOTIFY_EVENT, 0, x, y)
+    }
+
+    pub fn fake_button(&self, button: MouseButton, pressed: bool) -> Result<(), OSError> {
+        let detail = match button {
+            MouseButton::Left => 1,
+            MouseButton::Middle => 2,
+            MouseButton::Right => 3,
+            MouseButton::Side => 8,
+            MouseButton::Extra => 9,
+        };
+        let type_ = if pressed {
+            xproto::BUTTON_PRESS_EVENT
+        } else {
+            xproto::BUTTON_RELEASE_EVENT
+        };
+        self.fake_input(type_, detail, 0, 0)
+    }
+
+    pub fn fake_key(&self, keycode: u8, pressed: bool) -> Result<(), OSError> {
+        let type_ = if pressed {
+            xproto::KEY_PRESS_EVENT
+        } else {
+            xproto::KEY_RELEASE_EVENT
+        };
+        self.fake_input(type_, keycode, 0, 0)
+    }
+
+    /// Waits until the server has processed the input, so the events it caused are queued
+    pub fn sync(&self) -> Result<(), OSError> {
+        self.conn.get_input_focus()?.reply()?;
+        Ok(())
+    }
+
+    fn fake_input(&self, type_: u8, detail: u8, x: i16, y: i16) -> Result<(), OSError> {
+        if self
+            .conn
+            .extension_information(xtest::X11_EXTENSION_NAME)?
+            .is_none()
+        {
+            return Err(OSError::UnsupportedExtension(xtest::X11_EXTENSION_NAME));
+        }
+        self.conn
+            .xtest_fake_input(type_, detail, CURRENT_TIME, self.root(), x, y, NONE as u8)?;
+        self.conn.flush()?;
+        Ok(())
+    }
+
+    fn root(&self) -> xproto::Window {
+        self.conn.setup().roots[self.screen_num].root
+    }
+}
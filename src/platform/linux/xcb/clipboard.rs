@@ -1,85 +1,275 @@
 use super::Connection;
-use crate::error::OSError;
+use crate::{
+    clipboard::{self, ClipboardProvider, Content, Selection},
+    error::OSError,
+    platform::{self, poll_fds},
+};
 use mime::Mime;
 use std::{
+    collections::BTreeMap,
     convert::TryInto,
     mem,
-    sync::atomic::Ordering,
-    thread,
+    os::unix::io::AsRawFd,
+    sync::{atomic::Ordering, Arc},
+    task::Poll,
     time::{Duration, Instant},
 };
 use x11rb::{
-    connection::Connection as XConnection,
+    connection::{Connection as XConnection, RequestConnection},
     protocol::xproto::{
-        Atom, ConnectionExt, EventMask, PropMode, SelectionNotifyEvent, SelectionRequestEvent,
-        Window, SELECTION_NOTIFY_EVENT,
+        Atom, AtomEnum, ChangeWindowAttributesAux, ConnectionExt, EventMask, GetPropertyReply,
+        PropMode, SelectionNotifyEvent, SelectionRequestEvent, Window, SELECTION_NOTIFY_EVENT,
     },
     CURRENT_TIME, NONE,
 };
 
+/// Size of a `ChangeProperty` request without its data
+const CHANGE_PROPERTY_HEADER: usize = 24;
+
+/// How long the owner of a selection may stay silent before a load gives up
+const TRANSFER_TIMEOUT: Duration = Duration::from_millis(100);
+
+/// How long a requestor may leave a chunk unread before its transfer is dropped
+const INCR_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// Data sent to a requestor in chunks because it does not fit in a request, the requestor asks
+/// for the next chunk by deleting the property
+#[derive(Debug)]
+pub struct IncrTransfer {
+    target: Atom,
+    data: Vec<u8>,
+    sent: usize,
+    deadline: Instant,
+}
+
+impl IncrTransfer {
+    /// Returns the next chunk of at most `chunk_size` bytes, it's empty once all the data was
+    /// sent
+    fn next_chunk(&mut self, chunk_size: usize) -> &[u8] {
+        let start = self.sent;
+        self.sent = (start + chunk_size).min(self.data.len());
+        &self.data[start..self.sent]
+    }
+}
+
+/// A conversion of a selection by its owner, the data arrives through the
+/// `CLIPBOARD_RECEIVER` property of the hidden window
+#[derive(Debug)]
+pub struct ClipboardLoad {
+    selection: Atom,
+    /// The targets left to try, the first one is being converted
+    targets: Vec<Atom>,
+    /// The data received until now once the owner sends it in chunks
+    incr: Option<Vec<u8>>,
+    /// The owner is given up on if it stays silent until then
+    deadline: Instant,
+}
+
+/// What a load did with the events of a round
+enum LoadStep {
+    Waiting,
+    /// Data came, more may have been read with the replies
+    Received,
+    /// The current target gave nothing, the next one is tried
+    Failed,
+}
+
 impl Connection {
-    pub fn load_from_clipboard(&self, media_type: Mime) -> Result<Option<Vec<u8>>, OSError> {
-        let selection_owner = self
-            .conn
-            .get_selection_owner(self.atoms.CLIPBOARD)?
-            .reply()?
-            .owner;
+    fn selection_atom(&self, selection: Selection) -> Atom {
+        match selection {
+            Selection::Clipboard => self.atoms.CLIPBOARD,
+            Selection::Primary => AtomEnum::PRIMARY.into(),
+        }
+    }
+
+    /// Returns `None` for the selections this connection does not handle
+    pub fn selection_from_atom(&self, atom: Atom) -> Option<Selection> {
+        [Selection::Clipboard, Selection::Primary]
+            .iter()
+            .copied()
+            .find(|selection| self.selection_atom(*selection) == atom)
+    }
+
+    /// Drops the content of a selection taken by another client
+    pub fn lose_selection(&self, atom: Atom) -> Option<Selection> {
+        let selection = self.selection_from_atom(atom)?;
+        self.clipboard_data.lock().remove(&selection)?;
+        Some(selection)
+    }
+
+    pub fn start_clipboard_load(
+        &self,
+        selection: Selection,
+        media_type: Mime,
+    ) -> Result<platform::ClipboardLoad, OSError> {
+        let selection = self.selection_atom(selection);
+        let selection_owner = self.conn.get_selection_owner(selection)?.reply()?.owner;
         if selection_owner == NONE {
-            return Ok(None);
+            return Ok(platform::ClipboardLoad::Ready(None));
         }
         let conv_target = self
             .conn
             .intern_atom(false, media_type.essence_str().as_bytes())?
             .reply()?
             .atom;
-        if let Some(result) = self.load_from_clipboard_inner(conv_target)? {
-            return Ok(Some(result));
+        let mut targets = vec![conv_target];
+        // The targets of the ICCCM with a known encoding, `TEXT` is in the one of the owner
+        if clipboard::is_utf8_text(&media_type) {
+            targets.push(self.atoms.UTF8_STRING);
+        } else if clipboard::is_latin1_text(&media_type) {
+            targets.push(self.atoms.STRING);
         }
-        if media_type.type_() == mime::TEXT && media_type.subtype() == mime::PLAIN {
-            let mut utf8 = true;
-            if let Some(n) = media_type.get_param("charset") {
-                if n.as_str() != "utf-8" {
-                    utf8 = false;
-                }
-            }
-            if utf8 {
-                for atom in &[self.atoms.UTF8_STRING, self.atoms.STRING, self.atoms.TEXT] {
-                    if let Some(result) = self.load_from_clipboard_inner(*atom)? {
-                        return Ok(Some(result));
-                    }
-                }
+        let load = self.convert_selection(selection, targets)?;
+        Ok(platform::ClipboardLoad::Xcb(load))
+    }
+
+    pub fn available_clipboard_types(&self, selection: Selection) -> Result<Vec<Mime>, OSError> {
+        let selection = self.selection_atom(selection);
+        if self.conn.get_selection_owner(selection)?.reply()?.owner == NONE {
+            return Ok(Vec::new());
+        }
+        let mut load = self.convert_selection(selection, vec![self.atoms.TARGETS])?;
+        let targets = loop {
+            if let Poll::Ready(targets) = self.poll_clipboard_load(&mut load)? {
+                break targets;
             }
+            let (mut fds, timeout) = self.clipboard_load_pollfds(&load)?;
+            poll_fds(&mut fds, timeout)?;
+        };
+        let targets = match targets {
+            Some(targets) => targets,
+            None => return Ok(Vec::new()),
+        };
+        let cookies = targets
+            .chunks_exact(mem::size_of::<Atom>())
+            .map(|atom| Atom::from_ne_bytes(atom.try_into().unwrap()))
+            .filter(|atom| *atom != NONE)
+            .map(|atom| self.conn.get_atom_name(atom))
+            .collect::<Result<Vec<_>, _>>()?;
+        let mut names = Vec::with_capacity(cookies.len());
+        for cookie in cookies {
+            names.push(String::from_utf8_lossy(&cookie.reply()?.name).into_owned());
         }
-        Ok(None)
+        Ok(clipboard::media_types(names))
+    }
+
+    /// Asks the owner of `selection` to convert it to the first of `targets`, the others are
+    /// tried in order when it can't
+    fn convert_selection(
+        &self,
+        selection: Atom,
+        targets: Vec<Atom>,
+    ) -> Result<ClipboardLoad, OSError> {
+        let mut load = ClipboardLoad {
+            selection,
+            targets,
+            incr: None,
+            deadline: Instant::now(),
+        };
+        self.request_conversion(&mut load)?;
+        Ok(load)
     }
 
-    fn load_from_clipboard_inner(&self, conv_target: Atom) -> Result<Option<Vec<u8>>, OSError> {
+    fn request_conversion(&self, load: &mut ClipboardLoad) -> Result<(), OSError> {
         *self.clipboard_receiver_semaphore.lock() = None;
         self.conn.convert_selection(
             self.hidden_window,
-            self.atoms.CLIPBOARD,
-            conv_target,
+            load.selection,
+            load.targets[0],
             self.atoms.CLIPBOARD_RECEIVER,
             CURRENT_TIME,
         )?;
-        self.conn.flush()?;
-        let start = Instant::now();
-        while start.elapsed() < Duration::from_millis(100) {
-            self.run_event_for_queue()?;
-            if self.clipboard_receiver_semaphore.lock().is_some() {
-                break;
+        load.incr = None;
+        load.deadline = Instant::now() + TRANSFER_TIMEOUT;
+        Ok(())
+    }
+
+    /// Goes on with what the owner of the selection sent, the events read meanwhile are queued
+    /// for the event loop
+    pub fn poll_clipboard_load(
+        &self,
+        load: &mut ClipboardLoad,
+    ) -> Result<Poll<Option<Vec<u8>>>, OSError> {
+        loop {
+            // The replies of the previous round may have brought the awaited events
+            self.queue_read_events()?;
+
+            let now = Instant::now();
+            let step = match &mut load.incr {
+                None => {
+                    let converted = self.clipboard_receiver_semaphore.lock().take();
+                    match converted {
+                        Some(true) => {
+                            let prop = self.take_receiver_property()?;
+                            if prop.type_ != self.atoms.INCR {
+                                return Ok(Poll::Ready(Some(prop.value)));
+                            }
+                            // The data is received incrementally, deleting the property
+                            // started the transfer
+                            self.clipboard_data_chunk_received
+                                .store(false, Ordering::SeqCst);
+                            load.incr = Some(Vec::new());
+                            load.deadline = now + TRANSFER_TIMEOUT;
+                            LoadStep::Received
+                        }
+                        // Conversion could not be performed
+                        Some(false) => LoadStep::Failed,
+                        // The selection owner does not give us its data
+                        None if now >= load.deadline => LoadStep::Failed,
+                        None => LoadStep::Waiting,
+                    }
+                }
+                Some(data) => {
+                    let chunk_received = self
+                        .clipboard_data_chunk_received
+                        .swap(false, Ordering::SeqCst);
+                    if chunk_received {
+                        let prop = self.take_receiver_property()?;
+                        if prop.value.is_empty() {
+                            return Ok(Poll::Ready(Some(mem::take(data))));
+                        }
+                        data.extend_from_slice(&prop.value);
+                        load.deadline = now + TRANSFER_TIMEOUT;
+                        LoadStep::Received
+                    } else if now >= load.deadline {
+                        LoadStep::Failed
+                    } else {
+                        LoadStep::Waiting
+                    }
+                }
+            };
+            match step {
+                LoadStep::Waiting => return Ok(Poll::Pending),
+                LoadStep::Received => {}
+                LoadStep::Failed => {
+                    load.targets.remove(0);
+                    if load.targets.is_empty() {
+                        return Ok(Poll::Ready(None));
+                    }
+                    self.request_conversion(load)?;
+                }
             }
-            thread::yield_now();
         }
+    }
+
+    pub fn clipboard_load_pollfds(
+        &self,
+        load: &ClipboardLoad,
+    ) -> Result<(Vec<libc::pollfd>, Option<Duration>), OSError> {
+        // The owner of the selection answers the requests sent until now
         self.conn.flush()?;
-        if let Some(conversion_performed) = self.clipboard_receiver_semaphore.lock().take() {
-            if !conversion_performed {
-                return Ok(None); // Conversion could not be performed
-            }
-        } else {
-            return Ok(None); // The selection owner does not give us its data
-        }
-        let prop = self
+        let fd = libc::pollfd {
+            fd: self.conn.as_raw_fd(),
+            events: libc::POLLIN,
+            revents: 0,
+        };
+        let timeout = load.deadline.saturating_duration_since(Instant::now());
+        Ok((vec![fd], Some(timeout)))
+    }
+
+    /// Reads and deletes the property through which the owner of a selection sends its data
+    fn take_receiver_property(&self) -> Result<GetPropertyReply, OSError> {
+        let length = self
             .conn
             .get_property(
                 false,
@@ -89,100 +279,69 @@ impl Connection {
                 0,
                 0,
             )?
+            .reply()?
+            .bytes_after;
+        let prop = self
+            .conn
+            .get_property(
+                true,
+                self.hidden_window,
+                self.atoms.CLIPBOARD_RECEIVER,
+                0u32,
+                0,
+                length.div_ceil(4),
+            )?
             .reply()?;
-        if prop.type_ != self.atoms.INCR {
-            let prop_length = prop.bytes_after;
-            let prop = self
-                .conn
-                .get_property(
-                    false,
-                    self.hidden_window,
-                    self.atoms.CLIPBOARD_RECEIVER,
-                    0u32,
-                    0,
-                    prop_length,
-                )?
-                .reply()?;
-            let result = prop.value;
-            self.conn
-                .delete_property(self.hidden_window, self.atoms.CLIPBOARD_RECEIVER)?;
-            Ok(Some(result))
-        } else {
-            // The data is received incrementally
-            // Start the transference process
-            self.clipboard_data_chunk_received
-                .store(false, Ordering::SeqCst);
-            self.conn
-                .delete_property(self.hidden_window, self.atoms.CLIPBOARD_RECEIVER)?;
-            self.conn.flush()?;
-            let mut data = Vec::new();
-            let start = Instant::now();
-            loop {
-                loop {
-                    if start.elapsed() >= Duration::from_millis(100) {
-                        return Ok(None);
-                    }
-                    self.run_event_for_queue()?;
-                    if self.clipboard_data_chunk_received.load(Ordering::SeqCst) {
-                        break;
-                    }
-                    thread::yield_now();
-                }
-                self.clipboard_data_chunk_received
-                    .store(false, Ordering::SeqCst);
-                let prop = self
-                    .conn
-                    .get_property(
-                        false,
-                        self.hidden_window,
-                        self.atoms.CLIPBOARD_RECEIVER,
-                        0u32,
-                        0,
-                        0,
-                    )?
-                    .reply()?;
-                let prop_length = prop.bytes_after;
-                let prop = self
-                    .conn
-                    .get_property(
-                        true,
-                        self.hidden_window,
-                        self.atoms.CLIPBOARD_RECEIVER,
-                        0u32,
-                        0,
-                        prop_length,
-                    )?
-                    .reply()?;
-                if prop_length == 0 {
-                    break;
-                }
-                data.extend_from_slice(&prop.value);
-            }
-            Ok(Some(data))
-        }
+        Ok(prop)
     }
 
-    pub fn store_on_clipboard(&self, media_type: mime::Mime, data: &[u8]) -> Result<(), OSError> {
+    pub fn store_on_clipboard(
+        &self,
+        selection: Selection,
+        media_type: mime::Mime,
+        data: &[u8],
+    ) -> Result<(), OSError> {
+        if let Some(clipboard_thread) = &self.clipboard_thread {
+            return clipboard_thread
+                .connection
+                .store_on_clipboard(selection, media_type, data);
+        }
         self.clipboard_data
             .lock()
+            .entry(selection)
+            .or_default()
             .insert(media_type, data.to_owned());
-        self.conn
-            .set_selection_owner(self.hidden_window, self.atoms.CLIPBOARD, CURRENT_TIME)?;
+        self.own_selection(selection)
+    }
+
+    pub fn store_provider_on_clipboard(
+        &self,
+        selection: Selection,
+        provider: Arc<dyn ClipboardProvider>,
+    ) -> Result<(), OSError> {
+        if let Some(clipboard_thread) = &self.clipboard_thread {
+            return clipboard_thread
+                .connection
+                .store_provider_on_clipboard(selection, provider);
+        }
+        self.clipboard_data
+            .lock()
+            .insert(selection, Content::Provider(provider));
+        self.own_selection(selection)
+    }
+
+    fn own_selection(&self, selection: Selection) -> Result<(), OSError> {
+        self.conn.set_selection_owner(
+            self.hidden_window,
+            self.selection_atom(selection),
+            CURRENT_TIME,
+        )?;
+        // The connection of a `ClipboardThread` is not flushed by an event loop
+        self.conn.flush()?;
         Ok(())
     }
 
     pub fn process_selection_request(&self, e: SelectionRequestEvent) -> Result<(), OSError> {
-        let requested = String::from_utf8_lossy(
-            &self
-                .conn
-                .get_atom_name(e.target)
-                .unwrap()
-                .reply()
-                .unwrap()
-                .name,
-        )
-        .into_owned();
-        println!("{} requested", requested);
         let mut se = SelectionNotifyEvent {
             requestor: e.requestor,
             selection: e.selection,
@@ -192,7 +351,16 @@ impl Connection {
             response_type: SELECTION_NOTIFY_EVENT,
             sequence: 0,
         };
-        self.process_selection_request_inner(e.target, e.property, e.requestor, &mut se)?;
+        match self.selection_from_atom(e.selection) {
+            Some(selection) => self.process_selection_request_inner(
+                selection,
+                e.target,
+                e.property,
+                e.requestor,
+                &mut se,
+            )?,
+            None => se.property = NONE,
+        }
         self.conn
             .send_event(true, e.requestor, EventMask::NoEvent, se)?;
         Ok(())
@@ -200,6 +368,7 @@ impl Connection {
 
     fn process_selection_request_inner(
         &self,
+        selection: Selection,
         target: Atom,
         property: Atom,
         requestor: Window,
@@ -216,30 +385,30 @@ impl Connection {
                 &[0; 8],
             )?;
         } else if target == self.atoms.TARGETS {
-            let mut targets = Vec::with_capacity((self.clipboard_data.lock().len() * 4) + 3 + 3);
+            let mimes = self
+                .clipboard_data
+                .lock()
+                .get(&selection)
+                .map_or_else(Vec::new, Content::media_types);
+            let mut targets = Vec::with_capacity((mimes.len() * 4) + 3 + 3);
             targets.extend_from_slice(&self.atoms.TIMESTAMP.to_ne_bytes());
             targets.extend_from_slice(&self.atoms.TARGETS.to_ne_bytes());
             targets.extend_from_slice(&self.atoms.MULTIPLE.to_ne_bytes());
-            for mime in self.clipboard_data.lock().keys() {
+            for mime in &mimes {
                 let mime_atom = self
                     .conn
                     .intern_atom(false, mime.essence_str().as_bytes())?
                     .reply()?
                     .atom;
                 targets.extend_from_slice(&mime_atom.to_ne_bytes());
-                if mime.type_() == mime::TEXT && mime.subtype() == mime::PLAIN {
-                    let mut utf8 = true;
-                    if let Some(n) = mime.get_param("charset") {
-                        if n.as_str() != "utf-8" {
-                            utf8 = false;
-                        }
-                    }
-                    if utf8 {
-                        targets.extend_from_slice(&self.atoms.UTF8_STRING.to_ne_bytes());
-                        targets.extend_from_slice(&self.atoms.MIME_TEXT_PLAIN_UTF8.to_ne_bytes());
-                        targets.extend_from_slice(&self.atoms.STRING.to_ne_bytes());
-                        targets.extend_from_slice(&self.atoms.TEXT.to_ne_bytes());
-                    }
+                // `STRING` gets UTF-8 text converted to Latin-1
+                if clipboard::is_utf8_text(mime) {
+                    targets.extend_from_slice(&self.atoms.UTF8_STRING.to_ne_bytes());
+                    targets.extend_from_slice(&self.atoms.MIME_TEXT_PLAIN_UTF8.to_ne_bytes());
+                    targets.extend_from_slice(&self.atoms.STRING.to_ne_bytes());
+                    targets.extend_from_slice(&self.atoms.TEXT.to_ne_bytes());
+                } else if clipboard::is_latin1_text(mime) {
+                    targets.extend_from_slice(&self.atoms.STRING.to_ne_bytes());
                 }
             }
             self.conn.change_property(
@@ -283,7 +452,9 @@ impl Connection {
                         continue;
                     }
                     se.property = property;
-                    self.process_selection_request_inner(target, property, requestor, se)?;
+                    self.process_selection_request_inner(
+                        selection, target, property, requestor, se,
+                    )?;
                     if se.property == NONE {
                         self.conn.change_property(
                             PropMode::Replace,
@@ -308,52 +479,59 @@ impl Connection {
                 )?;
             }
         } else {
+            // A provider produces the data without holding the lock
+            let content = self.clipboard_data.lock().get(&selection).cloned();
+            let content = content.unwrap_or_default();
             let mut result = None;
             let mut result_target = target;
-            for (mime, data) in self.clipboard_data.lock().iter() {
+            let mut to_latin1 = false;
+            for mime in content.media_types() {
                 let mime_atom = self
                     .conn
                     .intern_atom(false, mime.essence_str().as_bytes())?
                     .reply()?
                     .atom;
                 if target == mime_atom {
-                    result = Some(data.clone());
+                    result = Some(mime);
                     break;
                 }
-                if mime.type_() == mime::TEXT && mime.subtype() == mime::PLAIN {
-                    let mut utf8 = true;
-                    if let Some(n) = mime.get_param("charset") {
-                        if n.as_str() != "utf-8" {
-                            utf8 = false;
-                        }
-                    }
-                    if utf8 {
-                        if target == self.atoms.MIME_TEXT_PLAIN_UTF8
-                            || target == self.atoms.UTF8_STRING
-                        {
-                            result = Some(data.clone());
-                            break;
-                        } else if target == self.atoms.TEXT {
-                            result = Some(data.clone());
-                            result_target = self.atoms.UTF8_STRING;
-                            break;
-                        } else if target == self.atoms.STRING {
-                            result = Some(data.clone());
-                            break;
-                        }
+                if clipboard::is_utf8_text(&mime) {
+                    if target == self.atoms.MIME_TEXT_PLAIN_UTF8 || target == self.atoms.UTF8_STRING
+                    {
+                        result = Some(mime);
+                        break;
+                    } else if target == self.atoms.TEXT {
+                        result = Some(mime);
+                        result_target = self.atoms.UTF8_STRING;
+                        break;
+                    } else if target == self.atoms.STRING {
+                        result = Some(mime);
+                        to_latin1 = true;
+                        break;
                     }
+                } else if clipboard::is_latin1_text(&mime) && target == self.atoms.STRING {
+                    result = Some(mime);
+                    break;
                 }
             }
-            if let Some(data) = result {
-                self.conn.change_property(
-                    PropMode::Replace,
-                    requestor,
-                    property,
-                    target,
-                    8,
-                    data.len() as u32,
-                    &data,
-                )?;
+            if let Some(mut data) = result.and_then(|mime| content.provide(&mime)) {
+                if to_latin1 {
+                    data = clipboard::utf8_to_latin1(&data);
+                }
+                // The type of the property tells the encoding chosen for `TEXT`
+                if data.len() > self.incr_chunk_size() {
+                    self.start_incr_transfer(requestor, property, result_target, data)?;
+                } else {
+                    self.conn.change_property(
+                        PropMode::Replace,
+                        requestor,
+                        property,
+                        result_target,
+                        8,
+                        data.len() as u32,
+                        &data,
+                    )?;
+                }
                 se.target = result_target;
             } else {
                 se.property = NONE;
@@ -361,4 +539,135 @@ impl Connection {
         }
         Ok(())
     }
+    fn incr_chunk_size(&self) -> usize {
+        self.conn.maximum_request_bytes() - CHANGE_PROPERTY_HEADER
+    }
+
+    fn start_incr_transfer(
+        &self,
+        requestor: Window,
+        property: Atom,
+        target: Atom,
+        data: Vec<u8>,
+    ) -> Result<(), OSError> {
+        // The requestor deletes the property to ask for each chunk, the transfer ends if it's
+        // destroyed. The hidden window already has the events it needs
+        if requestor != self.hidden_window {
+            self.conn.change_window_attributes(
+                requestor,
+                &ChangeWindowAttributesAux::new()
+                    .event_mask(EventMask::PropertyChange | EventMask::StructureNotify),
+            )?;
+        }
+        self.conn.change_property(
+            PropMode::Replace,
+            requestor,
+            property,
+            self.atoms.INCR,
+            32,
+            1,
+            &(data.len() as u32).to_ne_bytes(),
+        )?;
+        self.incr_transfers.lock().insert(
+            (requestor, property),
+            IncrTransfer {
+                target,
+                data,
+                sent: 0,
+                deadline: Instant::now() + INCR_TIMEOUT,
+            },
+        );
+        Ok(())
+    }
+
+    /// Sends the next chunk of the transfer to `requestor` through `property` if there is one,
+    /// the empty chunk ends it
+    pub fn continue_incr_transfer(&self, requestor: Window, property: Atom) -> Result<(), OSError> {
+        let chunk_size = self.incr_chunk_size();
+        let mut transfers = self.incr_transfers.lock();
+        let transfer = match transfers.get_mut(&(requestor, property)) {
+            Some(transfer) => transfer,
+            None => return Ok(()),
+        };
+        let target = transfer.target;
+        transfer.deadline = Instant::now() + INCR_TIMEOUT;
+        let chunk = transfer.next_chunk(chunk_size);
+        let finished = chunk.is_empty();
+        self.conn.change_property(
+            PropMode::Replace,
+            requestor,
+            property,
+            target,
+            8,
+            chunk.len() as u32,
+            chunk,
+        )?;
+        self.conn.flush()?;
+        if finished {
+            transfers.remove(&(requestor, property));
+            self.forget_requestor(&transfers, requestor)?;
+        }
+        Ok(())
+    }
+
+    /// Drops the transfers to a requestor that was destroyed, returns whether there were any
+    pub fn drop_incr_transfers(&self, requestor: Window) -> bool {
+        let mut transfers = self.incr_transfers.lock();
+        let count = transfers.len();
+        transfers.retain(|(window, _), _| *window != requestor);
+        transfers.len() != count
+    }
+
+    /// Drops the transfers whose requestor stopped reading, returns when the next one expires
+    pub fn expire_incr_transfers(&self) -> Result<Option<Instant>, OSError> {
+        let now = Instant::now();
+        let mut transfers = self.incr_transfers.lock();
+        let expired = transfers
+            .iter()
+            .filter(|(_, transfer)| transfer.deadline <= now)
+            .map(|(key, _)| *key)
+            .collect::<Vec<_>>();
+        for key in expired {
+            transfers.remove(&key);
+            self.forget_requestor(&transfers, key.0)?;
+        }
+        Ok(transfers.values().map(|transfer| transfer.deadline).min())
+    }
+
+    /// Stops listening to the events of `requestor` once its last transfer ended
+    fn forget_requestor(
+        &self,
+        transfers: &BTreeMap<(Window, Atom), IncrTransfer>,
+        requestor: Window,
+    ) -> Result<(), OSError> {
+        if requestor == self.hidden_window
+            || transfers.keys().any(|(window, _)| *window == requestor)
+        {
+            return Ok(());
+        }
+        self.conn.change_window_attributes(
+            requestor,
+            &ChangeWindowAttributesAux::new().event_mask(EventMask::NoEvent),
+        )?;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn incr_transfers_end_with_an_empty_chunk() {
+        let mut transfer = IncrTransfer {
+            target: NONE,
+            data: (0..10).collect(),
+            sent: 0,
+            deadline: Instant::now(),
+        };
+        assert_eq!(transfer.next_chunk(4), &[0, 1, 2, 3]);
+        assert_eq!(transfer.next_chunk(4), &[4, 5, 6, 7]);
+        assert_eq!(transfer.next_chunk(4), &[8, 9]);
+        assert!(transfer.next_chunk(4).is_empty());
+    }
 }
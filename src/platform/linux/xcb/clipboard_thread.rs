@@ -0,0 +1,98 @@
+use super::Connection;
+use crate::{error::OSError, event::Event};
+use std::{
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc,
+    },
+    thread::{self, JoinHandle},
+};
+use x11rb::protocol::xproto::Window;
+
+/// A connection of its own that answers the requests for the selections of the application, on
+/// a thread that doesn't depend on the event loop
+#[derive(Debug)]
+pub struct ClipboardThread {
+    pub connection: Arc<Connection>,
+    /// The ownership losses, delivered by the event loop, and the error that ended the thread
+    pub events: flume::Receiver<Result<Event, OSError>>,
+    stop: Arc<AtomicBool>,
+    thread: Option<JoinHandle<()>>,
+}
+
+impl ClipboardThread {
+    /// `event_window` is woken when there are events for the event loop
+    fn spawn(event_window: Window) -> Result<Self, OSError> {
+        let connection = Arc::new(Connection::new()?);
+        let (sender, events) = flume::unbounded();
+        let stop = Arc::new(AtomicBool::new(false));
+        let thread = {
+            let connection = connection.clone();
+            let stop = stop.clone();
+            thread::Builder::new()
+                .name("moving-clipboard-server".to_owned())
+                .spawn(move || {
+                    if let Err(e) = serve(&connection, &stop, &sender, event_window) {
+                        // The event loop fails with it, it's only woken while the connection of
+                        // the thread works
+                        let _ = sender.send(Err(e));
+                        let _ = connection.wake(event_window);
+                    }
+                })?
+        };
+        Ok(Self {
+            connection,
+            events,
+            stop,
+            thread: Some(thread),
+        })
+    }
+}
+
+fn serve(
+    connection: &Connection,
+    stop: &AtomicBool,
+    sender: &flume::Sender<Result<Event, OSError>>,
+    event_window: Window,
+) -> Result<(), OSError> {
+    while !stop.load(Ordering::SeqCst) {
+        connection.wait(None, &mut [])?;
+        // The selection requests are answered while the events are managed, the changes of the
+        // selections are also seen by the connection of the event loop
+        while let Some(event) = connection.poll_event()? {
+            if let Event::ClipboardOwnershipLost { .. } = event {
+                let _ = sender.send(Ok(event));
+                connection.wake(event_window)?;
+            }
+        }
+    }
+    Ok(())
+}
+
+impl Drop for ClipboardThread {
+    fn drop(&mut self) {
+        self.stop.store(true, Ordering::SeqCst);
+        // A failure means that the thread already ended
+        let _ = self.connection.wakeup();
+        if let Some(thread) = self.thread.take() {
+            let _ = thread.join();
+        }
+    }
+}
+
+impl Connection {
+    /// Hands the selections of the application to a `ClipboardThread`, other clients get their
+    /// content even while the event loop does not run
+    pub fn with_clipboard_thread(mut self) -> Result<Self, OSError> {
+        self.clipboard_thread = Some(ClipboardThread::spawn(self.hidden_window)?);
+        Ok(self)
+    }
+
+    /// Returns the window that owns the selections of the application
+    pub fn selection_window(&self) -> Window {
+        match &self.clipboard_thread {
+            Some(clipboard_thread) => clipboard_thread.connection.hidden_window,
+            None => self.hidden_window,
+        }
+    }
+}
@@ -1,5 +1,6 @@
 use crate::error::*;
-use x11rb::errors::*;
+use std::io;
+use x11rb::{errors::*, x11_utils::X11Error};
 
 impl From<ConnectionError> for OSError {
     fn from(e: ConnectionError) -> Self {
@@ -13,17 +14,35 @@ impl From<ConnectionError> for OSError {
             }
             ConnectionError::ParseError(_) => OSError::Parse,
             ConnectionError::InsufficientMemory => OSError::InsufficientMemory,
-            ConnectionError::IOError(io) => OSError::IO(io),
+            // libxcb only reports that the connection failed, which x11rb wraps in an error of
+            // the kind `Other`, the connection is unusable after it
+            ConnectionError::IOError(io)
+                if io.get_ref().is_some_and(|e| e.is::<ConnectionError>()) =>
+            {
+                OSError::DisplayLost
+            }
+            ConnectionError::IOError(io) => OSError::from_display_io(io),
             _ => OSError::Unknown,
         }
     }
 }
 
+impl From<X11Error> for OSError {
+    fn from(e: X11Error) -> Self {
+        OSError::X11Protocol {
+            major_opcode: e.major_opcode,
+            minor_opcode: e.minor_opcode,
+            error_code: e.error_code,
+            sequence: e.sequence,
+        }
+    }
+}
+
 impl From<ReplyError> for OSError {
     fn from(e: ReplyError) -> Self {
         match e {
             ReplyError::ConnectionError(e) => Self::from(e),
-            ReplyError::X11Error(e) => OSError::Other(format!("{:?}", e)),
+            ReplyError::X11Error(e) => Self::from(e),
         }
     }
 }
@@ -32,7 +51,60 @@ impl From<ReplyOrIdError> for OSError {
     fn from(e: ReplyOrIdError) -> Self {
         match e {
             ReplyOrIdError::ConnectionError(e) => Self::from(e),
+            ReplyOrIdError::X11Error(e) => Self::from(e),
             e => OSError::Other(format!("{:?}", e)),
         }
     }
 }
+
+impl From<ConnectError> for OSError {
+    fn from(e: ConnectError) -> Self {
+        match e {
+            ConnectError::InsufficientMemory => OSError::InsufficientMemory,
+            // `DISPLAY` is not set or names a server that doesn't listen
+            ConnectError::DisplayParsingError => OSError::NoDisplay,
+            ConnectError::IOError(io)
+                if matches!(
+                    io.kind(),
+                    io::ErrorKind::NotFound | io::ErrorKind::ConnectionRefused
+                ) =>
+            {
+                OSError::NoDisplay
+            }
+            ConnectError::IOError(io) => OSError::IO(io),
+            e => OSError::Other(format!("unable to connect to the X11 server: {}", e)),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use x11rb::protocol::ErrorKind;
+
+    #[test]
+    fn x11_errors_keep_the_failed_request() {
+        let error = ReplyError::X11Error(X11Error {
+            error_kind: ErrorKind::Drawable,
+            error_code: 9,
+            sequence: 42,
+            bad_value: 0x40_0001,
+            minor_opcode: 0,
+            major_opcode: 72,
+        });
+        let error = OSError::from(error);
+        assert!(matches!(
+            error,
+            OSError::X11Protocol {
+                major_opcode: 72,
+                minor_opcode: 0,
+                error_code: 9,
+                sequence: 42,
+            }
+        ));
+        assert_eq!(
+            error.to_string(),
+            "X11 error 9 for the request 72.0 (sequence 42)"
+        );
+    }
+}
@@ -1,9 +1,20 @@
 use super::Connection;
-use crate::{error::OSError, event::*, platform::WindowId};
-use std::sync::atomic::Ordering;
+use crate::{
+    error::OSError,
+    event::*,
+    platform::{poll_fds, WindowId},
+};
+use std::{
+    os::unix::io::AsRawFd,
+    sync::atomic::Ordering,
+    time::{Duration, Instant},
+};
 use x11rb::{
     connection::Connection as XConnection,
-    protocol::{xproto, Event as XEvent},
+    protocol::{
+        xproto::{self, ConnectionExt},
+        Event as XEvent,
+    },
     NONE,
 };
 
@@ -12,10 +23,15 @@ impl Connection {
         if let Some(event) = self.events_queue.lock().pop_back() {
             return Ok(Some(event));
         }
+        if let Some(clipboard_thread) = &self.clipboard_thread {
+            if let Ok(event) = clipboard_thread.events.try_recv() {
+                return event.map(Some);
+            }
+        }
         loop {
             let xevent = self.conn.poll_for_event()?;
             if let Some(event) = xevent {
-                if let Some(event) = self.manage_event(event) {
+                if let Some(event) = self.manage_event(event)? {
                     break Ok(Some(event));
                 }
             } else {
@@ -24,7 +40,89 @@ impl Connection {
         }
     }
 
-    fn manage_event(&self, event: XEvent) -> Option<Event> {
+    /// Queues an event as if it was received from the server
+    pub fn inject_event(&self, event: Event) -> Result<(), OSError> {
+        self.events_queue.lock().push_front(event);
+        Ok(())
+    }
+
+    /// Blocks until the server sends something, one of `sources` is ready, `timeout` expires
+    /// or `wakeup` is called
+    pub fn wait(
+        &self,
+        timeout: Option<Duration>,
+        sources: &mut [libc::pollfd],
+    ) -> Result<(), OSError> {
+        // libxcb keeps the events read while it waited for a reply, the socket doesn't wake up
+        // for them
+        let mut drained = false;
+        while let Some(xevent) = self.conn.poll_for_event()? {
+            drained = true;
+            if let Some(event) = self.manage_event(xevent)? {
+                self.events_queue.lock().push_front(event);
+            }
+        }
+        let clipboard_events = self
+            .clipboard_thread
+            .as_ref()
+            .is_some_and(|clipboard_thread| !clipboard_thread.events.is_empty());
+        if drained || !self.events_queue.lock().is_empty() || clipboard_events {
+            return Ok(());
+        }
+        // Wakes up to drop the transfers of the requestors that stopped reading
+        let timeout = match self.expire_incr_transfers()? {
+            Some(deadline) => {
+                let expiry = deadline.saturating_duration_since(Instant::now());
+                Some(timeout.map_or(expiry, |timeout| timeout.min(expiry)))
+            }
+            None => timeout,
+        };
+        self.conn.flush()?;
+        let mut fds = vec![libc::pollfd {
+            fd: self.conn.as_raw_fd(),
+            events: libc::POLLIN,
+            revents: 0,
+        }];
+        fds.extend_from_slice(sources);
+        poll_fds(&mut fds, timeout)?;
+        sources.copy_from_slice(&fds[1..]);
+        Ok(())
+    }
+
+    #[cfg(feature = "async")]
+    pub fn pollfds(&self) -> Result<Vec<libc::pollfd>, OSError> {
+        // The replies to the requests sent until now may be what is awaited
+        self.conn.flush()?;
+        Ok(vec![libc::pollfd {
+            fd: self.conn.as_raw_fd(),
+            events: libc::POLLIN,
+            revents: 0,
+        }])
+    }
+
+    /// Sends an event to the hidden window so a thread waiting on the connection wakes up, it's
+    /// discarded by `manage_event`
+    pub fn wakeup(&self) -> Result<(), OSError> {
+        self.wake(self.hidden_window)
+    }
+
+    /// Like `wakeup` for the connection whose hidden window is `window`
+    pub fn wake(&self, window: xproto::Window) -> Result<(), OSError> {
+        let event = xproto::ClientMessageEvent {
+            response_type: xproto::CLIENT_MESSAGE_EVENT,
+            format: 32,
+            sequence: 0,
+            window,
+            type_: self.atoms.MOVING_WAKEUP,
+            data: [0u32; 5].into(),
+        };
+        self.conn
+            .send_event(false, window, xproto::EventMask::NoEvent, event)?;
+        self.conn.flush()?;
+        Ok(())
+    }
+
+    fn manage_event(&self, event: XEvent) -> Result<Option<Event>, OSError> {
         match event {
             XEvent::SelectionNotify(e) => {
                 self.clipboard_receiver_semaphore
@@ -32,7 +130,17 @@ impl Connection {
                     .replace(e.property != NONE);
             }
             XEvent::SelectionRequest(e) => {
-                self.process_selection_request(e).unwrap();
+                self.process_selection_request(e)?;
+            }
+            XEvent::SelectionClear(e) => {
+                if let Some(selection) = self.lose_selection(e.selection) {
+                    return Ok(Some(Event::ClipboardOwnershipLost { selection }));
+                }
+            }
+            XEvent::XfixesSelectionNotify(e) if e.owner != self.selection_window() => {
+                if let Some(selection) = self.selection_from_atom(e.selection) {
+                    return Ok(Some(Event::ClipboardChanged { selection }));
+                }
             }
             XEvent::PropertyNotify(e)
                 if e.window == self.hidden_window
@@ -42,55 +150,53 @@ impl Connection {
                 self.clipboard_data_chunk_received
                     .store(true, Ordering::SeqCst);
             }
+            XEvent::PropertyNotify(e) if e.state == xproto::Property::Delete => {
+                self.continue_incr_transfer(e.window, e.atom)?;
+            }
+            // A requestor is not a window of the application
+            XEvent::DestroyNotify(e) if self.drop_incr_transfers(e.window) => return Ok(None),
             _ => {}
         }
         #[cfg(feature = "windows")]
-        match event {
+        let event = match event {
             XEvent::ButtonPress(e) | XEvent::ButtonRelease(e) => {
                 let state = match event {
                     XEvent::ButtonPress(_) => ButtonState::Pressed,
                     XEvent::ButtonRelease(_) => ButtonState::Released,
                     _ => unreachable!(),
                 };
-                let mut immediate = None;
-                let mut flag = 1;
-                for i in 0..3 {
-                    let button = match i {
-                        0 => MouseButton::Left,
-                        1 => MouseButton::Right,
-                        2 => MouseButton::Middle,
-                        _ => unreachable!(),
-                    };
-                    if e.detail & flag != 0 {
-                        if immediate.is_none() {
-                            immediate = Some(button);
-                        } else {
-                            self.events_queue.lock().push_front(Event::WindowEvent {
-                                window: WindowId::from_x11(e.event),
-                                event: WindowEvent::MouseButton {
-                                    x: e.event_x as _,
-                                    y: e.event_y as _,
-                                    button,
-                                    state,
-                                },
-                            });
-                        }
-                    }
-                    flag <<= 1;
-                }
-                if let Some(button) = immediate {
-                    Some(Event::WindowEvent {
-                        window: WindowId::from_x11(e.event),
-                        event: WindowEvent::MouseButton {
-                            x: e.event_x as _,
-                            y: e.event_y as _,
-                            button,
-                            state,
-                        },
-                    })
-                } else {
-                    None
-                }
+                // The detail is the number of the button, 4 to 7 are the scroll wheel
+                let button = match e.detail {
+                    1 => MouseButton::Left,
+                    2 => MouseButton::Middle,
+                    3 => MouseButton::Right,
+                    8 => MouseButton::Side,
+                    9 => MouseButton::Extra,
+                    _ => return Ok(None),
+                };
+                Some(Event::WindowEvent {
+                    window: WindowId::from_x11(e.event),
+                    event: WindowEvent::MouseButton {
+                        x: e.event_x as _,
+                        y: e.event_y as _,
+                        button,
+                        state,
+                    },
+                })
+            }
+            XEvent::KeyPress(e) | XEvent::KeyRelease(e) => {
+                let state = match event {
+                    XEvent::KeyPress(_) => ButtonState::Pressed,
+                    XEvent::KeyRelease(_) => ButtonState::Released,
+                    _ => unreachable!(),
+                };
+                Some(Event::WindowEvent {
+                    window: WindowId::from_x11(e.event),
+                    event: WindowEvent::Key {
+                        keycode: e.detail as u32,
+                        state,
+                    },
+                })
             }
             XEvent::ConfigureNotify(e) => {
                 if let Some(window) = self.windows.read().get(&WindowId::from_x11(e.window)) {
@@ -100,15 +206,14 @@ impl Connection {
                             &mut window.write().xcb_mut(),
                             e.width,
                             e.height,
-                        )
-                        .unwrap();
-                        return Some(Event::WindowEvent {
+                        )?;
+                        return Ok(Some(Event::WindowEvent {
                             window: WindowId::from_x11(e.window),
                             event: WindowEvent::Resize {
                                 width: e.width as f64,
                                 height: e.height as f64,
                             },
-                        });
+                        }));
                     }
                 }
                 None
@@ -137,12 +242,12 @@ impl Connection {
             XEvent::ClientMessage(event) => {
                 let data = event.data.as_data32();
                 if event.format == 32 && data[0] == self.atoms.WM_DELETE_WINDOW {
-                    return Some(Event::WindowEvent {
+                    return Ok(Some(Event::WindowEvent {
                         window: WindowId::from_x11(event.window),
                         event: WindowEvent::CloseRequested,
-                    });
+                    }));
                 }
-                return None;
+                None
             }
             XEvent::Expose(e) if e.count == 0 => Some(Event::WindowEvent {
                 window: WindowId::from_x11(e.window),
@@ -153,15 +258,26 @@ impl Connection {
                 event: WindowEvent::Destroy,
             }),
             _ => None,
-        }
+        };
         #[cfg(not(feature = "windows"))]
-        None
+        let event = None;
+        Ok(event)
     }
 
-    pub fn run_event_for_queue(&self) -> Result<(), OSError> {
-        let xevent = self.conn.poll_for_event()?;
-        if let Some(event) = xevent.and_then(|e| self.manage_event(e)) {
-            self.events_queue.lock().push_front(event);
+    /// Handles the events read while waiting for replies, the ones for the event loop are
+    /// queued and it's woken up
+    pub fn queue_read_events(&self) -> Result<(), OSError> {
+        let mut queued = false;
+        while let Some(xevent) = self.conn.poll_for_event()? {
+            if let Some(event) = self.manage_event(xevent)? {
+                self.events_queue.lock().push_front(event);
+                queued = true;
+            }
+        }
+        // The event loop may be waiting for the connection, the events were already read from
+        // it. The wakeup itself is discarded
+        if queued {
+            self.wakeup()?;
         }
         Ok(())
     }
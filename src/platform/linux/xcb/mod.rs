@@ -1,6 +1,9 @@
 use super::{WindowId, WindowPlatformData};
-use crate::{error::OSError, event::Event};
-use mime::Mime;
+use crate::{
+    clipboard::{Content, Selection},
+    error::OSError,
+    event::Event,
+};
 use parking_lot::{Mutex, RwLock};
 use std::{
     collections::{BTreeMap, VecDeque},
@@ -11,6 +14,7 @@ use x11rb::{
     connection::Connection as XConnection,
     protocol::{
         shm::ConnectionExt as ShmConnectionExt,
+        xfixes::{ConnectionExt as XfixesConnectionExt, SelectionEventMask},
         xproto::{self, ConnectionExt},
     },
     xcb_ffi::XCBConnection,
@@ -31,6 +35,7 @@ atom_manager! {
         MIME_TEXT_PLAIN_UTF8: b"text/plain;charset=utf-8",
         INCR,
         CLIPBOARD_RECEIVER,
+        MOVING_WAKEUP,
         NULL,
         _MOTIF_WM_HINTS,
         _NET_WM_NAME,
@@ -46,15 +51,19 @@ pub struct Connection {
     hidden_window: u32,
     clipboard_receiver_semaphore: Arc<Mutex<Option<bool>>>,
     events_queue: Mutex<VecDeque<Event>>,
-    clipboard_data: Mutex<BTreeMap<Mime, Vec<u8>>>,
+    clipboard_data: Mutex<BTreeMap<Selection, Content>>,
     clipboard_data_chunk_received: AtomicBool,
-    windows: RwLock<BTreeMap<WindowId, Arc<RwLock<WindowPlatformData>>>>,
+    /// The transfers to other clients in progress, by requestor and property
+    incr_transfers: Mutex<BTreeMap<(xproto::Window, xproto::Atom), clipboard::IncrTransfer>>,
+    /// Set when the selections are served by their own thread
+    clipboard_thread: Option<clipboard_thread::ClipboardThread>,
+    pub(super) windows: RwLock<BTreeMap<WindowId, Arc<RwLock<WindowPlatformData>>>>,
 }
 
 impl Connection {
     pub fn new() -> Result<Self, OSError> {
-        let (conn, screen_num) = XCBConnection::connect(None).unwrap();
-        let atoms = AtomCollection::new(&conn).unwrap();
+        let (conn, screen_num) = XCBConnection::connect(None)?;
+        let atoms = AtomCollection::new(&conn)?;
         let shm = conn
             .shm_query_version()
             .ok()
@@ -62,7 +71,7 @@ impl Connection {
             .filter(|reply| reply.shared_pixmaps)
             .is_some();
         let screen_root = conn.setup().roots[screen_num].root;
-        let win_id = conn.generate_id().unwrap();
+        let win_id = conn.generate_id()?;
         conn.create_window(
             COPY_DEPTH_FROM_PARENT,
             win_id,
@@ -75,9 +84,22 @@ impl Connection {
             xproto::WindowClass::InputOutput,
             0,
             &xproto::CreateWindowAux::new().event_mask(xproto::EventMask::PropertyChange),
-        )
-        .unwrap();
-        let atoms = atoms.reply().unwrap();
+        )?;
+        let atoms = atoms.reply()?;
+        // Without XFixes the changes of the selections made by other clients go unnoticed
+        let xfixes = conn
+            .xfixes_query_version(1, 0)
+            .ok()
+            .and_then(|cookie| cookie.reply().ok())
+            .is_some();
+        if xfixes {
+            let mask = u32::from(SelectionEventMask::SetSelectionOwner)
+                | u32::from(SelectionEventMask::SelectionWindowDestroy)
+                | u32::from(SelectionEventMask::SelectionClientClose);
+            for selection in [atoms.CLIPBOARD, xproto::AtomEnum::PRIMARY.into()].iter() {
+                conn.xfixes_select_selection_input(win_id, *selection, mask)?;
+            }
+        }
         Ok(Self {
             conn,
             screen_num,
@@ -88,6 +110,8 @@ impl Connection {
             events_queue: Mutex::new(VecDeque::new()),
             clipboard_data: Mutex::new(BTreeMap::new()),
             clipboard_data_chunk_received: AtomicBool::new(false),
+            incr_transfers: Mutex::new(BTreeMap::new()),
+            clipboard_thread: None,
             windows: RwLock::new(BTreeMap::new()),
         })
     }
@@ -95,15 +119,22 @@ impl Connection {
 
 impl Drop for Connection {
     fn drop(&mut self) {
-        self.conn.destroy_window(self.hidden_window).unwrap();
+        // Nothing is left to destroy once the connection to the server is lost
+        let _ = self.conn.destroy_window(self.hidden_window);
     }
 }
 
+#[cfg(feature = "automation")]
+mod automation;
 mod clipboard;
+mod clipboard_thread;
 mod errors;
 mod events;
 #[cfg(feature = "windows")]
+mod visual;
+#[cfg(feature = "windows")]
 mod window;
 
+pub use self::clipboard::ClipboardLoad;
 #[cfg(feature = "windows")]
 pub use self::window::*;
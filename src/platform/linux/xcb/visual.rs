@@ -0,0 +1,121 @@
+use crate::surface;
+
+/// Describes how the server expects the pixels of an image to be laid out in memory
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub struct PixelLayout {
+    pub depth: u8,
+    pub bits_per_pixel: u8,
+    pub scanline_pad: u8,
+    pub alpha_mask: u32,
+    pub red_mask: u32,
+    pub green_mask: u32,
+    pub blue_mask: u32,
+    /// Byte order of the pixels, `false` is least significant byte first
+    pub msb_first: bool,
+}
+
+impl PixelLayout {
+    /// Bytes between the beginning of two consecutive scanlines
+    pub fn stride(&self, width: u32) -> usize {
+        let pad = self.scanline_pad.max(8) as usize;
+        let bits = width as usize * self.bits_per_pixel as usize;
+        bits.div_ceil(pad) * pad / 8
+    }
+
+    /// Returns `true` if a surface of `format` can be handed to the server without conversion
+    pub fn matches(&self, format: surface::Format, width: u32) -> bool {
+        let (alpha_mask, red_mask, green_mask, blue_mask) = format.masks();
+        let bpp = format.bytes_per_pixel();
+        self.bits_per_pixel as usize == bpp * 8
+            && self.stride(width) == width as usize * bpp
+            && (self.red_mask, self.green_mask, self.blue_mask) == (red_mask, green_mask, blue_mask)
+            && (self.alpha_mask == alpha_mask || self.alpha_mask == 0)
+            && self.msb_first == cfg!(target_endian = "big")
+    }
+
+    /// Converts `src`, a tightly packed image of `format`, to this layout
+    pub fn convert(
+        &self,
+        format: surface::Format,
+        src: &[u8],
+        width: u32,
+        height: u32,
+        dst: &mut [u8],
+    ) {
+        let masks = (
+            self.alpha_mask,
+            self.red_mask,
+            self.green_mask,
+            self.blue_mask,
+        );
+        let src_bpp = format.bytes_per_pixel();
+        let dst_bpp = (self.bits_per_pixel / 8) as usize;
+        let src_stride = width as usize * src_bpp;
+        let dst_stride = self.stride(width);
+        let rows = src
+            .chunks_exact(src_stride)
+            .zip(dst.chunks_exact_mut(dst_stride))
+            .take(height as usize);
+        for (src_row, dst_row) in rows {
+            let pixels = src_row
+                .chunks_exact(src_bpp)
+                .zip(dst_row.chunks_exact_mut(dst_bpp));
+            for (src_pixel, dst_pixel) in pixels {
+                let raw = match src_bpp {
+                    2 => u16::from_ne_bytes([src_pixel[0], src_pixel[1]]) as u32,
+                    _ => {
+                        u32::from_ne_bytes([src_pixel[0], src_pixel[1], src_pixel[2], src_pixel[3]])
+                    }
+                };
+                let pixel = surface::encode_with_masks(masks, format.decode(raw));
+                for (i, byte) in dst_pixel.iter_mut().enumerate() {
+                    let shift = if self.msb_first { dst_bpp - 1 - i } else { i } * 8;
+                    *byte = (pixel >> shift) as u8;
+                }
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn layout(bits_per_pixel: u8, msb_first: bool) -> PixelLayout {
+        PixelLayout {
+            depth: 16,
+            bits_per_pixel,
+            scanline_pad: 32,
+            alpha_mask: 0,
+            red_mask: 0xf800,
+            green_mask: 0x7e0,
+            blue_mask: 0x1f,
+            msb_first,
+        }
+    }
+
+    #[test]
+    fn stride_is_padded() {
+        assert_eq!(layout(16, false).stride(3), 8);
+        assert_eq!(layout(16, false).stride(4), 8);
+        assert!(!layout(16, false).matches(surface::Format::Rgb565, 3));
+        assert!(!layout(16, false).matches(surface::Format::Argb8888, 4));
+        assert_eq!(
+            layout(16, false).matches(surface::Format::Rgb565, 4),
+            cfg!(target_endian = "little")
+        );
+    }
+
+    #[test]
+    fn argb_to_rgb565() {
+        let src: Vec<u8> = [0xffff0000u32, 0xff00ff00, 0xff0000ff]
+            .iter()
+            .flat_map(|p| p.to_ne_bytes().to_vec())
+            .collect();
+        let mut dst = vec![0; 8];
+        layout(16, false).convert(surface::Format::Argb8888, &src, 3, 1, &mut dst);
+        assert_eq!(dst, [0x00, 0xf8, 0xe0, 0x07, 0x1f, 0x00, 0, 0]);
+        layout(16, true).convert(surface::Format::Argb8888, &src, 3, 1, &mut dst);
+        assert_eq!(dst, [0xf8, 0x00, 0x07, 0xe0, 0x00, 0x1f, 0, 0]);
+    }
+}
@@ -1,31 +1,22 @@
-use super::Connection;
+use super::{visual::PixelLayout, Connection};
 use crate::{
     dpi::LogicalSize,
     error::OSError,
-    platform::{WindowId, WindowPlatformData},
-    surface::{self, Surface},
+    platform::{munmap_buffer, WindowId, WindowPlatformData},
+    surface::{self, FrameBuffer, Surface},
     window as mwin,
 };
 use atomic::Atomic;
 use libc::{mmap, munmap, MAP_ANON, MAP_FAILED, MAP_PRIVATE, MAP_SHARED, PROT_READ, PROT_WRITE};
-use parking_lot::RwLock;
-use std::{
-    mem,
-    os::unix::io::AsRawFd,
-    ptr::null_mut,
-    sync::{
-        atomic::{AtomicPtr, Ordering},
-        Arc,
-    },
-};
+use parking_lot::{Mutex, RwLock};
+use std::{mem, os::unix::io::AsRawFd, ptr::null_mut, sync::Arc};
 use x11rb::{
     connection::{Connection as XConnection, RequestConnection},
     protocol::{
         render::{self as xrender, ConnectionExt as _, PictType},
         shm::{self, ConnectionExt as _},
-        xproto::{self, ColormapAlloc, ConnectionExt, Visualid},
+        xproto::{self, ColormapAlloc, ConnectionExt, VisualClass, Visualid},
     },
-    rust_connection::ReplyError,
     wrapper::ConnectionExt as _,
 };
 
@@ -40,8 +31,25 @@ struct MotifHints {
 
 #[derive(Debug)]
 pub enum WindowBufferKind {
-    Native { depth: u8 },
+    Native {
+        depth: u8,
+    },
     Shm(shm::Seg),
+    /// The surface does not have the layout of the visual, the pixels are converted on redraw
+    Converted {
+        depth: u8,
+        image: ConvertedImage,
+    },
+}
+
+#[derive(Debug)]
+pub enum ConvertedImage {
+    Native(Mutex<Vec<u8>>),
+    Shm {
+        seg: shm::Seg,
+        ptr: *mut u8,
+        len: usize,
+    },
 }
 
 #[derive(Debug)]
@@ -51,15 +59,21 @@ pub struct Window {
     gcontext: xproto::Gcontext,
     colormap: u32,
     win_id: u32,
-    depth: u8,
+    layout: PixelLayout,
+    format: surface::Format,
     pub(super) width: u16,
     pub(super) height: u16,
-    shared_surface_data: Arc<(AtomicPtr<u8>, Atomic<surface::SharedData>)>,
+    surface: Surface,
 }
 
 impl Connection {
-    pub fn create_window(&self, builder: mwin::WindowBuilder) -> Result<mwin::Window, OSError> {
-        let (depth, visual_id) = self.choose_visual(self.screen_num)?;
+    pub fn create_window(
+        &self,
+        builder: mwin::WindowBuilder,
+        connection: Arc<crate::platform::Connection>,
+    ) -> Result<mwin::Window, OSError> {
+        let format = builder.surface_format;
+        let (visual_id, layout) = self.choose_visual(self.screen_num, format)?;
 
         let screen = &self.conn.setup().roots[self.screen_num];
         let win_id = self.conn.generate_id()?;
@@ -88,7 +102,7 @@ impl Connection {
             );
 
         self.conn.create_window(
-            depth,
+            layout.depth,
             win_id,
             screen.root,
             0,
@@ -162,8 +176,9 @@ impl Connection {
         self.conn.map_window(win_id)?;
         self.conn.flush()?;
 
-        let (pixmap, buffer_kind, surface) =
-            self.create_window_buffer(win_id, depth, width as u32, height as u32)?;
+        let (pixmap, buffer_kind, frame_buffer) =
+            self.create_window_buffer(win_id, layout, format, width as u32, height as u32)?;
+        let surface = Surface::new(format, frame_buffer, width as u32, height as u32);
 
         let logical_size = Arc::new(Atomic::new(LogicalSize {
             w: width as f64,
@@ -176,10 +191,11 @@ impl Connection {
             gcontext,
             colormap,
             win_id,
-            depth,
+            layout,
+            format,
             width,
             height,
-            shared_surface_data: surface.shared(),
+            surface: surface.clone(),
         })));
 
         self.windows
@@ -192,25 +208,33 @@ impl Connection {
             logical_size,
             dpi: Arc::new(Atomic::new(1.0)), // TODO: Implement DPI
             platform_data: window,
+            connection,
         })
     }
 
     pub fn create_window_buffer(
         &self,
         win_id: u32,
-        depth: u8,
+        layout: PixelLayout,
+        format: surface::Format,
         width: u32,
         height: u32,
-    ) -> Result<(u32, WindowBufferKind, Surface), OSError> {
+    ) -> Result<(u32, WindowBufferKind, FrameBuffer), OSError> {
+        // Pixmaps and mappings can't be empty, an empty window keeps a single pixel that is never
+        // drawn
+        let (width, height) = (width.max(1), height.max(1));
         let pixmap = self.conn.generate_id()?;
+        let depth = layout.depth;
+        let direct = layout.matches(format, width);
 
         let buffer_kind;
 
-        let frame_buffer_ptr;
-        let frame_buffer_len;
+        let frame_buffer;
+        let frame_buffer_len = (width as usize) * (height as usize) * format.bytes_per_pixel();
+        let image_len = layout.stride(width) * (height as usize);
 
         if self.shm {
-            let segment_size = (width as u32) * (height as u32) * 4;
+            let segment_size = image_len as u32;
             let shmseg = self.conn.generate_id()?;
             let reply = self
                 .conn
@@ -234,11 +258,6 @@ impl Connection {
                 return Err(x11rb::errors::ConnectionError::InsufficientMemory.into());
             }
 
-            frame_buffer_ptr = addr as *mut u8;
-            frame_buffer_len = segment_size as usize;
-
-            buffer_kind = WindowBufferKind::Shm(shmseg);
-
             if let Err(e) = self.conn.shm_create_pixmap(
                 pixmap,
                 win_id,
@@ -249,133 +268,159 @@ impl Connection {
                 0,
             ) {
                 let _ = self.conn.shm_detach(shmseg);
+                unsafe {
+                    munmap(addr, image_len);
+                }
                 return Err(e.into());
             }
+
+            if direct {
+                frame_buffer =
+                    unsafe { FrameBuffer::from_raw(addr as *mut u8, image_len, munmap_buffer) };
+                buffer_kind = WindowBufferKind::Shm(shmseg);
+            } else {
+                frame_buffer = match anonymous_mapping(frame_buffer_len) {
+                    Ok(frame_buffer) => frame_buffer,
+                    Err(e) => {
+                        let _ = self.conn.shm_detach(shmseg);
+                        let _ = self.conn.free_pixmap(pixmap);
+                        unsafe {
+                            munmap(addr, image_len);
+                        }
+                        return Err(e);
+                    }
+                };
+                buffer_kind = WindowBufferKind::Converted {
+                    depth,
+                    image: ConvertedImage::Shm {
+                        seg: shmseg,
+                        ptr: addr as *mut u8,
+                        len: image_len,
+                    },
+                };
+            }
         } else {
-            frame_buffer_len = (width as usize) * (height as usize) * 4;
             self.conn
                 .create_pixmap(depth, pixmap, win_id, width as u16, height as u16)?;
-            let addr = unsafe {
-                mmap(
-                    null_mut(),
-                    frame_buffer_len,
-                    PROT_READ | PROT_WRITE,
-                    MAP_PRIVATE | MAP_ANON,
-                    0,
-                    0,
-                )
+
+            frame_buffer = match anonymous_mapping(frame_buffer_len) {
+                Ok(frame_buffer) => frame_buffer,
+                Err(e) => {
+                    let _ = self.conn.free_pixmap(pixmap);
+                    return Err(e);
+                }
             };
 
-            if addr == MAP_FAILED {
-                return Err(x11rb::errors::ConnectionError::InsufficientMemory.into());
+            if direct {
+                buffer_kind = WindowBufferKind::Native { depth };
+            } else {
+                buffer_kind = WindowBufferKind::Converted {
+                    depth,
+                    image: ConvertedImage::Native(Mutex::new(vec![0; image_len])),
+                };
             }
-
-            frame_buffer_ptr = addr as *mut u8;
-
-            buffer_kind = WindowBufferKind::Native { depth };
         }
-        let frame_buffer_ptr = AtomicPtr::new(frame_buffer_ptr);
-        let shared_data = Atomic::new(surface::SharedData {
-            buffer_len: frame_buffer_len,
-            width,
-            height,
-        });
-
-        let shared = Arc::new((frame_buffer_ptr, shared_data));
-
         self.conn.flush()?;
-        Ok((
-            pixmap,
-            buffer_kind,
-            Surface::new(surface::Format::Argb8888, shared),
-        ))
+        Ok((pixmap, buffer_kind, frame_buffer))
     }
 
-    pub fn destroy_window(&self, window: &mut Window) -> Result<(), OSError> {
-        match window.buffer_kind {
+    /// Frees the pixmap and the memory that only the server reads, the surface memory is released
+    /// once nothing uses it
+    fn release_window_buffer(
+        &self,
+        pixmap: u32,
+        buffer_kind: &mut WindowBufferKind,
+    ) -> Result<(), OSError> {
+        // The memory of this process is released first, the requests fail once the server is
+        // gone and it frees the segments of its dead clients itself
+        match *buffer_kind {
             WindowBufferKind::Native { .. } => {}
             WindowBufferKind::Shm(shmseg) => {
                 self.conn.shm_detach(shmseg)?;
             }
+            WindowBufferKind::Converted {
+                depth,
+                image: ConvertedImage::Shm { seg, ptr, len },
+            } => {
+                unsafe {
+                    munmap(ptr as *mut _, len);
+                }
+                // The mapping is gone even if the detach fails
+                *buffer_kind = WindowBufferKind::Native { depth };
+                self.conn.shm_detach(seg)?;
+            }
+            WindowBufferKind::Converted { .. } => {}
         }
-        unsafe {
-            munmap(
-                window.shared_surface_data.0.load(Ordering::SeqCst) as *mut _,
-                window
-                    .shared_surface_data
-                    .1
-                    .load(Ordering::SeqCst)
-                    .buffer_len,
-            );
-        }
-        self.conn.free_pixmap(window.pixmap)?;
+        self.conn.free_pixmap(pixmap)?;
+        Ok(())
+    }
+
+    pub fn destroy_window(&self, window: &mut Window) -> Result<(), OSError> {
+        self.release_window_buffer(window.pixmap, &mut window.buffer_kind)?;
         self.conn.destroy_window(window.win_id)?;
         self.conn.free_colormap(window.colormap)?;
-        self.windows
-            .write()
-            .remove(&WindowId::from_x11(window.win_id));
         Ok(())
     }
 
-    pub fn redraw_window(&self, window: &Window) {
+    pub fn redraw_window(&self, window: &Window) -> Result<(), OSError> {
+        let guard = window.surface.lock();
+        if guard.size() != (window.width as u32, window.height as u32) {
+            // The surface is being resized, it will be redrawn after that
+            return Ok(());
+        }
+        if window.width == 0 || window.height == 0 {
+            return Ok(());
+        }
+        let buffer = guard.bytes();
+        let put_image = |depth, data: &[u8]| {
+            self.conn.put_image(
+                xproto::ImageFormat::ZPixmap,
+                window.pixmap,
+                window.gcontext,
+                window.width,
+                window.height,
+                0,
+                0,
+                0,
+                depth,
+                data,
+            )
+        };
+        let (width, height) = (window.width as u32, window.height as u32);
         match window.buffer_kind {
             WindowBufferKind::Native { depth } => {
-                let buffer = unsafe {
-                    std::slice::from_raw_parts_mut(
-                        window.shared_surface_data.0.load(Ordering::SeqCst),
-                        window
-                            .shared_surface_data
-                            .1
-                            .load(Ordering::SeqCst)
-                            .buffer_len,
-                    )
-                };
-                self.conn
-                    .put_image(
-                        xproto::ImageFormat::ZPixmap,
-                        window.pixmap,
-                        window.gcontext,
-                        window.width,
-                        window.height,
-                        0,
-                        0,
-                        0,
-                        depth,
-                        buffer,
-                    )
-                    .unwrap();
-
-                self.conn
-                    .copy_area(
-                        window.pixmap,
-                        window.win_id,
-                        window.gcontext,
-                        0,
-                        0,
-                        0,
-                        0,
-                        window.width,
-                        window.height,
-                    )
-                    .unwrap();
-            }
-            WindowBufferKind::Shm(_) => {
-                self.conn
-                    .copy_area(
-                        window.pixmap,
-                        window.win_id,
-                        window.gcontext,
-                        0,
-                        0,
-                        0,
-                        0,
-                        window.width,
-                        window.height,
-                    )
-                    .unwrap();
+                put_image(depth, buffer)?;
             }
+            WindowBufferKind::Shm(_) => {}
+            WindowBufferKind::Converted { depth, ref image } => match *image {
+                ConvertedImage::Native(ref image) => {
+                    let mut image = image.lock();
+                    window
+                        .layout
+                        .convert(window.format, buffer, width, height, &mut image);
+                    put_image(depth, &image)?;
+                }
+                ConvertedImage::Shm { ptr, len, .. } => {
+                    let image = unsafe { std::slice::from_raw_parts_mut(ptr, len) };
+                    window
+                        .layout
+                        .convert(window.format, buffer, width, height, image);
+                }
+            },
         }
-        self.conn.flush().unwrap();
+        self.conn.copy_area(
+            window.pixmap,
+            window.win_id,
+            window.gcontext,
+            0,
+            0,
+            0,
+            0,
+            window.width,
+            window.height,
+        )?;
+        self.conn.flush()?;
+        Ok(())
     }
 
     pub(super) fn update_win_buffer_size(
@@ -384,79 +429,167 @@ impl Connection {
         new_width: u16,
         new_height: u16,
     ) -> Result<(), OSError> {
-        match window.buffer_kind {
-            WindowBufferKind::Native { .. } => {}
-            WindowBufferKind::Shm(shmseg) => {
-                self.conn.shm_detach(shmseg)?;
-            }
-        }
-        unsafe {
-            munmap(
-                window.shared_surface_data.0.load(Ordering::SeqCst) as *mut _,
-                window
-                    .shared_surface_data
-                    .1
-                    .load(Ordering::SeqCst)
-                    .buffer_len,
-            );
-        }
-        self.conn.free_pixmap(window.pixmap)?;
-        let (pixmap, buffer_kind, new_surface) = self.create_window_buffer(
+        // The old buffer stays in place until its replacement exists, a failure leaves the window
+        // with the ids it still owns
+        let (pixmap, buffer_kind, frame_buffer) = self.create_window_buffer(
             window.win_id,
-            window.depth,
+            window.layout,
+            window.format,
             new_width as u32,
             new_height as u32,
         )?;
 
-        window.pixmap = pixmap;
-        window.shared_surface_data = new_surface.shared().clone();
-        window.buffer_kind = buffer_kind;
+        let old_pixmap = mem::replace(&mut window.pixmap, pixmap);
+        let mut old_buffer_kind = mem::replace(&mut window.buffer_kind, buffer_kind);
+        window
+            .surface
+            .replace_buffer(frame_buffer, new_width as u32, new_height as u32);
         window.width = new_width;
         window.height = new_height;
-        Ok(())
+        self.release_window_buffer(old_pixmap, &mut old_buffer_kind)
     }
 
-    // Next function is take from `x11rb` crate cairo example
+    /// Returns how the pixels of a drawable of `depth` are laid out given the channel masks of
+    /// its visual
+    fn pixel_layout(&self, depth: u8, masks: (u32, u32, u32, u32)) -> Option<PixelLayout> {
+        let setup = self.conn.setup();
+        let format = setup.pixmap_formats.iter().find(|f| f.depth == depth)?;
+        if ![8, 16, 24, 32].contains(&format.bits_per_pixel) {
+            return None;
+        }
+        let (alpha_mask, red_mask, green_mask, blue_mask) = masks;
+        Some(PixelLayout {
+            depth,
+            bits_per_pixel: format.bits_per_pixel,
+            scanline_pad: format.scanline_pad,
+            alpha_mask,
+            red_mask,
+            green_mask,
+            blue_mask,
+            msb_first: setup.image_byte_order == xproto::ImageOrder::MSBFirst,
+        })
+    }
+
+    /// Choose a visual for a surface of `format`. It tries to find a visual with the same channels
+    /// and falls back to the screen's default visual, converting the pixels when they are not laid
+    /// out like the surface ones.
+    fn choose_visual(
+        &self,
+        screen_num: usize,
+        format: surface::Format,
+    ) -> Result<(Visualid, PixelLayout), OSError> {
+        if let Some(visual) = self.find_visual(screen_num, format)? {
+            return Ok(visual);
+        }
+        if format == surface::Format::Argb8888 {
+            // Same layout, the alpha channel is just ignored
+            if let Some(visual) = self.find_visual(screen_num, surface::Format::Xrgb8888)? {
+                return Ok(visual);
+            }
+        }
 
-    /// Choose a visual to use. This function tries to find a depth=32 visual and falls back to the
-    /// screen's default visual.
-    fn choose_visual(&self, screen_num: usize) -> Result<(u8, Visualid), ReplyError> {
-        let depth = 32;
         let screen = &self.conn.setup().roots[screen_num];
+        let root_visual = screen
+            .allowed_depths
+            .iter()
+            .filter(|d| d.depth == screen.root_depth)
+            .flat_map(|d| &d.visuals)
+            .find(|v| v.visual_id == screen.root_visual && v.class == VisualClass::TrueColor);
+        root_visual
+            .and_then(|v| {
+                let masks = (0, v.red_mask, v.green_mask, v.blue_mask);
+                self.pixel_layout(screen.root_depth, masks)
+                    .map(|layout| (v.visual_id, layout))
+            })
+            .ok_or(OSError::UnsupportedFormat(format))
+    }
 
-        // Try to use XRender to find a visual with alpha support
-        let has_render = self
-            .conn
-            .extension_information(xrender::X11_EXTENSION_NAME)?
-            .is_some();
-        if has_render {
-            let formats = self.conn.render_query_pict_formats()?.reply()?;
-            // Find the ARGB32 format that must be supported.
-            let format = formats
-                .formats
+    // Next function is based on the `x11rb` crate cairo example
+
+    /// Finds a visual whose channels are the same as the `format` ones
+    fn find_visual(
+        &self,
+        screen_num: usize,
+        format: surface::Format,
+    ) -> Result<Option<(Visualid, PixelLayout)>, OSError> {
+        let depth = format.depth();
+        let screen = &self.conn.setup().roots[screen_num];
+        let masks = format.masks();
+        let (alpha_mask, red_mask, green_mask, blue_mask) = masks;
+        let layout = match self.pixel_layout(depth, masks) {
+            Some(layout) => layout,
+            None => return Ok(None),
+        };
+
+        if alpha_mask == 0 {
+            // The core protocol describes the color channels of the visuals
+            let visual = screen
+                .allowed_depths
                 .iter()
-                .filter(|info| (info.type_, info.depth) == (PictType::Direct, depth))
-                .filter(|info| {
-                    let d = info.direct;
-                    (d.red_mask, d.green_mask, d.blue_mask, d.alpha_mask)
-                        == (0xff, 0xff, 0xff, 0xff)
-                })
-                .find(|info| {
-                    let d = info.direct;
-                    (d.red_shift, d.green_shift, d.blue_shift, d.alpha_shift) == (16, 8, 0, 24)
+                .filter(|d| d.depth == depth)
+                .flat_map(|d| &d.visuals)
+                .find(|v| {
+                    v.class == VisualClass::TrueColor
+                        && (v.red_mask, v.green_mask, v.blue_mask)
+                            == (red_mask, green_mask, blue_mask)
                 });
-            if let Some(format) = format {
-                // Now we need to find the visual that corresponds to this format
-                if let Some(visual) = formats.screens[screen_num]
-                    .depths
-                    .iter()
-                    .flat_map(|d| &d.visuals)
-                    .find(|v| v.format == format.id)
-                {
-                    return Ok((format.depth, visual.visual));
-                }
-            }
+            return Ok(visual.map(|v| (v.visual_id, layout)));
+        }
+
+        // Use XRender to find a visual with alpha support
+        if self
+            .conn
+            .extension_information(xrender::X11_EXTENSION_NAME)?
+            .is_none()
+        {
+            return Ok(None);
         }
-        Ok((screen.root_depth, screen.root_visual))
+        let formats = self.conn.render_query_pict_formats()?.reply()?;
+        let channel = |mask: u32| {
+            let shift = mask.trailing_zeros() as u16;
+            ((mask >> shift) as u16, shift)
+        };
+        let (a, r, g, b) = (
+            channel(alpha_mask),
+            channel(red_mask),
+            channel(green_mask),
+            channel(blue_mask),
+        );
+        let format = formats
+            .formats
+            .iter()
+            .filter(|info| (info.type_, info.depth) == (PictType::Direct, depth))
+            .find(|info| {
+                let d = info.direct;
+                (d.red_mask, d.green_mask, d.blue_mask, d.alpha_mask) == (r.0, g.0, b.0, a.0)
+                    && (d.red_shift, d.green_shift, d.blue_shift, d.alpha_shift)
+                        == (r.1, g.1, b.1, a.1)
+            });
+        // Now we need to find the visual that corresponds to this format
+        let visual = format.and_then(|format| {
+            formats.screens[screen_num]
+                .depths
+                .iter()
+                .flat_map(|d| &d.visuals)
+                .find(|v| v.format == format.id)
+        });
+        Ok(visual.map(|v| (v.visual, layout)))
+    }
+}
+
+fn anonymous_mapping(len: usize) -> Result<FrameBuffer, OSError> {
+    let addr = unsafe {
+        mmap(
+            null_mut(),
+            len,
+            PROT_READ | PROT_WRITE,
+            MAP_PRIVATE | MAP_ANON,
+            0,
+            0,
+        )
+    };
+    if addr == MAP_FAILED {
+        return Err(x11rb::errors::ConnectionError::InsufficientMemory.into());
     }
+    Ok(unsafe { FrameBuffer::from_raw(addr as *mut u8, len, munmap_buffer) })
 }
@@ -19,3 +19,6 @@ pub use platform::*;
     not(target_os = "windows")
 ))]
 compile_error!("Platform not supported");
+
+#[cfg(all(feature = "async", not(target_os = "linux")))]
+compile_error!("The `async` feature is only supported on Linux");
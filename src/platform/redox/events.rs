@@ -1,8 +1,38 @@
 use super::*;
 use crate::{error::*, event::*};
+use std::{io, time::Duration};
 
 impl Connection {
     pub fn poll_event(&self) -> Result<Option<Event>, OSError> {
         todo!();
     }
+
+    pub fn inject_event(&self, _event: Event) -> Result<(), OSError> {
+        Err(OSError::Unsupported)
+    }
+
+    /// Only waits on `sources`, the windows aren't watched yet
+    pub fn wait(
+        &self,
+        timeout: Option<Duration>,
+        sources: &mut [libc::pollfd],
+    ) -> Result<(), OSError> {
+        // Rounded up so a wait never ends before the deadline
+        let timeout = timeout.map_or(-1, |timeout| {
+            let millis = timeout.as_nanos().div_ceil(1_000_000);
+            millis.min(i32::MAX as u128) as i32
+        });
+        if unsafe { libc::poll(sources.as_mut_ptr(), sources.len() as _, timeout) } < 0 {
+            let error = io::Error::last_os_error();
+            // An interrupted wait looks like a spurious wakeup to the event loop
+            if error.kind() != io::ErrorKind::Interrupted {
+                return Err(error.into());
+            }
+        }
+        Ok(())
+    }
+
+    pub fn wakeup(&self) -> Result<(), OSError> {
+        Err(OSError::Unsupported)
+    }
 }
@@ -20,7 +20,10 @@ impl WindowId {
 pub struct Connection {}
 
 impl Connection {
-    pub fn new() -> Result<Self, OSError> {
+    pub fn new(
+        _backend: Option<crate::event_loop::Backend>,
+        _clipboard_thread: bool,
+    ) -> Result<Self, OSError> {
         Ok(Self {})
     }
 }
@@ -1,13 +1,16 @@
 use super::*;
-use crate::{window as mwin, Size};
+use crate::{
+    dpi::LogicalSize,
+    surface::{FrameBuffer, Surface},
+    window as mwin,
+};
+use atomic::Atomic;
 use orbclient::{renderer::Renderer, Window};
 use parking_lot::RwLock;
-use std::{fmt, ptr::NonNull, sync::Arc};
+use std::{fmt, sync::Arc};
 
 pub struct WindowPlatformData {
-    buffer: Vec<u8>,
-    orb_window: Arc<RwLock<Window>>,
-    pixels_box: Arc<RwLock<mwin::PixelsBox>>,
+    orb_window: RwLock<Window>,
 }
 
 impl fmt::Debug for WindowPlatformData {
@@ -17,34 +20,38 @@ impl fmt::Debug for WindowPlatformData {
 }
 
 impl Connection {
-    pub fn create_window(&self, builder: mwin::WindowBuilder) -> Result<mwin::Window, OSError> {
-        let orb_window = Window::new(0, 0, builder.width as u32, builder.height as u32, "")
-            .ok_or_else(|| OSError::Unknown)?;
+    pub fn create_window(
+        self: &Arc<Self>,
+        builder: mwin::WindowBuilder,
+    ) -> Result<mwin::Window, OSError> {
+        let (width, height) = (builder.width as u32, builder.height as u32);
+        let orb_window =
+            Window::new(0, 0, width, height, &builder.title).ok_or_else(|| OSError::Unknown)?;
 
-        let frame_buffer_len = orb_window.data().len();
-        let mut buffer = Vec::with_capacity(frame_buffer_len);
-
-        let frame_buffer_ptr: NonNull<u8> =
-            NonNull::new(buffer.as_mut_slice().as_mut_ptr()).unwrap();
+        let len = (width * height) as usize * builder.surface_format.bytes_per_pixel();
+        let surface = Surface::new(
+            builder.surface_format,
+            FrameBuffer::alloc(len),
+            width,
+            height,
+        );
 
         // todo use correct window id
         let id = WindowId::from_orbclient(0);
 
-        let pixels_box = Arc::new(RwLock::new(mwin::PixelsBox::from_raw(
-            Size::new(builder.width, builder.height),
-            frame_buffer_ptr,
-            frame_buffer_len,
-        )));
-
         let platform_data = Arc::new(RwLock::new(WindowPlatformData {
-            buffer,
-            orb_window: Arc::new(RwLock::new(orb_window)),
-            pixels_box: pixels_box.clone(),
+            orb_window: RwLock::new(orb_window),
         }));
         Ok(mwin::Window {
             id,
-            pixels_box,
+            surface,
+            logical_size: Arc::new(Atomic::new(LogicalSize {
+                w: builder.width,
+                h: builder.height,
+            })),
+            dpi: Arc::new(Atomic::new(1.0)),
             platform_data,
+            connection: self.clone(),
         })
     }
 
@@ -52,21 +59,20 @@ impl Connection {
         todo!()
     }
 
-    pub fn redraw_window(&self, window: &mwin::Window) {
-        let color_data: Vec<orbclient::Color> = window
-            .platform_data
-            .read()
-            .buffer
-            .iter()
-            .map(|v| orbclient::Color { data: *v as u32 })
-            .collect();
-
-        window
-            .platform_data
-            .read()
-            .orb_window
-            .write()
-            .data_mut()
-            .clone_from_slice(color_data.as_slice());
+    pub fn redraw_window(&self, window: &mwin::Window) -> Result<(), OSError> {
+        let guard = window.surface.lock();
+        let format = guard.format();
+        let width = guard.width().max(1);
+        let platform_data = window.platform_data.read();
+        let mut orb_window = platform_data.orb_window.write();
+        for (i, color) in orb_window.data_mut().iter_mut().enumerate() {
+            let (x, y) = (i as u32 % width, i as u32 / width);
+            if let Some(pixel) = guard.pixel(x, y) {
+                color.data = format.decode(pixel);
+            }
+        }
+        drop(guard);
+        orb_window.sync();
+        Ok(())
     }
 }
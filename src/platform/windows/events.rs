@@ -3,12 +3,18 @@ use crate::{error::*, event::*};
 use lazy_static::lazy_static;
 use winapi::{
     shared::{
-        minwindef::{LPARAM, LRESULT, UINT, WPARAM},
+        minwindef::{FALSE, LPARAM, LRESULT, UINT, WPARAM},
         windef::HWND,
     },
-    um::winuser::{DefWindowProcW, PeekMessageA, TranslateMessage, DispatchMessageA, MSG, PM_REMOVE},
+    um::{
+        winbase::{INFINITE, WAIT_FAILED},
+        winuser::{
+            DefWindowProcW, DispatchMessageA, MsgWaitForMultipleObjects, PeekMessageA,
+            PostThreadMessageW, TranslateMessage, MSG, PM_REMOVE, QS_ALLINPUT, WM_NULL,
+        },
+    },
 };
-use std::ptr;
+use std::{io, ptr, time::Duration};
 
 lazy_static! {
     static ref EVENTS_CHANNEL: (flume::Sender<Event>, flume::Receiver<Event>) = flume::unbounded();
@@ -25,6 +31,35 @@ impl Connection {
         }
         Ok(EVENTS_CHANNEL.1.try_recv().ok())
     }
+
+    pub fn inject_event(&self, event: Event) -> Result<(), OSError> {
+        EVENTS_CHANNEL.0.send(event).map_err(|_| OSError::Unknown)
+    }
+
+    /// Blocks until a message arrives on the thread of the event loop or `timeout` expires
+    pub fn wait(&self, timeout: Option<Duration>) -> Result<(), OSError> {
+        if !EVENTS_CHANNEL.1.is_empty() {
+            return Ok(());
+        }
+        // Rounded up so a wait never ends before the deadline
+        let millis = timeout.map_or(INFINITE, |timeout| {
+            let millis = timeout.as_nanos().div_ceil(1_000_000);
+            millis.min(INFINITE as u128 - 1) as u32
+        });
+        if unsafe { MsgWaitForMultipleObjects(0, ptr::null(), FALSE, millis, QS_ALLINPUT) }
+            == WAIT_FAILED
+        {
+            return Err(io::Error::last_os_error().into());
+        }
+        Ok(())
+    }
+
+    pub fn wakeup(&self) -> Result<(), OSError> {
+        if unsafe { PostThreadMessageW(self.thread_id, WM_NULL, 0, 0) } == 0 {
+            return Err(io::Error::last_os_error().into());
+        }
+        Ok(())
+    }
 }
 
 pub(super) unsafe extern "system" fn window_proc(
@@ -1,6 +1,9 @@
 use parking_lot::RwLock;
 use std::{collections::BTreeMap, sync::Arc};
-use winapi::{shared::windef::HWND, um::winuser::WM_DESTROY};
+use winapi::{
+    shared::{minwindef::DWORD, windef::HWND},
+    um::{processthreadsapi::GetCurrentThreadId, winuser::WM_DESTROY},
+};
 
 use crate::{error::OSError, event::Event};
 mod clipboard;
@@ -26,12 +29,18 @@ unsafe impl Send for WindowId {}
 #[derive(Debug)]
 pub struct Connection {
     windows: RwLock<BTreeMap<WindowId, Arc<RwLock<WindowPlatformData>>>>,
+    // The thread running the event loop, `wakeup` posts a message to it
+    thread_id: DWORD,
 }
 
 impl Connection {
-    pub fn new() -> Result<Self, OSError> {
+    pub fn new(
+        _backend: Option<crate::event_loop::Backend>,
+        _clipboard_thread: bool,
+    ) -> Result<Self, OSError> {
         Ok(Self {
             windows: RwLock::new(BTreeMap::new()),
+            thread_id: unsafe { GetCurrentThreadId() },
         })
     }
 }
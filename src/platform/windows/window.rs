@@ -18,7 +18,7 @@ use winapi::um::{
 };
 
 use super::*;
-use crate::{error::OSError, window as mwin, Size, dpi::LogicalSize, surface::{self, Surface}};
+use crate::{error::OSError, window as mwin, Size, dpi::LogicalSize, surface::{self, FrameBuffer, Surface}};
 
 fn win32_string(value: &str) -> Vec<u16> {
     OsStr::new(value)
@@ -96,9 +96,12 @@ impl Connection {
             let frame_buffer_ptr: NonNull<u8> =
                 NonNull::new(buffer.as_mut_slice().as_mut_ptr()).unwrap();
 
-            let shared = Arc::new((AtomicPtr::new(buffer.as_mut_slice().as_mut_ptr()), Atomic::new((surface::SharedData { buffer_len: buffer.len(), width: builder.width as u32, height: builder.height as u32 }))));
-
-            let surface = Surface::new(builder.surface_format, shared);
+            let surface = Surface::new(
+                builder.surface_format,
+                FrameBuffer::alloc(frame_buffer_len),
+                builder.width as u32,
+                builder.height as u32,
+            );
 
             let platform_data = Arc::new(RwLock::new(WindowPlatformData { buffer }));
 
@@ -121,5 +124,7 @@ impl Connection {
         Ok(())
     }
 
-    pub fn redraw_window(&self, window: &mwin::Window) {}
+    pub fn redraw_window(&self, window: &mwin::Window) -> Result<(), OSError> {
+        Ok(())
+    }
 }
@@ -0,0 +1,170 @@
+//! Recording of the events delivered by an event loop and their replay
+//!
+//! A recording is a file with one JSON object per line, each one a [`RecordedEvent`]. Windows are
+//! identified by the order in which they were created by the event loop, so a recording can be
+//! replayed in a later session, where the platform ids are different.
+
+use crate::{clipboard::Selection, error::OSError, event::WindowEvent};
+use serde::{Deserialize, Serialize};
+use std::{
+    collections::VecDeque,
+    fs::File,
+    io::{BufRead, BufReader, BufWriter, Write},
+    path::Path,
+    time::{Duration, Instant},
+};
+
+#[derive(Debug, Copy, Clone, PartialEq, Serialize, Deserialize)]
+pub struct RecordedEvent {
+    /// Time since the start of the recording
+    pub time: Duration,
+    pub event: Recordable,
+}
+
+/// The events that come from the display server. The ones made by the event loop itself, the
+/// user events and the readiness of file descriptors only mean something in their session
+#[derive(Debug, Copy, Clone, PartialEq, Serialize, Deserialize)]
+pub enum Recordable {
+    Window {
+        /// Index of the window in creation order
+        window: usize,
+        event: WindowEvent,
+    },
+    ClipboardChanged {
+        selection: Selection,
+    },
+    ClipboardOwnershipLost {
+        selection: Selection,
+    },
+}
+
+/// Reads all the events of a recording
+pub fn load(path: impl AsRef<Path>) -> Result<Vec<RecordedEvent>, OSError> {
+    let reader = BufReader::new(File::open(path)?);
+    let mut events = Vec::new();
+    for line in reader.lines() {
+        let line = line?;
+        if line.trim().is_empty() {
+            continue;
+        }
+        events.push(serde_json::from_str(&line).map_err(json_error)?);
+    }
+    Ok(events)
+}
+
+/// Failures of the file are IO errors, the rest are malformed or unrepresentable events
+fn json_error(e: serde_json::Error) -> OSError {
+    if e.is_io() {
+        OSError::IO(e.into())
+    } else {
+        OSError::Parse
+    }
+}
+
+#[derive(Debug)]
+pub(crate) struct Recorder {
+    writer: BufWriter<File>,
+    start: Instant,
+}
+
+impl Recorder {
+    pub fn create(path: impl AsRef<Path>) -> Result<Self, OSError> {
+        Ok(Self {
+            writer: BufWriter::new(File::create(path)?),
+            start: Instant::now(),
+        })
+    }
+
+    pub fn record(&mut self, event: Recordable) -> Result<(), OSError> {
+        let recorded = RecordedEvent {
+            time: self.start.elapsed(),
+            event,
+        };
+        serde_json::to_writer(&mut self.writer, &recorded).map_err(json_error)?;
+        // Flushed on every event so the recording survives a crash of the application
+        writeln!(self.writer)?;
+        self.writer.flush()?;
+        Ok(())
+    }
+}
+
+#[derive(Debug)]
+pub(crate) struct Replayer {
+    events: VecDeque<RecordedEvent>,
+    start: Option<Instant>,
+}
+
+impl Replayer {
+    pub fn open(path: impl AsRef<Path>) -> Result<Self, OSError> {
+        Ok(Self::new(load(path)?))
+    }
+
+    pub fn new(events: Vec<RecordedEvent>) -> Self {
+        Self {
+            events: events.into(),
+            start: None,
+        }
+    }
+
+    /// Returns the next event if its time has come, the clock starts on the first call
+    pub fn next_due(&mut self) -> Option<RecordedEvent> {
+        match self.time_until_next() {
+            Some(time) if time == Duration::from_secs(0) => self.events.pop_front(),
+            _ => None,
+        }
+    }
+
+    /// Returns how long until the next event is due, `None` if there are no more events
+    pub fn time_until_next(&mut self) -> Option<Duration> {
+        let start = *self.start.get_or_insert_with(Instant::now);
+        let event = self.events.front()?;
+        Some(event.time.saturating_sub(start.elapsed()))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::event::{ButtonState, MouseButton};
+
+    #[test]
+    fn record_and_replay() {
+        let file = tempfile::NamedTempFile::new().unwrap();
+        let click = WindowEvent::MouseButton {
+            x: 1.5,
+            y: 2.0,
+            state: ButtonState::Pressed,
+            button: MouseButton::Left,
+        };
+        let click = Recordable::Window {
+            window: 1,
+            event: click,
+        };
+        let clipboard_changed = Recordable::ClipboardChanged {
+            selection: Selection::Primary,
+        };
+        let mut recorder = Recorder::create(file.path()).unwrap();
+        recorder.record(click).unwrap();
+        recorder.record(clipboard_changed).unwrap();
+        drop(recorder);
+
+        let events = load(file.path()).unwrap();
+        assert_eq!(events.len(), 2);
+        assert_eq!(events[0].event, click);
+        assert_eq!(events[1].event, clipboard_changed);
+        assert!(events[0].time <= events[1].time);
+
+        let mut replayer = Replayer::new(vec![
+            RecordedEvent {
+                time: Duration::ZERO,
+                ..events[0]
+            },
+            RecordedEvent {
+                time: Duration::from_secs(3600),
+                ..events[1]
+            },
+        ]);
+        assert_eq!(replayer.next_due().map(|e| e.event), Some(click));
+        assert_eq!(replayer.next_due(), None);
+    }
+}
@@ -1,15 +1,86 @@
-use atomic::Atomic;
-use std::{sync::{
-    atomic::{AtomicPtr, Ordering},
-    Arc,
-}, slice};
-
-pub(crate) type Shared = Arc<(AtomicPtr<u8>, Atomic<SharedData>)>;
+use crate::error::OSError;
+use parking_lot::{Mutex, MutexGuard, RwLock};
+use std::{
+    slice,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc,
+    },
+};
 
+/// Pixel layouts, the names follow the order of the channels from the most significant bit of
+/// a little endian pixel
 #[derive(Debug, Copy, Clone, Ord, PartialOrd, Eq, PartialEq, Hash)]
 pub enum Format {
     Argb8888,
+    /// Like `Argb8888` but the alpha channel is ignored, windows using it are opaque
     Xrgb8888,
+    Rgb565,
+    Abgr8888,
+    Argb2101010,
+}
+
+impl Format {
+    pub fn bytes_per_pixel(self) -> usize {
+        match self {
+            Self::Rgb565 => 2,
+            Self::Argb8888 | Self::Xrgb8888 | Self::Abgr8888 | Self::Argb2101010 => 4,
+        }
+    }
+
+    pub fn has_alpha(self) -> bool {
+        self.masks().0 != 0
+    }
+
+    /// Bits used by the color and alpha channels
+    pub fn depth(self) -> u8 {
+        let (a, r, g, b) = self.masks();
+        (a | r | g | b).count_ones() as u8
+    }
+
+    /// Channel masks in `(alpha, red, green, blue)` order
+    pub(crate) fn masks(self) -> (u32, u32, u32, u32) {
+        match self {
+            Self::Argb8888 => (0xff000000, 0xff0000, 0xff00, 0xff),
+            Self::Xrgb8888 => (0, 0xff0000, 0xff00, 0xff),
+            Self::Rgb565 => (0, 0xf800, 0x7e0, 0x1f),
+            Self::Abgr8888 => (0xff000000, 0xff, 0xff00, 0xff0000),
+            Self::Argb2101010 => (0xc0000000, 0x3ff00000, 0xffc00, 0x3ff),
+        }
+    }
+
+    /// Converts an `Argb8888` color to a pixel in this format
+    pub fn encode(self, argb: u32) -> u32 {
+        encode_with_masks(self.masks(), argb)
+    }
+
+    /// Converts a pixel in this format to an `Argb8888` color
+    pub fn decode(self, pixel: u32) -> u32 {
+        let (a, r, g, b) = self.masks();
+        let channel = |mask: u32, default: u32| {
+            if mask == 0 {
+                return default;
+            }
+            let shift = mask.trailing_zeros();
+            let max = mask >> shift;
+            (((pixel & mask) >> shift) * 0xff + max / 2) / max
+        };
+        (channel(a, 0xff) << 24) | (channel(r, 0) << 16) | (channel(g, 0) << 8) | channel(b, 0)
+    }
+}
+
+/// Packs an `Argb8888` color in a pixel with the given `(alpha, red, green, blue)` masks
+pub(crate) fn encode_with_masks(masks: (u32, u32, u32, u32), argb: u32) -> u32 {
+    let (a, r, g, b) = masks;
+    let channel = |mask: u32, value: u32| {
+        if mask == 0 {
+            return 0;
+        }
+        let shift = mask.trailing_zeros();
+        let max = mask >> shift;
+        (((value & 0xff) * max + 0x7f) / 0xff) << shift
+    };
+    channel(a, argb >> 24) | channel(r, argb >> 16) | channel(g, argb >> 8) | channel(b, argb)
 }
 
 // NOTE: Default must be supported by all platforms and must not fail
@@ -19,57 +90,398 @@ impl Default for Format {
     }
 }
 
-#[derive(Debug, Copy, Clone)]
-pub(crate) struct SharedData {
-    pub buffer_len: usize,
-    pub width: u32,
-    pub height: u32,
+/// Memory holding the pixels of a surface, it is released when the last reference to it is
+/// dropped so a surface that was resized never leaves dangling slices behind
+#[derive(Debug)]
+pub(crate) struct FrameBuffer {
+    ptr: *mut u8,
+    len: usize,
+    release: unsafe fn(*mut u8, usize),
+}
+
+unsafe impl Send for FrameBuffer {}
+unsafe impl Sync for FrameBuffer {}
+
+impl FrameBuffer {
+    /// `release` is called with `ptr` and `len` once the buffer is no longer used, `ptr` must
+    /// be aligned to 4 bytes
+    pub(crate) unsafe fn from_raw(
+        ptr: *mut u8,
+        len: usize,
+        release: unsafe fn(*mut u8, usize),
+    ) -> Self {
+        Self { ptr, len, release }
+    }
+
+    /// Allocates a zeroed buffer on the heap
+    #[allow(dead_code)]
+    pub(crate) fn alloc(len: usize) -> Self {
+        unsafe fn release(ptr: *mut u8, len: usize) {
+            let words = len.div_ceil(4);
+            drop(Vec::from_raw_parts(ptr as *mut u32, words, words));
+        }
+        // Allocated as words to keep the pixels aligned
+        let words = vec![0u32; len.div_ceil(4)].into_boxed_slice();
+        let ptr = Box::into_raw(words) as *mut u32 as *mut u8;
+        unsafe { Self::from_raw(ptr, len, release) }
+    }
+
+    pub(crate) fn len(&self) -> usize {
+        self.len
+    }
+}
+
+impl Drop for FrameBuffer {
+    fn drop(&mut self) {
+        unsafe { (self.release)(self.ptr, self.len) }
+    }
+}
+
+#[derive(Debug, Clone)]
+struct BufferState {
+    buffer: Arc<FrameBuffer>,
+    width: u32,
+    height: u32,
+}
+
+#[derive(Debug)]
+struct Shared {
+    /// Held by `SurfaceGuard`, only one thread can touch the pixels at a time
+    access: Mutex<()>,
+    state: RwLock<BufferState>,
+    /// Incremented every time the buffer is replaced
+    generation: AtomicU64,
 }
 
 #[derive(Debug, Clone)]
 pub struct Surface {
     format: Format,
-    shared: Arc<(AtomicPtr<u8>, Atomic<SharedData>)>,
+    shared: Arc<Shared>,
 }
 
 impl Surface {
-    pub(crate) fn new(format: Format, shared: Shared) -> Self {
-        Self { format, shared }
+    pub(crate) fn new(format: Format, buffer: FrameBuffer, width: u32, height: u32) -> Self {
+        debug_assert!(buffer.len() >= (width * height) as usize * format.bytes_per_pixel());
+        Self {
+            format,
+            shared: Arc::new(Shared {
+                access: Mutex::new(()),
+                state: RwLock::new(BufferState {
+                    buffer: Arc::new(buffer),
+                    width,
+                    height,
+                }),
+                generation: AtomicU64::new(0),
+            }),
+        }
+    }
+
+    /// Swaps the memory behind the surface, the guards taken before become stale
+    pub(crate) fn replace_buffer(&self, buffer: FrameBuffer, width: u32, height: u32) {
+        debug_assert!(buffer.len() >= (width * height) as usize * self.format.bytes_per_pixel());
+        let mut state = self.shared.state.write();
+        *state = BufferState {
+            buffer: Arc::new(buffer),
+            width,
+            height,
+        };
+        self.shared.generation.fetch_add(1, Ordering::SeqCst);
     }
 
-    pub(crate) fn shared(&self) -> Arc<(AtomicPtr<u8>, Atomic<SharedData>)> {
-        self.shared.clone()
+    pub fn format(&self) -> Format {
+        self.format
     }
 
     pub fn width(&self) -> u32 {
-        self.shared.1.load(Ordering::SeqCst).width
+        self.shared.state.read().width
     }
 
     pub fn height(&self) -> u32 {
-        self.shared.1.load(Ordering::SeqCst).height
+        self.shared.state.read().height
     }
 
     pub fn size(&self) -> (u32, u32) {
-        let shared = self.shared.1.load(Ordering::SeqCst);
-        (shared.width, shared.height)
+        let state = self.shared.state.read();
+        (state.width, state.height)
     }
 
-    /// Note that although it takes an immutable reference to self, it sets a pixel in the buffer
-    /// this is made for simplify parallerization processes
+    /// Gives exclusive access to the pixels, it blocks while another guard of the surface is
+    /// alive. Note that redrawing the window also locks its surface so the guard must be dropped
+    /// before.
+    pub fn lock(&self) -> SurfaceGuard<'_> {
+        let access = self.shared.access.lock();
+        let state = self.shared.state.read().clone();
+        SurfaceGuard {
+            format: self.format,
+            shared: &self.shared,
+            _access: access,
+            generation: self.shared.generation.load(Ordering::SeqCst),
+            state,
+        }
+    }
+
+    /// Sets a single pixel, prefer `lock` to draw many pixels because this locks the surface
+    /// every time.
+    ///
+    /// `pixel` must be in the surface format, the bits that do not fit in a pixel are discarded
     pub fn put_u32_pixel(&self, x: u32, y: u32, pixel: u32) {
-        let size_data = self.shared.1.load(Ordering::SeqCst);
-        let offset = ((y * size_data.width) + x) as usize;
-        if (offset + 1) * 4 > size_data.buffer_len {
-            return;
+        let _ = self.lock().put_pixel(x, y, pixel);
+    }
+
+    /// Returns the pixel in the surface format, `None` if it is out of the surface
+    pub fn u32_pixel(&self, x: u32, y: u32) -> Option<u32> {
+        self.lock().pixel(x, y)
+    }
+}
+
+/// Exclusive access to the pixels of a surface, if the surface is resized while the guard is
+/// alive the guard becomes stale and the methods giving access to the pixels fail
+#[derive(Debug)]
+pub struct SurfaceGuard<'a> {
+    format: Format,
+    shared: &'a Shared,
+    _access: MutexGuard<'a, ()>,
+    generation: u64,
+    state: BufferState,
+}
+
+impl<'a> SurfaceGuard<'a> {
+    pub fn format(&self) -> Format {
+        self.format
+    }
+
+    pub fn width(&self) -> u32 {
+        self.state.width
+    }
+
+    pub fn height(&self) -> u32 {
+        self.state.height
+    }
+
+    pub fn size(&self) -> (u32, u32) {
+        (self.state.width, self.state.height)
+    }
+
+    /// Bytes between the beginning of two consecutive rows
+    pub fn stride(&self) -> usize {
+        self.state.width as usize * self.format.bytes_per_pixel()
+    }
+
+    /// `true` if the surface was resized after the guard was taken
+    pub fn is_stale(&self) -> bool {
+        self.shared.generation.load(Ordering::SeqCst) != self.generation
+    }
+
+    fn check(&self) -> Result<(), OSError> {
+        if self.is_stale() {
+            Err(OSError::SurfaceResized)
+        } else {
+            Ok(())
         }
-        unsafe {
-            *(self.shared.0.load(Ordering::SeqCst) as *mut u32).offset(offset as isize) = pixel;
+    }
+
+    fn len(&self) -> usize {
+        self.stride() * self.state.height as usize
+    }
+
+    /// Read-only access to the pixels, it works even if the guard is stale
+    pub(crate) fn bytes(&self) -> &[u8] {
+        unsafe { slice::from_raw_parts(self.state.buffer.ptr, self.len()) }
+    }
+
+    pub fn bytes_mut(&mut self) -> Result<&mut [u8], OSError> {
+        self.check()?;
+        Ok(unsafe { slice::from_raw_parts_mut(self.state.buffer.ptr, self.len()) })
+    }
+
+    /// The pixels as words, only for formats of 4 bytes per pixel
+    pub fn pixels_mut(&mut self) -> Result<&mut [u32], OSError> {
+        let format = self.format;
+        as_pixels(self.bytes_mut()?, format)
+    }
+
+    /// Iterates over the rows of pixels, only for formats of 4 bytes per pixel
+    pub fn rows_mut(&mut self) -> Result<impl Iterator<Item = &mut [u32]>, OSError> {
+        let width = (self.state.width as usize).max(1);
+        Ok(self.pixels_mut()?.chunks_exact_mut(width))
+    }
+
+    /// Splits the surface in at most `n` bands of consecutive rows that can be sent to other
+    /// threads to be filled in parallel
+    pub fn split_rows_mut(&mut self, n: usize) -> Result<Vec<RowBand<'_>>, OSError> {
+        let (width, height) = self.size();
+        let format = self.format;
+        let stride = self.stride();
+        let rows_per_band = (height as usize).div_ceil(n.max(1)).max(1);
+        let bytes = self.bytes_mut()?;
+        if stride == 0 {
+            return Ok(Vec::new());
         }
+        Ok(bytes
+            .chunks_mut(stride * rows_per_band)
+            .enumerate()
+            .map(|(i, bytes)| RowBand {
+                format,
+                first_row: (i * rows_per_band) as u32,
+                width,
+                height: (bytes.len() / stride) as u32,
+                bytes,
+            })
+            .collect())
+    }
+
+    /// Returns the pixel in the surface format, `None` if it is out of the surface
+    pub fn pixel(&self, x: u32, y: u32) -> Option<u32> {
+        if x >= self.state.width || y >= self.state.height {
+            return None;
+        }
+        let bpp = self.format.bytes_per_pixel();
+        let offset = y as usize * self.stride() + x as usize * bpp;
+        Some(read_pixel(&self.bytes()[offset..offset + bpp]))
+    }
+
+    /// Sets a pixel in the surface format, the bits that do not fit in a pixel are discarded.
+    /// Pixels out of the surface are ignored.
+    pub fn put_pixel(&mut self, x: u32, y: u32, pixel: u32) -> Result<(), OSError> {
+        if x >= self.state.width || y >= self.state.height {
+            return Ok(());
+        }
+        let bpp = self.format.bytes_per_pixel();
+        let offset = y as usize * self.stride() + x as usize * bpp;
+        write_pixel(&mut self.bytes_mut()?[offset..offset + bpp], pixel);
+        Ok(())
+    }
+}
+
+/// A band of rows taken from a `SurfaceGuard`
+#[derive(Debug)]
+pub struct RowBand<'a> {
+    format: Format,
+    first_row: u32,
+    width: u32,
+    height: u32,
+    bytes: &'a mut [u8],
+}
+
+impl<'a> RowBand<'a> {
+    /// Index in the surface of the first row of the band
+    pub fn first_row(&self) -> u32 {
+        self.first_row
+    }
+
+    pub fn width(&self) -> u32 {
+        self.width
     }
 
-    pub fn data_mut(&self) -> &mut [u8] {
-        unsafe {
-            slice::from_raw_parts_mut(self.shared.0.load(Ordering::SeqCst), self.shared.1.load(Ordering::SeqCst).buffer_len)
+    pub fn height(&self) -> u32 {
+        self.height
+    }
+
+    pub fn stride(&self) -> usize {
+        self.width as usize * self.format.bytes_per_pixel()
+    }
+
+    pub fn bytes_mut(&mut self) -> &mut [u8] {
+        self.bytes
+    }
+
+    pub fn pixels_mut(&mut self) -> Result<&mut [u32], OSError> {
+        as_pixels(self.bytes, self.format)
+    }
+
+    pub fn rows_mut(&mut self) -> Result<impl Iterator<Item = &mut [u32]>, OSError> {
+        let width = (self.width as usize).max(1);
+        Ok(self.pixels_mut()?.chunks_exact_mut(width))
+    }
+
+    /// Sets a pixel, `y` is relative to the first row of the band
+    pub fn put_pixel(&mut self, x: u32, y: u32, pixel: u32) {
+        if x >= self.width || y >= self.height {
+            return;
         }
+        let bpp = self.format.bytes_per_pixel();
+        let offset = y as usize * self.stride() + x as usize * bpp;
+        write_pixel(&mut self.bytes[offset..offset + bpp], pixel);
+    }
+}
+
+fn as_pixels(bytes: &mut [u8], format: Format) -> Result<&mut [u32], OSError> {
+    if format.bytes_per_pixel() != 4 {
+        return Err(OSError::UnsupportedFormat(format));
+    }
+    // The buffers are always aligned so the prefix and the suffix are empty
+    let (prefix, pixels, suffix) = unsafe { bytes.align_to_mut::<u32>() };
+    debug_assert!(prefix.is_empty() && suffix.is_empty());
+    Ok(pixels)
+}
+
+pub(crate) fn read_pixel(bytes: &[u8]) -> u32 {
+    match bytes.len() {
+        2 => u16::from_ne_bytes([bytes[0], bytes[1]]) as u32,
+        _ => u32::from_ne_bytes([bytes[0], bytes[1], bytes[2], bytes[3]]),
+    }
+}
+
+fn write_pixel(bytes: &mut [u8], pixel: u32) {
+    match bytes.len() {
+        2 => bytes.copy_from_slice(&(pixel as u16).to_ne_bytes()),
+        _ => bytes.copy_from_slice(&pixel.to_ne_bytes()),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn format_conversions() {
+        assert_eq!(Format::Argb8888.encode(0x80123456), 0x80123456);
+        assert_eq!(Format::Xrgb8888.decode(0x00123456), 0xff123456);
+        assert_eq!(Format::Abgr8888.encode(0xff112233), 0xff332211);
+        assert_eq!(Format::Rgb565.encode(0xffff0000), 0xf800);
+        assert_eq!(Format::Rgb565.decode(0x07e0), 0xff00ff00);
+        assert_eq!(Format::Argb2101010.encode(0xffffffff), 0xffffffff);
+        assert_eq!(Format::Argb2101010.decode(0x3ff00000), 0x00ff0000);
+        assert_eq!(Format::Rgb565.bytes_per_pixel(), 2);
+        assert_eq!(Format::Xrgb8888.depth(), 24);
+        assert_eq!(Format::Argb2101010.depth(), 32);
+    }
+
+    fn surface(format: Format, width: u32, height: u32) -> Surface {
+        let len = (width * height) as usize * format.bytes_per_pixel();
+        Surface::new(format, FrameBuffer::alloc(len), width, height)
+    }
+
+    #[test]
+    fn stale_guards_fail() {
+        let surface = surface(Format::Argb8888, 4, 4);
+        let mut guard = surface.lock();
+        guard.put_pixel(1, 2, 0xff00ff00).unwrap();
+        assert_eq!(guard.pixels_mut().unwrap()[2 * 4 + 1], 0xff00ff00);
+        surface.replace_buffer(FrameBuffer::alloc(8 * 8 * 4), 8, 8);
+        assert!(guard.is_stale());
+        assert!(guard.pixels_mut().is_err());
+        assert_eq!(guard.pixel(1, 2), Some(0xff00ff00));
+        drop(guard);
+        assert_eq!(surface.lock().size(), (8, 8));
+        assert_eq!(surface.u32_pixel(1, 2), Some(0));
+    }
+
+    #[test]
+    fn row_bands_are_disjoint() {
+        let surface = surface(Format::Rgb565, 3, 5);
+        let mut guard = surface.lock();
+        assert!(guard.pixels_mut().is_err());
+        std::thread::scope(|scope| {
+            for mut band in guard.split_rows_mut(2).unwrap() {
+                scope.spawn(move || {
+                    for y in 0..band.height() {
+                        band.put_pixel(0, y, band.first_row() + y);
+                    }
+                });
+            }
+        });
+        let column: Vec<_> = (0..5).map(|y| guard.pixel(0, y).unwrap()).collect();
+        assert_eq!(column, [0, 1, 2, 3, 4]);
     }
 }
@@ -0,0 +1,466 @@
+use crate::{error::OSError, surface::SurfaceGuard, Rect};
+use std::{collections::BTreeMap, fs, path::Path, str, sync::Arc};
+
+const PSF2_MAGIC: [u8; 4] = [0x72, 0xb5, 0x4a, 0x86];
+const PSF2_HAS_UNICODE_TABLE: u32 = 0x01;
+const PSF2_SEPARATOR: u8 = 0xff;
+const PSF2_START_SEQUENCE: u8 = 0xfe;
+
+#[derive(Debug, Clone)]
+struct Glyph {
+    width: u32,
+    height: u32,
+    /// Horizontal distance from the pen position to the left of the bitmap
+    x_offset: i32,
+    /// Vertical distance from the baseline to the bottom of the bitmap
+    y_offset: i32,
+    advance: i32,
+    /// Rows of `(width + 7) / 8` bytes, the most significant bit is the leftmost pixel
+    bitmap: Vec<u8>,
+}
+
+impl Glyph {
+    fn row_len(&self) -> usize {
+        self.width.div_ceil(8) as usize
+    }
+
+    fn is_set(&self, x: u32, y: u32) -> bool {
+        let byte = self.bitmap[(y as usize) * self.row_len() + (x / 8) as usize];
+        byte & (0x80 >> (x % 8)) != 0
+    }
+}
+
+#[derive(Debug)]
+struct FontData {
+    glyphs: BTreeMap<char, Glyph>,
+    ascent: i32,
+    descent: i32,
+}
+
+/// A bitmap font, cloning it is cheap because the glyphs are shared
+#[derive(Debug, Clone)]
+pub struct Font {
+    data: Arc<FontData>,
+}
+
+#[derive(Debug, Copy, Clone, Ord, PartialOrd, Eq, PartialEq, Hash)]
+pub struct TextMetrics {
+    pub width: u32,
+    pub height: u32,
+}
+
+impl Font {
+    /// Loads a PSF2 or BDF font from a file, the format is detected from the content
+    pub fn load(path: impl AsRef<Path>) -> Result<Self, OSError> {
+        Self::from_bytes(&fs::read(path)?)
+    }
+
+    pub fn from_bytes(data: &[u8]) -> Result<Self, OSError> {
+        if data.starts_with(&PSF2_MAGIC) {
+            Self::from_psf2(data)
+        } else if data.starts_with(b"STARTFONT") {
+            Self::from_bdf(data)
+        } else {
+            Err(OSError::Parse)
+        }
+    }
+
+    pub fn from_psf2(data: &[u8]) -> Result<Self, OSError> {
+        let field = |index: usize| -> Result<u32, OSError> {
+            let start = 4 + index * 4;
+            data.get(start..start + 4)
+                .map(|b| u32::from_le_bytes([b[0], b[1], b[2], b[3]]))
+                .ok_or(OSError::Parse)
+        };
+        if !data.starts_with(&PSF2_MAGIC) {
+            return Err(OSError::Parse);
+        }
+        let header_size = field(1)? as usize;
+        let flags = field(2)?;
+        let length = field(3)? as usize;
+        let char_size = field(4)? as usize;
+        let height = field(5)?;
+        let width = field(6)?;
+        if char_size < (width as usize).div_ceil(8) * height as usize {
+            return Err(OSError::Parse);
+        }
+
+        let glyphs_end = length
+            .checked_mul(char_size)
+            .and_then(|len| len.checked_add(header_size))
+            .filter(|end| *end <= data.len())
+            .ok_or(OSError::Parse)?;
+        let bitmaps = &data[header_size..glyphs_end];
+        let glyph = |index: usize| Glyph {
+            width,
+            height,
+            x_offset: 0,
+            y_offset: 0,
+            advance: width as i32,
+            bitmap: bitmaps[index * char_size..index * char_size + char_size].to_vec(),
+        };
+
+        let mut glyphs = BTreeMap::new();
+        if flags & PSF2_HAS_UNICODE_TABLE != 0 {
+            let mut table = data[glyphs_end..].split(|b| *b == PSF2_SEPARATOR);
+            for index in 0..length {
+                let entry = table.next().ok_or(OSError::Parse)?;
+                // Multi-character sequences come after the first start marker, we only map
+                // single characters
+                let singles = entry.split(|b| *b == PSF2_START_SEQUENCE).next().unwrap();
+                let singles = str::from_utf8(singles).map_err(|_| OSError::Parse)?;
+                for c in singles.chars() {
+                    glyphs.entry(c).or_insert_with(|| glyph(index));
+                }
+            }
+        } else {
+            for index in 0..length {
+                if let Some(c) = std::char::from_u32(index as u32) {
+                    glyphs.insert(c, glyph(index));
+                }
+            }
+        }
+
+        Ok(Self {
+            data: Arc::new(FontData {
+                glyphs,
+                ascent: height as i32,
+                descent: 0,
+            }),
+        })
+    }
+
+    pub fn from_bdf(data: &[u8]) -> Result<Self, OSError> {
+        fn numbers<'a>(
+            words: impl Iterator<Item = &'a str>,
+            n: usize,
+        ) -> Result<Vec<i32>, OSError> {
+            let values = words
+                .take(n)
+                .map(|w| w.parse().map_err(|_| OSError::Parse))
+                .collect::<Result<Vec<i32>, OSError>>()?;
+            if values.len() != n {
+                return Err(OSError::Parse);
+            }
+            Ok(values)
+        }
+
+        let text = str::from_utf8(data).map_err(|_| OSError::Parse)?;
+        let mut lines = text.lines();
+        let mut bounding_box = None;
+        let mut ascent = None;
+        let mut descent = None;
+        let mut glyphs = BTreeMap::new();
+
+        // Per character state
+        let mut encoding = None;
+        let mut advance = None;
+        let mut bbx = None;
+
+        while let Some(line) = lines.next() {
+            let mut words = line.split_whitespace();
+            match words.next() {
+                Some("FONTBOUNDINGBOX") => bounding_box = Some(numbers(words, 4)?),
+                Some("FONT_ASCENT") => ascent = Some(numbers(words, 1)?[0]),
+                Some("FONT_DESCENT") => descent = Some(numbers(words, 1)?[0]),
+                Some("STARTCHAR") => {
+                    encoding = None;
+                    advance = None;
+                    bbx = None;
+                }
+                Some("ENCODING") => encoding = Some(numbers(words, 1)?[0]),
+                Some("DWIDTH") => advance = Some(numbers(words, 1)?[0]),
+                Some("BBX") => bbx = Some(numbers(words, 4)?),
+                Some("BITMAP") => {
+                    let bbx = bbx
+                        .clone()
+                        .or_else(|| bounding_box.clone())
+                        .ok_or(OSError::Parse)?;
+                    let (width, height) = (bbx[0].max(0) as u32, bbx[1].max(0) as u32);
+                    let row_len = width.div_ceil(8) as usize;
+                    let mut bitmap = Vec::with_capacity(row_len * height as usize);
+                    for _ in 0..height {
+                        let row = lines.next().ok_or(OSError::Parse)?.trim();
+                        for i in 0..row_len {
+                            let byte = row
+                                .get(i * 2..i * 2 + 2)
+                                .and_then(|hex| u8::from_str_radix(hex, 16).ok())
+                                .ok_or(OSError::Parse)?;
+                            bitmap.push(byte);
+                        }
+                    }
+                    let c = encoding
+                        .filter(|e| *e >= 0)
+                        .and_then(|e| std::char::from_u32(e as u32));
+                    if let Some(c) = c {
+                        glyphs.insert(
+                            c,
+                            Glyph {
+                                width,
+                                height,
+                                x_offset: bbx[2],
+                                y_offset: bbx[3],
+                                advance: advance.unwrap_or(width as i32),
+                                bitmap,
+                            },
+                        );
+                    }
+                }
+                _ => {}
+            }
+        }
+
+        let bounding_box = bounding_box.ok_or(OSError::Parse)?;
+        Ok(Self {
+            data: Arc::new(FontData {
+                glyphs,
+                ascent: ascent.unwrap_or(bounding_box[1] + bounding_box[3]),
+                descent: descent.unwrap_or(-bounding_box[3]),
+            }),
+        })
+    }
+
+    /// Distance from the top of a line to the baseline
+    pub fn ascent(&self) -> u32 {
+        self.data.ascent.max(0) as u32
+    }
+
+    pub fn line_height(&self) -> u32 {
+        (self.data.ascent + self.data.descent).max(0) as u32
+    }
+
+    pub fn has_glyph(&self, c: char) -> bool {
+        self.data.glyphs.contains_key(&c)
+    }
+
+    fn glyph(&self, c: char) -> Option<&Glyph> {
+        let glyphs = &self.data.glyphs;
+        glyphs
+            .get(&c)
+            .or_else(|| glyphs.get(&std::char::REPLACEMENT_CHARACTER))
+            .or_else(|| glyphs.get(&'?'))
+    }
+
+    /// Returns the size of the box that `text` fills when drawn, lines are split on `'\n'`
+    pub fn measure(&self, text: &str) -> TextMetrics {
+        let mut width = 0;
+        let mut lines = 0;
+        for line in text.split('\n') {
+            let line_width: i32 = line
+                .chars()
+                .filter_map(|c| self.glyph(c))
+                .map(|g| g.advance)
+                .sum();
+            width = width.max(line_width.max(0) as u32);
+            lines += 1;
+        }
+        TextMetrics {
+            width,
+            height: lines * self.line_height(),
+        }
+    }
+
+    /// Draws `text` with its top-left corner at `(x, y)`, `color` is ARGB whatever the surface
+    /// format is and its alpha is used to blend the glyphs over the current content of the surface
+    pub fn draw(
+        &self,
+        surface: &mut SurfaceGuard,
+        text: &str,
+        x: i32,
+        y: i32,
+        color: u32,
+    ) -> Result<(), OSError> {
+        let (width, height) = surface.size();
+        let clip = (0, 0, width as i32, height as i32);
+        self.draw_inner(surface, text, x, y, color, clip)
+    }
+
+    /// Like `draw` but nothing is drawn outside of `clip`
+    pub fn draw_clipped(
+        &self,
+        surface: &mut SurfaceGuard,
+        text: &str,
+        x: i32,
+        y: i32,
+        color: u32,
+        clip: Rect,
+    ) -> Result<(), OSError> {
+        let (width, height) = surface.size();
+        let clip = (
+            (clip.min_x().floor() as i32).max(0),
+            (clip.min_y().floor() as i32).max(0),
+            (clip.max_x().ceil() as i32).min(width as i32),
+            (clip.max_y().ceil() as i32).min(height as i32),
+        );
+        self.draw_inner(surface, text, x, y, color, clip)
+    }
+
+    fn draw_inner(
+        &self,
+        surface: &mut SurfaceGuard,
+        text: &str,
+        x: i32,
+        y: i32,
+        color: u32,
+        clip: (i32, i32, i32, i32),
+    ) -> Result<(), OSError> {
+        let (clip_x0, clip_y0, clip_x1, clip_y1) = clip;
+        let format = surface.format();
+        if clip_x0 >= clip_x1 || clip_y0 >= clip_y1 || color >> 24 == 0 {
+            return Ok(());
+        }
+        let mut baseline = y + self.data.ascent;
+        for line in text.split('\n') {
+            let mut pen_x = x;
+            for glyph in line.chars().filter_map(|c| self.glyph(c)) {
+                let left = pen_x + glyph.x_offset;
+                let top = baseline - glyph.y_offset - glyph.height as i32;
+                for gy in 0..glyph.height {
+                    let py = top + gy as i32;
+                    if py < clip_y0 || py >= clip_y1 {
+                        continue;
+                    }
+                    for gx in 0..glyph.width {
+                        let px = left + gx as i32;
+                        if px < clip_x0 || px >= clip_x1 || !glyph.is_set(gx, gy) {
+                            continue;
+                        }
+                        let (px, py) = (px as u32, py as u32);
+                        if let Some(dst) = surface.pixel(px, py) {
+                            let pixel = blend(color, format.decode(dst));
+                            surface.put_pixel(px, py, format.encode(pixel))?;
+                        }
+                    }
+                }
+                pen_x += glyph.advance;
+            }
+            baseline += self.line_height() as i32;
+        }
+        Ok(())
+    }
+}
+
+impl PartialEq for Font {
+    fn eq(&self, other: &Font) -> bool {
+        Arc::ptr_eq(&self.data, &other.data)
+    }
+}
+
+/// Fonts have no order, only a font and its clones compare, as equal
+impl PartialOrd for Font {
+    fn partial_cmp(&self, other: &Font) -> Option<std::cmp::Ordering> {
+        if self == other {
+            Some(std::cmp::Ordering::Equal)
+        } else {
+            None
+        }
+    }
+}
+
+/// Source-over blending of two ARGB pixels
+fn blend(src: u32, dst: u32) -> u32 {
+    let alpha = src >> 24;
+    if alpha == 0xff {
+        return src;
+    }
+    let channel = |shift: u32| {
+        let s = (src >> shift) & 0xff;
+        let d = (dst >> shift) & 0xff;
+        (s * alpha + d * (0xff - alpha) + 0x7f) / 0xff
+    };
+    let dst_alpha = dst >> 24;
+    let out_alpha = alpha + (dst_alpha * (0xff - alpha) + 0x7f) / 0xff;
+    (out_alpha << 24) | (channel(16) << 16) | (channel(8) << 8) | channel(0)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const BDF: &str = "STARTFONT 2.1
+FONT -test-fixed
+SIZE 8 75 75
+FONTBOUNDINGBOX 4 6 0 -1
+STARTPROPERTIES 2
+FONT_ASCENT 5
+FONT_DESCENT 1
+ENDPROPERTIES
+CHARS 2
+STARTCHAR A
+ENCODING 65
+DWIDTH 5 0
+BBX 4 5 0 0
+BITMAP
+60
+90
+F0
+90
+90
+ENDCHAR
+STARTCHAR space
+ENCODING 32
+DWIDTH 3 0
+BBX 1 1 0 0
+BITMAP
+00
+ENDCHAR
+ENDFONT
+";
+
+    fn psf2(unicode: Option<&[u8]>) -> Vec<u8> {
+        let mut data = PSF2_MAGIC.to_vec();
+        let flags = if unicode.is_some() { 1 } else { 0 };
+        for field in &[0u32, 32, flags, 2, 8, 8, 6] {
+            data.extend_from_slice(&field.to_le_bytes());
+        }
+        data.extend_from_slice(&[0xfc; 8]);
+        data.extend_from_slice(&[0x30; 8]);
+        if let Some(table) = unicode {
+            data.extend_from_slice(table);
+        }
+        data
+    }
+
+    #[test]
+    fn bdf_metrics() {
+        let font = Font::from_bytes(BDF.as_bytes()).unwrap();
+        assert_eq!(font.ascent(), 5);
+        assert_eq!(font.line_height(), 6);
+        assert!(font.has_glyph('A'));
+        assert_eq!(
+            font.measure("A A\nA"),
+            TextMetrics {
+                width: 13,
+                height: 12
+            }
+        );
+        // Unknown characters have no replacement glyph in this font
+        assert_eq!(font.measure("Z").width, 0);
+    }
+
+    #[test]
+    fn psf2_unicode_table() {
+        let font = Font::from_bytes(&psf2(Some(b"a\xffb\xc3\xa9\xfe\x65\xcc\x81\xff"))).unwrap();
+        assert!(font.has_glyph('a'));
+        assert!(font.has_glyph('b'));
+        assert!(font.has_glyph('é'));
+        assert!(!font.has_glyph('e'));
+        assert_eq!(font.measure("ab").width, 12);
+        assert_eq!(font.line_height(), 8);
+    }
+
+    #[test]
+    fn psf2_without_table_maps_indices() {
+        let font = Font::from_psf2(&psf2(None)).unwrap();
+        assert!(font.has_glyph('\u{0}'));
+        assert!(font.has_glyph('\u{1}'));
+        assert!(Font::from_psf2(&psf2(None)[..40]).is_err());
+    }
+
+    #[test]
+    fn blending() {
+        assert_eq!(blend(0xff123456, 0xffabcdef), 0xff123456);
+        assert_eq!(blend(0x00123456, 0xffabcdef), 0xffabcdef);
+        assert_eq!(blend(0x80ffffff, 0xff000000), 0xff808080);
+    }
+}
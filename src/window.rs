@@ -2,13 +2,15 @@ use crate::{
     dpi,
     error::OSError,
     event_loop::EventLoop,
-    platform::{WindowId, WindowPlatformData},
-    surface, CONNECTION,
+    platform::{Connection, WindowPlatformData},
+    surface, text,
 };
 use atomic::Atomic;
 use parking_lot::RwLock;
 use std::sync::{atomic::Ordering, Arc};
 
+pub use crate::platform::WindowId;
+
 /// Be careful the windows support transparency and are fully transparent at the start
 #[derive(Debug)]
 pub struct Window {
@@ -18,9 +20,14 @@ pub struct Window {
     pub(crate) dpi: Arc<Atomic<dpi::Dpi>>,
     // This is used to store platform-specific information
     pub(crate) platform_data: Arc<RwLock<WindowPlatformData>>,
+    pub(crate) connection: Arc<Connection>,
 }
 
 impl Window {
+    pub fn id(&self) -> WindowId {
+        self.id
+    }
+
     pub fn dpi(&self) -> dpi::Dpi {
         self.dpi.load(Ordering::SeqCst)
     }
@@ -37,8 +44,22 @@ impl Window {
         self.surface.clone()
     }
 
-    pub fn redraw(&self) {
-        CONNECTION.redraw_window(&self);
+    /// Presents the content of the surface, fails when the display connection is lost
+    pub fn redraw(&self) -> Result<(), OSError> {
+        self.connection.redraw_window(self)
+    }
+
+    /// Destroys the window now instead of when it's dropped, the surface stays usable but is
+    /// never presented again
+    pub fn close(&self) -> Result<(), OSError> {
+        self.connection.destroy_window(self.id)
+    }
+}
+
+impl Drop for Window {
+    fn drop(&mut self) {
+        // Nothing is left to clean up when the connection failed
+        let _ = self.close();
     }
 }
 
@@ -49,6 +70,7 @@ pub struct WindowBuilder {
     pub(crate) title: String,
     pub(crate) decorations: bool,
     pub(crate) surface_format: surface::Format,
+    pub(crate) title_font: Option<text::Font>,
 }
 
 impl WindowBuilder {
@@ -59,6 +81,7 @@ impl WindowBuilder {
             title: String::new(),
             decorations: true,
             surface_format: surface::Format::default(),
+            title_font: None,
         }
     }
 
@@ -93,7 +116,14 @@ impl WindowBuilder {
         self
     }
 
-    pub fn build(self, el: &EventLoop) -> Result<Window, OSError> {
+    /// Font used to draw the title on client-side decorations, without it the title is only
+    /// given to the window manager
+    pub fn with_title_font(mut self, font: text::Font) -> Self {
+        self.title_font = Some(font);
+        self
+    }
+
+    pub fn build<T: 'static>(self, el: &EventLoop<T>) -> Result<Window, OSError> {
         el.create_window(self)
     }
 }
@@ -0,0 +1,635 @@
+#![cfg(all(feature = "headless", feature = "windows"))]
+
+use moving::{
+    error::OSError,
+    event::{Event, StartCause, WindowEvent},
+    event_loop::{Backend, ControlFlow, EventLoop, EventLoopBuilder, EventLoopClosed},
+    headless,
+    window::WindowBuilder,
+};
+use std::{
+    sync::{Arc, Mutex},
+    time::{Duration, Instant},
+};
+
+// The backend is given to the builder so the tests can run in parallel, only
+// `backend_selection` goes through the environment
+fn headless_loop() -> EventLoop {
+    headless_loop_with_user_event()
+}
+
+fn headless_loop_with_user_event<T: 'static>() -> EventLoop<T> {
+    EventLoopBuilder::with_user_event()
+        .with_backend(Backend::Headless)
+        .build()
+        .unwrap()
+}
+
+#[test]
+fn events_and_frames() {
+    let event_loop = headless_loop();
+    let window = WindowBuilder::new()
+        .with_size(4.0, 3.0)
+        .build(&event_loop)
+        .unwrap();
+    assert!(headless::is_active(&event_loop));
+
+    let surface = window.surface();
+    surface.lock().put_pixel(1, 2, 0xff102030).unwrap();
+    window.redraw().unwrap();
+    let frames = headless::take_frames(&window).unwrap();
+    assert_eq!(frames.len(), 1);
+    assert_eq!((frames[0].width(), frames[0].height()), (4, 3));
+    assert_eq!(frames[0].pixel(1, 2), Some(0xff102030));
+    assert_eq!(frames[0].pixel(4, 0), None);
+    assert!(headless::take_frames(&window).unwrap().is_empty());
+
+    let resize = WindowEvent::Resize {
+        width: 8.0,
+        height: 6.0,
+    };
+    headless::push_window_event(&window, resize).unwrap();
+    headless::push_window_event(&window, WindowEvent::CloseRequested).unwrap();
+
+    let received = Arc::new(Mutex::new(Vec::new()));
+    let handler_received = received.clone();
+    event_loop
+        .run(move |event, control_flow| {
+            if let Event::WindowEvent { event, .. } = event {
+                handler_received.lock().unwrap().push(event);
+                if event == WindowEvent::CloseRequested {
+                    *control_flow = ControlFlow::Exit;
+                }
+            }
+        })
+        .unwrap();
+    assert_eq!(
+        *received.lock().unwrap(),
+        [resize, WindowEvent::CloseRequested]
+    );
+    assert_eq!(surface.size(), (8, 6));
+}
+
+#[test]
+fn injected_destroy_removes_the_window() {
+    let event_loop = headless_loop();
+    let window = WindowBuilder::new()
+        .with_size(2.0, 2.0)
+        .build(&event_loop)
+        .unwrap();
+    let proxy = event_loop.create_proxy();
+    let id = window.id();
+    std::thread::spawn(move || {
+        proxy
+            .inject(Event::WindowEvent {
+                window: id,
+                event: WindowEvent::Destroy,
+            })
+            .unwrap();
+    })
+    .join()
+    .unwrap();
+
+    event_loop
+        .run(move |event, control_flow| {
+            if let Event::WindowEvent {
+                event: WindowEvent::Destroy,
+                ..
+            } = event
+            {
+                *control_flow = ControlFlow::Exit;
+            }
+        })
+        .unwrap();
+    assert!(headless::take_frames(&window).is_err());
+}
+
+#[test]
+fn windows_close_independently() {
+    let event_loop = headless_loop();
+    let build = || {
+        WindowBuilder::new()
+            .with_size(2.0, 2.0)
+            .build(&event_loop)
+            .unwrap()
+    };
+    let first = build();
+    let second = build();
+    drop(build());
+
+    second.close().unwrap();
+    second.close().unwrap();
+    assert!(matches!(second.redraw(), Err(OSError::WindowClosed)));
+    assert!(headless::take_frames(&second).is_err());
+    drop(second);
+
+    let third = build();
+    for window in [&first, &third] {
+        window.redraw().unwrap();
+        assert_eq!(headless::take_frames(window).unwrap().len(), 1);
+    }
+}
+
+#[test]
+fn user_events_from_other_threads() {
+    let event_loop = headless_loop_with_user_event::<u32>();
+    let proxy = event_loop.create_proxy();
+    std::thread::spawn(move || {
+        for i in 0..3 {
+            proxy.send_event(i).unwrap();
+        }
+    })
+    .join()
+    .unwrap();
+
+    let received = Arc::new(Mutex::new(Vec::new()));
+    let handler_received = received.clone();
+    event_loop
+        .run(move |event, control_flow| {
+            if let Event::UserEvent(i) = event {
+                handler_received.lock().unwrap().push(i);
+                if i == 2 {
+                    *control_flow = ControlFlow::Exit;
+                }
+            }
+        })
+        .unwrap();
+    assert_eq!(*received.lock().unwrap(), [0, 1, 2]);
+}
+
+#[test]
+fn user_events_without_debug() {
+    struct Opaque(u32);
+
+    let event_loop = headless_loop_with_user_event::<Opaque>();
+    let proxy = event_loop.create_proxy();
+    assert!(proxy.send_event(Opaque(1)).is_ok());
+    drop(event_loop);
+    match proxy.send_event(Opaque(2)) {
+        Err(EventLoopClosed(Opaque(2))) => {}
+        _ => panic!("the event wasn't handed back"),
+    }
+}
+
+#[test]
+fn wait_blocks_until_woken_or_timed_out() {
+    let event_loop = headless_loop_with_user_event::<&'static str>();
+    let proxy = event_loop.create_proxy();
+    let sender = std::thread::spawn(move || {
+        std::thread::sleep(Duration::from_millis(50));
+        proxy.send_event("woken").unwrap();
+    });
+
+    let start = Instant::now();
+    let received = Arc::new(Mutex::new(Vec::new()));
+    let handler_received = received.clone();
+    let mut iterations = 0;
+    event_loop
+        .run(move |event, control_flow| match event {
+            Event::NewEvents(cause) => handler_received
+                .lock()
+                .unwrap()
+                .push(format!("{:?}", cause)),
+            Event::UserEvent(name) => handler_received.lock().unwrap().push(name.to_owned()),
+            Event::MainEventsCleared => {
+                iterations += 1;
+                *control_flow = match iterations {
+                    1 => ControlFlow::Wait,
+                    2 => ControlFlow::WaitUntil(Instant::now() + Duration::from_millis(20)),
+                    _ => ControlFlow::Exit,
+                };
+            }
+            _ => {}
+        })
+        .unwrap();
+    sender.join().unwrap();
+    assert!(start.elapsed() >= Duration::from_millis(70));
+    assert_eq!(
+        *received.lock().unwrap(),
+        ["WaitCancelled", "woken", "ResumeTimeReached"]
+    );
+}
+
+#[test]
+fn registered_fds_wake_the_loop() {
+    use moving::event_loop::Interest;
+    use std::{io::Write, os::unix::io::AsRawFd, os::unix::net::UnixStream};
+
+    let event_loop = headless_loop();
+    let (reader, mut writer) = UnixStream::pair().unwrap();
+    let token = event_loop.register_fd(reader.as_raw_fd(), Interest::READABLE);
+    let sender = std::thread::spawn(move || {
+        std::thread::sleep(Duration::from_millis(20));
+        writer.write_all(b"ping").unwrap();
+        writer
+    });
+
+    let ready = Arc::new(Mutex::new(None));
+    let handler_ready = ready.clone();
+    event_loop
+        .run(move |event, control_flow| {
+            *control_flow = ControlFlow::Wait;
+            if let Event::FdReady {
+                token: ready_token,
+                readable,
+                writable,
+            } = event
+            {
+                *handler_ready.lock().unwrap() = Some((ready_token, readable, writable));
+                *control_flow = ControlFlow::Exit;
+            }
+        })
+        .unwrap();
+    sender.join().unwrap();
+    assert_eq!(*ready.lock().unwrap(), Some((token, true, false)));
+    assert!(event_loop.unregister_fd(token));
+    assert!(!event_loop.unregister_fd(token));
+    drop(reader);
+}
+
+#[test]
+fn pump_events_returns_to_the_caller() {
+    use moving::event_loop::PumpStatus;
+
+    let event_loop = headless_loop();
+    let window = WindowBuilder::new()
+        .with_size(2.0, 2.0)
+        .build(&event_loop)
+        .unwrap();
+
+    let mut events = Vec::new();
+    let status =
+        event_loop.pump_events(Some(Duration::from_secs(0)), |event, _| events.push(event));
+    assert_eq!(status, PumpStatus::Continue);
+    assert_eq!(
+        events,
+        [Event::NewEvents(StartCause::Poll), Event::MainEventsCleared]
+    );
+
+    events.clear();
+    headless::push_window_event(&window, WindowEvent::Destroy).unwrap();
+    let status = event_loop.pump_events(None, |event, _| events.push(event));
+    assert_eq!(status, PumpStatus::Continue);
+    assert_eq!(
+        events[1],
+        Event::WindowEvent {
+            window: window.id(),
+            event: WindowEvent::Destroy,
+        }
+    );
+    assert!(headless::take_frames(&window).is_err());
+
+    let start = Instant::now();
+    let status = event_loop.pump_events(Some(Duration::from_millis(20)), |event, control_flow| {
+        if event == Event::NewEvents(StartCause::ResumeTimeReached) {
+            *control_flow = ControlFlow::Exit;
+        }
+    });
+    assert_eq!(status, PumpStatus::Exit);
+    assert!(start.elapsed() >= Duration::from_millis(20));
+}
+
+#[test]
+fn display_loss_ends_the_loop() {
+    use moving::error::OSError;
+
+    let event_loop = headless_loop();
+    let window = WindowBuilder::new()
+        .with_size(2.0, 2.0)
+        .build(&event_loop)
+        .unwrap();
+    headless::push_window_event(&window, WindowEvent::Dirted).unwrap();
+    headless::disconnect(&event_loop).unwrap();
+
+    let received = Arc::new(Mutex::new(Vec::new()));
+    let handler_received = received.clone();
+    let result = event_loop.run(move |event, _| match event {
+        Event::WindowEvent { .. } | Event::DisplayLost => {
+            handler_received.lock().unwrap().push(event)
+        }
+        _ => {}
+    });
+    assert!(matches!(result, Err(OSError::DisplayLost)));
+    assert_eq!(
+        *received.lock().unwrap(),
+        [
+            Event::WindowEvent {
+                window: window.id(),
+                event: WindowEvent::Dirted,
+            },
+            Event::DisplayLost
+        ]
+    );
+    assert!(window.redraw().is_err());
+    assert!(headless::take_frames(&window).is_err());
+}
+
+/// Runs `future` on the current thread, parking it until the future is woken
+#[cfg(feature = "async")]
+fn block_on<F: std::future::Future>(future: F) -> F::Output {
+    use std::task::{Context, Poll, Wake, Waker};
+
+    struct Unparker(std::thread::Thread);
+
+    impl Wake for Unparker {
+        fn wake(self: Arc<Self>) {
+            self.0.unpark();
+        }
+    }
+
+    let waker = Waker::from(Arc::new(Unparker(std::thread::current())));
+    let mut cx = Context::from_waker(&waker);
+    let mut future = Box::pin(future);
+    loop {
+        if let Poll::Ready(output) = future.as_mut().poll(&mut cx) {
+            return output;
+        }
+        std::thread::park();
+    }
+}
+
+#[cfg(feature = "async")]
+#[test]
+fn event_stream_wakes_the_task() {
+    use moving::event_stream::Stream;
+    use std::pin::Pin;
+
+    let event_loop = headless_loop_with_user_event();
+    let window = WindowBuilder::new()
+        .with_size(2.0, 2.0)
+        .build(&event_loop)
+        .unwrap();
+    let mut events = event_loop.events().unwrap();
+    let mut next = || {
+        block_on(std::future::poll_fn(|cx| {
+            Pin::new(&mut events).poll_next(cx)
+        }))
+    };
+
+    headless::push_window_event(&window, WindowEvent::CloseRequested).unwrap();
+    assert_eq!(
+        next(),
+        Some(Event::WindowEvent {
+            window: window.id(),
+            event: WindowEvent::CloseRequested,
+        })
+    );
+
+    let proxy = event_loop.create_proxy();
+    let sender = std::thread::spawn(move || {
+        std::thread::sleep(Duration::from_millis(20));
+        proxy.send_event(7u32).unwrap();
+    });
+    assert_eq!(next(), Some(Event::UserEvent(7)));
+    sender.join().unwrap();
+}
+
+#[cfg(feature = "async")]
+#[test]
+fn async_clipboard_transfers() {
+    use moving::clipboard::{self, mime, Selection};
+
+    let event_loop = headless_loop();
+    block_on(clipboard::store_async(
+        &event_loop,
+        Selection::Primary,
+        mime::TEXT_PLAIN_UTF_8,
+        b"moving",
+    ))
+    .unwrap();
+    let load = |selection| {
+        block_on(clipboard::load_async(
+            &event_loop,
+            selection,
+            mime::TEXT_PLAIN_UTF_8,
+        ))
+        .unwrap()
+    };
+    assert_eq!(load(Selection::Primary).as_deref(), Some(&b"moving"[..]));
+    assert_eq!(load(Selection::Clipboard), None);
+}
+
+#[test]
+fn selections_are_independent() {
+    use moving::clipboard::{self, mime, Selection};
+
+    let event_loop = headless_loop();
+    clipboard::store(&event_loop, mime::TEXT_PLAIN, b"copied").unwrap();
+    clipboard::store_to(
+        &event_loop,
+        Selection::Primary,
+        mime::TEXT_PLAIN,
+        b"selected",
+    )
+    .unwrap();
+    assert_eq!(
+        clipboard::load(&event_loop, mime::TEXT_PLAIN).unwrap(),
+        Some(b"copied".to_vec())
+    );
+    assert_eq!(
+        clipboard::load_from(&event_loop, Selection::Primary, mime::TEXT_PLAIN).unwrap(),
+        Some(b"selected".to_vec())
+    );
+}
+
+#[test]
+fn available_types_of_the_clipboard() {
+    use moving::clipboard::{self, mime, Selection};
+
+    let event_loop = headless_loop();
+    assert!(clipboard::available_types(&event_loop).unwrap().is_empty());
+    clipboard::store(&event_loop, mime::TEXT_HTML, b"<b>moving</b>").unwrap();
+    assert_eq!(
+        clipboard::available_types(&event_loop).unwrap(),
+        vec![mime::TEXT_HTML]
+    );
+    assert!(
+        clipboard::available_types_in(&event_loop, Selection::Primary)
+            .unwrap()
+            .is_empty()
+    );
+}
+
+#[test]
+fn lazy_clipboard_content() {
+    use moving::clipboard::{self, mime, ClipboardProvider};
+    use std::sync::atomic::{AtomicUsize, Ordering};
+
+    struct Image {
+        encoded: Arc<AtomicUsize>,
+    }
+
+    impl ClipboardProvider for Image {
+        fn media_types(&self) -> Vec<mime::Mime> {
+            vec![mime::IMAGE_PNG, mime::IMAGE_BMP]
+        }
+
+        fn provide(&self, media_type: &mime::Mime) -> Option<Vec<u8>> {
+            self.encoded.fetch_add(1, Ordering::SeqCst);
+            Some(media_type.subtype().as_str().as_bytes().to_vec())
+        }
+    }
+
+    let event_loop = headless_loop();
+    let encoded = Arc::new(AtomicUsize::new(0));
+    clipboard::store_lazy(
+        &event_loop,
+        Image {
+            encoded: encoded.clone(),
+        },
+    )
+    .unwrap();
+    assert_eq!(
+        clipboard::available_types(&event_loop).unwrap(),
+        vec![mime::IMAGE_PNG, mime::IMAGE_BMP]
+    );
+    assert_eq!(encoded.load(Ordering::SeqCst), 0);
+    assert_eq!(
+        clipboard::load(&event_loop, mime::IMAGE_BMP).unwrap(),
+        Some(b"bmp".to_vec())
+    );
+    assert_eq!(encoded.load(Ordering::SeqCst), 1);
+    assert_eq!(
+        clipboard::load(&event_loop, mime::TEXT_PLAIN).unwrap(),
+        None
+    );
+}
+
+#[test]
+fn clipboard_taken_by_another_client() {
+    use moving::clipboard::{self, mime, Selection};
+
+    let event_loop = headless_loop();
+    clipboard::store(&event_loop, mime::TEXT_PLAIN, b"ours").unwrap();
+    headless::store_as_other_client(
+        &event_loop,
+        Selection::Clipboard,
+        mime::TEXT_PLAIN,
+        b"theirs",
+    )
+    .unwrap();
+    headless::store_as_other_client(&event_loop, Selection::Primary, mime::TEXT_PLAIN, b"theirs")
+        .unwrap();
+
+    let events = Arc::new(Mutex::new(Vec::new()));
+    let handler_events = events.clone();
+    event_loop
+        .run(move |event, control_flow| match event {
+            Event::ClipboardChanged { .. } | Event::ClipboardOwnershipLost { .. } => {
+                handler_events.lock().unwrap().push(event)
+            }
+            Event::MainEventsCleared => *control_flow = ControlFlow::Exit,
+            _ => {}
+        })
+        .unwrap();
+    assert_eq!(
+        *events.lock().unwrap(),
+        vec![
+            Event::ClipboardOwnershipLost {
+                selection: Selection::Clipboard
+            },
+            Event::ClipboardChanged {
+                selection: Selection::Clipboard
+            },
+            Event::ClipboardChanged {
+                selection: Selection::Primary
+            },
+        ]
+    );
+    assert_eq!(
+        clipboard::load(&event_loop, mime::TEXT_PLAIN).unwrap(),
+        Some(b"theirs".to_vec())
+    );
+}
+
+#[test]
+fn backend_selection() {
+    // The backend given to the builder wins over the environment
+    std::env::set_var("MOVING_BACKEND", "x11");
+    let event_loop = EventLoopBuilder::new()
+        .with_backend(Backend::Headless)
+        .build()
+        .unwrap();
+    assert_eq!(event_loop.backend(), Backend::Headless);
+    assert!(headless::is_active(&event_loop));
+
+    std::env::set_var("MOVING_BACKEND", "mir");
+    assert!(matches!(EventLoop::new(), Err(OSError::Other(_))));
+    std::env::set_var("MOVING_BACKEND", "headless");
+    assert_eq!(EventLoop::new().unwrap().backend(), Backend::Headless);
+
+    // Without a display server the error is reported instead of falling back to headless
+    std::env::remove_var("MOVING_BACKEND");
+    std::env::remove_var("DISPLAY");
+    std::env::remove_var("WAYLAND_DISPLAY");
+    assert!(EventLoop::new().is_err());
+}
+
+/// Runs `event_loop` until a window is asked to close, returns the window and clipboard events
+#[cfg(feature = "record")]
+fn run_until_close_requested(event_loop: &EventLoop) -> Vec<Event> {
+    let events = Arc::new(Mutex::new(Vec::new()));
+    let handler_events = events.clone();
+    event_loop
+        .run(move |event, control_flow| match event {
+            Event::WindowEvent { event: e, .. } => {
+                handler_events.lock().unwrap().push(event);
+                if e == WindowEvent::CloseRequested {
+                    *control_flow = ControlFlow::Exit;
+                }
+            }
+            Event::ClipboardChanged { .. } | Event::ClipboardOwnershipLost { .. } => {
+                handler_events.lock().unwrap().push(event)
+            }
+            _ => {}
+        })
+        .unwrap();
+    let events = events.lock().unwrap().clone();
+    events
+}
+
+#[cfg(feature = "record")]
+#[test]
+fn recorded_events_are_replayed() {
+    use moving::clipboard::{mime, Selection};
+
+    let path = std::env::temp_dir().join(format!("moving-recording-{}", std::process::id()));
+
+    let event_loop = headless_loop();
+    let window = WindowBuilder::new()
+        .with_size(2.0, 2.0)
+        .build(&event_loop)
+        .unwrap();
+    event_loop.record_to(&path).unwrap();
+    let resize = WindowEvent::Resize {
+        width: 4.0,
+        height: 4.0,
+    };
+    headless::push_window_event(&window, resize).unwrap();
+    headless::store_as_other_client(&event_loop, Selection::Primary, mime::TEXT_PLAIN, b"theirs")
+        .unwrap();
+    headless::push_window_event(&window, WindowEvent::CloseRequested).unwrap();
+    let recorded = run_until_close_requested(&event_loop);
+    assert_eq!(recorded.len(), 3);
+
+    // The window of the new session takes the place of the recorded one
+    let event_loop = headless_loop();
+    let replay_window = WindowBuilder::new()
+        .with_size(2.0, 2.0)
+        .build(&event_loop)
+        .unwrap();
+    event_loop.replay_from(&path).unwrap();
+    let replayed = run_until_close_requested(&event_loop);
+    std::fs::remove_file(&path).unwrap();
+    let expected: Vec<Event> = recorded
+        .into_iter()
+        .map(|event| match event {
+            Event::WindowEvent { event, .. } => Event::WindowEvent {
+                window: replay_window.id(),
+                event,
+            },
+            event => event,
+        })
+        .collect();
+    assert_eq!(replayed, expected);
+}
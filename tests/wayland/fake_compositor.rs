@@ -0,0 +1,179 @@
+//! A Wayland compositor that only knows the requests needed by the tests, it announces the
+//! globals the library requires and answers the synchronizations, the other requests are read
+//! and ignored
+
+use std::{
+    collections::BTreeMap,
+    fs,
+    io::{Read, Write},
+    net::Shutdown,
+    os::unix::net::{UnixListener, UnixStream},
+    path::{Path, PathBuf},
+    sync::{Arc, Mutex},
+    thread,
+};
+
+const WL_DISPLAY: u32 = 1;
+const WL_DISPLAY_SYNC: u16 = 0;
+const WL_DISPLAY_GET_REGISTRY: u16 = 1;
+const WL_DISPLAY_DELETE_ID: u16 = 1;
+const WL_REGISTRY_BIND: u16 = 0;
+const WL_REGISTRY_GLOBAL: u16 = 0;
+const WL_CALLBACK_DONE: u16 = 0;
+const WL_SHM_FORMAT: u16 = 0;
+const WL_SEAT_CAPABILITIES: u16 = 0;
+
+/// The globals announced to every client, all in version 1
+const GLOBALS: &[&str] = &[
+    "wl_shm",
+    "wl_compositor",
+    "wl_subcompositor",
+    "xdg_wm_base",
+    "wl_seat",
+    "wl_data_device_manager",
+];
+
+/// `argb8888` and `xrgb8888`, the formats every compositor supports
+const SHM_FORMATS: &[u32] = &[0, 1];
+/// The seat has a pointer and no keyboard
+const SEAT_POINTER: u32 = 1;
+
+/// A compositor listening on a socket of its own until it's dropped
+pub struct FakeCompositor {
+    directory: PathBuf,
+    clients: Arc<Mutex<Vec<UnixStream>>>,
+}
+
+impl FakeCompositor {
+    /// Listens on `wayland-0` in a new directory, to be used as `XDG_RUNTIME_DIR`
+    pub fn start() -> Self {
+        let directory = (0..100)
+            .map(|i| {
+                std::env::temp_dir().join(format!("moving-wayland-{}-{}", std::process::id(), i))
+            })
+            .find(|directory| fs::create_dir(directory).is_ok())
+            .expect("no free directory");
+        let listener = UnixListener::bind(directory.join("wayland-0")).unwrap();
+        let clients = Arc::new(Mutex::new(Vec::new()));
+        let server_clients = clients.clone();
+        thread::spawn(move || {
+            for stream in listener.incoming() {
+                let stream = match stream {
+                    Ok(stream) => stream,
+                    Err(_) => break,
+                };
+                server_clients
+                    .lock()
+                    .unwrap()
+                    .push(stream.try_clone().unwrap());
+                thread::spawn(move || serve(stream));
+            }
+        });
+        Self { directory, clients }
+    }
+
+    /// The value of `XDG_RUNTIME_DIR` that connects to this compositor, with `WAYLAND_DISPLAY`
+    /// unset or set to `wayland-0`
+    pub fn runtime_dir(&self) -> &Path {
+        &self.directory
+    }
+}
+
+impl Drop for FakeCompositor {
+    fn drop(&mut self) {
+        let _ = fs::remove_dir_all(&self.directory);
+        for client in self.clients.lock().unwrap().iter() {
+            let _ = client.shutdown(Shutdown::Both);
+        }
+    }
+}
+
+/// Builds a message to `object`, `args` are already encoded
+fn message(object: u32, opcode: u16, args: &[u8]) -> Vec<u8> {
+    let size = 8 + args.len() as u32;
+    let mut message = Vec::new();
+    message.extend_from_slice(&object.to_ne_bytes());
+    message.extend_from_slice(&(size << 16 | opcode as u32).to_ne_bytes());
+    message.extend_from_slice(args);
+    message
+}
+
+/// Encodes a string argument, with its terminating nul and its padding
+fn string(value: &str) -> Vec<u8> {
+    let mut encoded = Vec::new();
+    encoded.extend_from_slice(&(value.len() as u32 + 1).to_ne_bytes());
+    encoded.extend_from_slice(value.as_bytes());
+    encoded.push(0);
+    encoded.resize((encoded.len() + 3) & !3, 0);
+    encoded
+}
+
+fn u32_at(args: &[u8], offset: usize) -> u32 {
+    let mut bytes = [0; 4];
+    bytes.copy_from_slice(&args[offset..offset + 4]);
+    u32::from_ne_bytes(bytes)
+}
+
+/// The file descriptors sent with the requests are closed unread, no request needs them
+fn serve(mut stream: UnixStream) {
+    // The interface of the objects whose requests are answered, by id
+    let mut objects = BTreeMap::new();
+    objects.insert(WL_DISPLAY, "wl_display");
+    loop {
+        let mut header = [0; 8];
+        if stream.read_exact(&mut header).is_err() {
+            break;
+        }
+        let object = u32_at(&header, 0);
+        let size_opcode = u32_at(&header, 4);
+        let opcode = size_opcode as u16;
+        let mut args = vec![0; (size_opcode >> 16) as usize - 8];
+        if stream.read_exact(&mut args).is_err() {
+            break;
+        }
+        let mut reply = Vec::new();
+        match (objects.get(&object).copied(), opcode) {
+            (Some("wl_display"), WL_DISPLAY_SYNC) => {
+                let callback = u32_at(&args, 0);
+                reply.extend(message(callback, WL_CALLBACK_DONE, &0u32.to_ne_bytes()));
+                reply.extend(message(
+                    WL_DISPLAY,
+                    WL_DISPLAY_DELETE_ID,
+                    &callback.to_ne_bytes(),
+                ));
+            }
+            (Some("wl_display"), WL_DISPLAY_GET_REGISTRY) => {
+                let registry = u32_at(&args, 0);
+                objects.insert(registry, "wl_registry");
+                for (name, interface) in GLOBALS.iter().enumerate() {
+                    let mut global = (name as u32 + 1).to_ne_bytes().to_vec();
+                    global.extend(string(interface));
+                    global.extend_from_slice(&1u32.to_ne_bytes());
+                    reply.extend(message(registry, WL_REGISTRY_GLOBAL, &global));
+                }
+            }
+            (Some("wl_registry"), WL_REGISTRY_BIND) => {
+                // The name, the interface as a string, the version and the new id
+                let name = u32_at(&args, 0) as usize;
+                let interface_len = (u32_at(&args, 4) as usize + 3) & !3;
+                let id = u32_at(&args, 8 + interface_len + 4);
+                match GLOBALS.get(name.wrapping_sub(1)).copied() {
+                    Some("wl_shm") => {
+                        for format in SHM_FORMATS {
+                            reply.extend(message(id, WL_SHM_FORMAT, &format.to_ne_bytes()));
+                        }
+                    }
+                    Some("wl_seat") => {
+                        let capabilities = SEAT_POINTER.to_ne_bytes();
+                        reply.extend(message(id, WL_SEAT_CAPABILITIES, &capabilities));
+                    }
+                    _ => {}
+                }
+            }
+            _ => {}
+        }
+        if stream.write_all(&reply).is_err() {
+            break;
+        }
+    }
+}
@@ -0,0 +1,67 @@
+#![cfg(all(feature = "wayland", feature = "windows", target_os = "linux"))]
+
+mod fake_compositor;
+
+use fake_compositor::FakeCompositor;
+use moving::{
+    event::{ButtonState, Event, MouseButton, WindowEvent},
+    event_loop::{Backend, EventLoopBuilder},
+    window::WindowBuilder,
+};
+use std::{sync::Mutex, time::Duration};
+
+// The compositor is chosen through the environment of the process so the tests can't run in
+// parallel
+static SERIAL: Mutex<()> = Mutex::new(());
+
+/// Starts a compositor and points `XDG_RUNTIME_DIR` and `WAYLAND_DISPLAY` to it
+fn start_compositor() -> FakeCompositor {
+    let compositor = FakeCompositor::start();
+    std::env::set_var("XDG_RUNTIME_DIR", compositor.runtime_dir());
+    std::env::set_var("WAYLAND_DISPLAY", "wayland-0");
+    compositor
+}
+
+#[test]
+fn injected_input_skips_the_pointer_tracking() {
+    let _serial = SERIAL.lock().unwrap();
+    let _compositor = start_compositor();
+    let event_loop = EventLoopBuilder::new()
+        .with_backend(Backend::Wayland)
+        .build()
+        .unwrap();
+    let window = WindowBuilder::new()
+        .with_size(4.0, 3.0)
+        .with_decorations(false)
+        .build(&event_loop)
+        .unwrap();
+
+    // The pointer of the compositor never entered a surface of the client
+    let injected = vec![
+        WindowEvent::MouseMove { x: 1.0, y: 2.0 },
+        WindowEvent::MouseButton {
+            x: 1.0,
+            y: 2.0,
+            state: ButtonState::Pressed,
+            button: MouseButton::Left,
+        },
+    ];
+    let proxy = event_loop.create_proxy();
+    for event in &injected {
+        proxy
+            .inject(Event::WindowEvent {
+                window: window.id(),
+                event: *event,
+            })
+            .unwrap();
+    }
+
+    let mut received = Vec::new();
+    event_loop.pump_events(Some(Duration::from_secs(0)), |event, _| {
+        if let Event::WindowEvent { window: id, event } = event {
+            assert_eq!(id, window.id());
+            received.push(event);
+        }
+    });
+    assert_eq!(received, injected);
+}
@@ -0,0 +1,466 @@
+//! An X11 server that only knows the requests needed by the tests, it answers the requests that
+//! have a reply and records all of them so the tests can check what the clients sent
+
+// Each test uses a part of the server
+#![allow(dead_code)]
+
+use std::{
+    collections::BTreeMap,
+    fs,
+    io::{Read, Write},
+    net::Shutdown,
+    os::unix::net::{UnixListener, UnixStream},
+    path::PathBuf,
+    sync::{Arc, Condvar, Mutex},
+    thread,
+    time::{Duration, Instant},
+};
+
+pub const INTERN_ATOM: u8 = 16;
+pub const GET_ATOM_NAME: u8 = 17;
+pub const CHANGE_PROPERTY: u8 = 18;
+pub const DELETE_PROPERTY: u8 = 19;
+pub const GET_PROPERTY: u8 = 20;
+pub const SET_SELECTION_OWNER: u8 = 22;
+pub const GET_SELECTION_OWNER: u8 = 23;
+pub const CONVERT_SELECTION: u8 = 24;
+pub const SEND_EVENT: u8 = 25;
+pub const GET_INPUT_FOCUS: u8 = 43;
+pub const QUERY_EXTENSION: u8 = 98;
+pub const CHANGE_WINDOW_ATTRIBUTES: u8 = 2;
+pub const CREATE_WINDOW: u8 = 1;
+
+/// The major opcode of the only extension, XTEST
+pub const XTEST: u8 = 132;
+pub const XTEST_FAKE_INPUT: u8 = 2;
+
+pub const BUTTON_PRESS: u8 = 4;
+pub const BUTTON_RELEASE: u8 = 5;
+pub const MOTION_NOTIFY: u8 = 6;
+pub const PROPERTY_NOTIFY: u8 = 28;
+pub const SELECTION_CLEAR: u8 = 29;
+pub const SELECTION_REQUEST: u8 = 30;
+pub const SELECTION_NOTIFY: u8 = 31;
+pub const DESTROY_NOTIFY: u8 = 17;
+
+/// Largest request in 4 bytes units, small so the big transfers are incremental
+pub const MAX_REQUEST_LENGTH: u16 = 64;
+
+/// The atoms predefined by the protocol that the library uses as constants
+const PREDEFINED_ATOMS: &[(&str, u32)] = &[("PRIMARY", 1), ("ATOM", 4), ("STRING", 31)];
+
+#[derive(Debug, Clone)]
+pub struct Request {
+    pub client: usize,
+    pub opcode: u8,
+    /// The second byte of the request
+    pub data: u8,
+    /// The request without its header
+    pub body: Vec<u8>,
+}
+
+impl Request {
+    pub fn u32_at(&self, offset: usize) -> u32 {
+        let bytes = [
+            self.body[offset],
+            self.body[offset + 1],
+            self.body[offset + 2],
+            self.body[offset + 3],
+        ];
+        u32::from_le_bytes(bytes)
+    }
+}
+
+#[derive(Debug, Clone)]
+struct Property {
+    type_: u32,
+    format: u8,
+    value: Vec<u8>,
+}
+
+#[derive(Debug, Default)]
+struct State {
+    atoms: BTreeMap<String, u32>,
+    selection_owners: BTreeMap<u32, u32>,
+    /// By window and name
+    properties: BTreeMap<(u32, u32), Property>,
+    /// Gets the input synthesized with XTEST
+    pointer_window: u32,
+    pointer: (i16, i16),
+    clients: Vec<Client>,
+    requests: Vec<Request>,
+}
+
+#[derive(Debug)]
+struct Client {
+    stream: UnixStream,
+    /// Sequence number of the last request
+    sequence: u16,
+}
+
+impl State {
+    fn intern(&mut self, name: &str) -> u32 {
+        let next = 0x100 + self.atoms.len() as u32;
+        *self.atoms.entry(name.to_owned()).or_insert(next)
+    }
+
+    /// Sends a reply to the last request of `client`, `body` follows the length of the reply
+    fn reply(&mut self, client: usize, data: u8, body: &[u8], extra: &[u8]) {
+        let client = &mut self.clients[client];
+        let mut reply = vec![1, data];
+        reply.extend_from_slice(&client.sequence.to_le_bytes());
+        reply.extend_from_slice(&((extra.len() / 4) as u32).to_le_bytes());
+        reply.extend_from_slice(body);
+        reply.resize(32, 0);
+        reply.extend_from_slice(extra);
+        // The client is gone if it fails, its thread notices it
+        let _ = client.stream.write_all(&reply);
+    }
+
+    fn send_event(&mut self, client: usize, event: [u8; 32]) {
+        let client = &mut self.clients[client];
+        let mut event = event;
+        event[2..4].copy_from_slice(&client.sequence.to_le_bytes());
+        let _ = client.stream.write_all(&event);
+    }
+}
+
+/// A server listening on a display of its own until it's dropped
+pub struct FakeServer {
+    display: u32,
+    path: PathBuf,
+    state: Arc<(Mutex<State>, Condvar)>,
+}
+
+impl FakeServer {
+    pub fn start() -> Self {
+        let directory = PathBuf::from("/tmp/.X11-unix");
+        fs::create_dir_all(&directory).unwrap();
+        let (display, path, listener) = (90..200)
+            .find_map(|display| {
+                let path = directory.join(format!("X{}", display));
+                if path.exists() {
+                    return None;
+                }
+                let listener = UnixListener::bind(&path).ok()?;
+                Some((display, path, listener))
+            })
+            .expect("no free display");
+        let mut state = State::default();
+        for (name, atom) in PREDEFINED_ATOMS {
+            state.atoms.insert((*name).to_owned(), *atom);
+        }
+        let state = Arc::new((Mutex::new(state), Condvar::new()));
+        let server_state = state.clone();
+        thread::spawn(move || {
+            for stream in listener.incoming() {
+                let stream = match stream {
+                    Ok(stream) => stream,
+                    Err(_) => break,
+                };
+                let state = server_state.clone();
+                thread::spawn(move || serve(stream, &state));
+            }
+        });
+        Self {
+            display,
+            path,
+            state,
+        }
+    }
+
+    /// The value of `DISPLAY` that connects to this server
+    pub fn display_name(&self) -> String {
+        format!(":{}", self.display)
+    }
+
+    pub fn atom(&self, name: &str) -> u32 {
+        self.state.0.lock().unwrap().intern(name)
+    }
+
+    /// Waits for the first request accepted by `filter` since the beginning, it's removed from
+    /// the recorded requests
+    pub fn wait_request(&self, mut filter: impl FnMut(&Request) -> bool) -> Request {
+        let deadline = Instant::now() + Duration::from_secs(5);
+        let (state, condvar) = &*self.state;
+        let mut state = state.lock().unwrap();
+        loop {
+            if let Some(index) = state.requests.iter().position(&mut filter) {
+                return state.requests.remove(index);
+            }
+            let now = Instant::now();
+            assert!(now < deadline, "the request never came");
+            state = condvar.wait_timeout(state, deadline - now).unwrap().0;
+        }
+    }
+
+    /// Returns the requests received until now that `filter` accepts
+    pub fn requests(&self, mut filter: impl FnMut(&Request) -> bool) -> Vec<Request> {
+        let state = self.state.0.lock().unwrap();
+        state
+            .requests
+            .iter()
+            .filter(|request| filter(request))
+            .cloned()
+            .collect()
+    }
+
+    pub fn send_event(&self, client: usize, event: [u8; 32]) {
+        self.state.0.lock().unwrap().send_event(client, event);
+    }
+
+    /// Makes `owner`, a window of no client, the owner of `selection`
+    pub fn set_selection_owner(&self, selection: u32, owner: u32) {
+        let mut state = self.state.0.lock().unwrap();
+        state.selection_owners.insert(selection, owner);
+    }
+
+    /// Makes `window` the one under the pointer, at the origin of the screen
+    pub fn set_pointer_window(&self, window: u32) {
+        self.state.0.lock().unwrap().pointer_window = window;
+    }
+
+    /// Writes a property as a client that isn't connected would
+    pub fn set_property(&self, window: u32, property: u32, type_: u32, format: u8, value: &[u8]) {
+        let property_value = Property {
+            type_,
+            format,
+            value: value.to_owned(),
+        };
+        let mut state = self.state.0.lock().unwrap();
+        state.properties.insert((window, property), property_value);
+    }
+
+    /// Closes the connection of `client` as a crashed server would
+    pub fn disconnect(&self, client: usize) {
+        let state = self.state.0.lock().unwrap();
+        let _ = state.clients[client].stream.shutdown(Shutdown::Both);
+    }
+}
+
+impl Drop for FakeServer {
+    fn drop(&mut self) {
+        let _ = fs::remove_file(&self.path);
+        let state = self.state.0.lock().unwrap();
+        for client in &state.clients {
+            let _ = client.stream.shutdown(Shutdown::Both);
+        }
+    }
+}
+
+/// Builds an event whose first bytes are `code` and whose fields after the sequence number are
+/// `fields`
+pub fn event(code: u8, detail: u8, fields: &[u32]) -> [u8; 32] {
+    let mut event = [0; 32];
+    event[0] = code;
+    event[1] = detail;
+    for (i, field) in fields.iter().enumerate() {
+        event[4 + i * 4..8 + i * 4].copy_from_slice(&field.to_le_bytes());
+    }
+    event
+}
+
+fn pad(len: usize) -> usize {
+    (len + 3) & !3
+}
+
+fn serve(mut stream: UnixStream, state: &(Mutex<State>, Condvar)) {
+    let mut setup = [0; 12];
+    if stream.read_exact(&mut setup).is_err() {
+        return;
+    }
+    assert_eq!(setup[0], b'l', "only little endian clients are supported");
+    let auth_len = pad(u16::from_le_bytes([setup[6], setup[7]]) as usize)
+        + pad(u16::from_le_bytes([setup[8], setup[9]]) as usize);
+    let mut auth = vec![0; auth_len];
+    if stream.read_exact(&mut auth).is_err() {
+        return;
+    }
+
+    let client = {
+        let mut state = state.0.lock().unwrap();
+        state.clients.push(Client {
+            stream: stream.try_clone().unwrap(),
+            sequence: 0,
+        });
+        state.clients.len() - 1
+    };
+    let resource_base = (client as u32 + 1) << 21;
+    let vendor = b"fake";
+    let mut reply = Vec::new();
+    reply.extend_from_slice(&[1, 0]);
+    reply.extend_from_slice(&11u16.to_le_bytes());
+    reply.extend_from_slice(&0u16.to_le_bytes());
+    reply.extend_from_slice(&21u16.to_le_bytes());
+    reply.extend_from_slice(&0u32.to_le_bytes()); // release
+    reply.extend_from_slice(&resource_base.to_le_bytes());
+    reply.extend_from_slice(&0x1f_ffffu32.to_le_bytes());
+    reply.extend_from_slice(&0u32.to_le_bytes()); // motion buffer
+    reply.extend_from_slice(&(vendor.len() as u16).to_le_bytes());
+    reply.extend_from_slice(&MAX_REQUEST_LENGTH.to_le_bytes());
+    // One screen, one format, LSB first images and bitmaps, 32 bits scanlines, keycodes 8-255
+    reply.extend_from_slice(&[1, 1, 0, 0, 32, 32, 8, 255, 0, 0, 0, 0]);
+    reply.extend_from_slice(vendor);
+    reply.extend_from_slice(&[24, 32, 32, 0, 0, 0, 0, 0]);
+    let root = 1u32;
+    for value in &[root, 2, 0xff_ffff, 0, 0] {
+        reply.extend_from_slice(&value.to_le_bytes());
+    }
+    for value in &[640u16, 480, 160, 120, 1, 1] {
+        reply.extend_from_slice(&value.to_le_bytes());
+    }
+    reply.extend_from_slice(&0u32.to_le_bytes()); // root visual
+    reply.extend_from_slice(&[0, 0, 24, 0]);
+    stream.write_all(&reply).unwrap();
+
+    loop {
+        let mut header = [0; 4];
+        if stream.read_exact(&mut header).is_err() {
+            break;
+        }
+        let len = u16::from_le_bytes([header[2], header[3]]) as usize * 4;
+        let mut body = vec![0; len.saturating_sub(4)];
+        if stream.read_exact(&mut body).is_err() {
+            break;
+        }
+        let request = Request {
+            client,
+            opcode: header[0],
+            data: header[1],
+            body,
+        };
+        let (lock, condvar) = state;
+        let mut state = lock.lock().unwrap();
+        state.clients[client].sequence = state.clients[client].sequence.wrapping_add(1);
+        answer(&mut state, &request);
+        state.requests.push(request);
+        condvar.notify_all();
+    }
+}
+
+fn answer(state: &mut State, request: &Request) {
+    let client = request.client;
+    match request.opcode {
+        INTERN_ATOM => {
+            let len = u16::from_le_bytes([request.body[0], request.body[1]]) as usize;
+            let name = String::from_utf8_lossy(&request.body[4..4 + len]).into_owned();
+            let atom = state.intern(&name);
+            state.reply(client, 0, &atom.to_le_bytes(), &[]);
+        }
+        GET_ATOM_NAME => {
+            let atom = request.u32_at(0);
+            let name = state
+                .atoms
+                .iter()
+                .find(|(_, value)| **value == atom)
+                .map_or_else(String::new, |(name, _)| name.clone());
+            let mut extra = name.into_bytes();
+            let len = extra.len() as u16;
+            extra.resize(pad(extra.len()), 0);
+            state.reply(client, 0, &len.to_le_bytes(), &extra);
+        }
+        CHANGE_PROPERTY => {
+            let (window, property) = (request.u32_at(0), request.u32_at(4));
+            let format = request.body[12];
+            let len = request.u32_at(16) as usize * (format as usize / 8);
+            let mut value = request.body[20..20 + len].to_vec();
+            let stored = state
+                .properties
+                .entry((window, property))
+                .or_insert_with(|| Property {
+                    type_: 0,
+                    format,
+                    value: Vec::new(),
+                });
+            stored.type_ = request.u32_at(8);
+            stored.format = format;
+            match request.data {
+                // Prepend and append
+                1 => {
+                    value.extend_from_slice(&stored.value);
+                    stored.value = value;
+                }
+                2 => stored.value.extend(value),
+                _ => stored.value = value,
+            }
+        }
+        DELETE_PROPERTY => {
+            let (window, property) = (request.u32_at(0), request.u32_at(4));
+            state.properties.remove(&(window, property));
+        }
+        GET_PROPERTY => {
+            let (window, property) = (request.u32_at(0), request.u32_at(4));
+            let offset = request.u32_at(12) as usize * 4;
+            let length = request.u32_at(16) as usize * 4;
+            let stored = state.properties.get(&(window, property)).cloned();
+            let stored = stored.unwrap_or(Property {
+                type_: 0,
+                format: 0,
+                value: Vec::new(),
+            });
+            let start = offset.min(stored.value.len());
+            let end = (start + length).min(stored.value.len());
+            let bytes_after = (stored.value.len() - end) as u32;
+            // Deleted once it was read to its end
+            if request.data == 1 && bytes_after == 0 {
+                state.properties.remove(&(window, property));
+            }
+            let units = match stored.format {
+                0 => 0,
+                format => (end - start) as u32 / (format as u32 / 8),
+            };
+            let mut body = Vec::new();
+            for value in &[stored.type_, bytes_after, units] {
+                body.extend_from_slice(&value.to_le_bytes());
+            }
+            let mut extra = stored.value[start..end].to_vec();
+            extra.resize(pad(extra.len()), 0);
+            state.reply(client, stored.format, &body, &extra);
+        }
+        SET_SELECTION_OWNER => {
+            let (owner, selection) = (request.u32_at(0), request.u32_at(4));
+            state.selection_owners.insert(selection, owner);
+        }
+        GET_SELECTION_OWNER => {
+            let selection = request.u32_at(0);
+            let owner = state.selection_owners.get(&selection).copied().unwrap_or(0);
+            state.reply(client, 0, &owner.to_le_bytes(), &[]);
+        }
+        SEND_EVENT => {
+            // The event goes to the client that created the destination
+            let destination = request.u32_at(0);
+            let receiver = (destination >> 21) as usize;
+            if receiver >= 1 && receiver <= state.clients.len() {
+                let mut event = [0; 32];
+                event.copy_from_slice(&request.body[8..40]);
+                event[0] |= 0x80;
+                state.send_event(receiver - 1, event);
+            }
+        }
+        GET_INPUT_FOCUS => state.reply(client, 0, &1u32.to_le_bytes(), &[]),
+        QUERY_EXTENSION => {
+            let len = u16::from_le_bytes([request.body[0], request.body[1]]) as usize;
+            let reply = match &request.body[4..4 + len] {
+                b"XTEST" => [1, XTEST, 0, 0],
+                _ => [0; 4],
+            };
+            state.reply(client, 0, &reply, &[]);
+        }
+        // The input goes to the client that synthesized it
+        XTEST if request.data == XTEST_FAKE_INPUT => {
+            let (type_, detail) = (request.body[0], request.body[1]);
+            if type_ == MOTION_NOTIFY {
+                let x = i16::from_le_bytes([request.body[20], request.body[21]]);
+                let y = i16::from_le_bytes([request.body[22], request.body[23]]);
+                state.pointer = (x, y);
+            }
+            let (x, y) = state.pointer;
+            let position = (x as u16 as u32) | ((y as u16 as u32) << 16);
+            // Time, root, event window, child, root and window positions, then the state with
+            // the same screen flag
+            let fields = [0, 1, state.pointer_window, 0, position, position, 1 << 16];
+            state.send_event(client, event(type_, detail, &fields));
+        }
+        _ => {}
+    }
+}
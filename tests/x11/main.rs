@@ -0,0 +1,391 @@
+#![cfg(all(feature = "x11", target_os = "linux"))]
+
+mod fake_server;
+
+use fake_server::FakeServer;
+use moving::{
+    error::OSError,
+    event::Event,
+    event_loop::{Backend, ControlFlow, EventLoopBuilder},
+};
+use std::{
+    sync::{Arc, Mutex},
+    time::{Duration, Instant},
+};
+
+// The display is chosen through the environment of the process so the tests can't run in
+// parallel
+static SERIAL: Mutex<()> = Mutex::new(());
+
+/// Starts a server and points `DISPLAY` to it
+fn start_server() -> FakeServer {
+    let server = FakeServer::start();
+    std::env::set_var("DISPLAY", server.display_name());
+    std::env::set_var("XAUTHORITY", "/nonexistent");
+    server
+}
+
+#[test]
+fn lost_connection_is_reported() {
+    let _serial = SERIAL.lock().unwrap();
+    let server = start_server();
+    let event_loop = EventLoopBuilder::new()
+        .with_backend(Backend::X11)
+        .build()
+        .unwrap();
+    server.disconnect(0);
+
+    let received = Arc::new(Mutex::new(Vec::new()));
+    let handler_received = received.clone();
+    let result = event_loop.run(move |event, control_flow| {
+        if let Event::DisplayLost = event {
+            *control_flow = ControlFlow::Exit;
+        }
+        handler_received.lock().unwrap().push(event);
+    });
+    assert!(matches!(result, Err(OSError::DisplayLost)));
+    assert!(matches!(
+        received.lock().unwrap().last(),
+        Some(Event::DisplayLost)
+    ));
+}
+
+#[cfg(feature = "clipboard")]
+#[test]
+fn events_read_with_a_reply_end_the_wait() {
+    let _serial = SERIAL.lock().unwrap();
+    let server = start_server();
+    let event_loop = EventLoopBuilder::new()
+        .with_backend(Backend::X11)
+        .build()
+        .unwrap();
+    event_loop.pump_events(Some(Duration::from_secs(0)), |_, _| {});
+
+    // The event comes before the reply to the request of the clipboard so libxcb reads both
+    let selection_clear = fake_server::event(
+        fake_server::SELECTION_CLEAR,
+        0,
+        &[0, 0, server.atom("CLIPBOARD")],
+    );
+    server.send_event(0, selection_clear);
+    assert!(moving::clipboard::available_types(&event_loop)
+        .unwrap()
+        .is_empty());
+
+    let start = Instant::now();
+    event_loop.pump_events(Some(Duration::from_secs(5)), |_, _| {});
+    assert!(start.elapsed() < Duration::from_secs(2));
+}
+
+#[cfg(feature = "clipboard")]
+#[test]
+fn clipboard_thread_serves_the_selections() {
+    use moving::clipboard::{self, mime};
+
+    let _serial = SERIAL.lock().unwrap();
+    let server = start_server();
+    let event_loop = EventLoopBuilder::new()
+        .with_backend(Backend::X11)
+        .with_clipboard_thread()
+        .build()
+        .unwrap();
+    clipboard::store(&event_loop, mime::TEXT_PLAIN_UTF_8, b"served").unwrap();
+    // The second client is the connection of the thread
+    let owner = server
+        .wait_request(|r| r.client == 1 && r.opcode == fake_server::SET_SELECTION_OWNER)
+        .u32_at(0);
+
+    // Answered while the event loop doesn't run
+    let (requestor, property) = (0x7000_0001, server.atom("RECEIVED"));
+    let selection_request = fake_server::event(
+        fake_server::SELECTION_REQUEST,
+        0,
+        &[
+            0,
+            owner,
+            requestor,
+            server.atom("CLIPBOARD"),
+            server.atom("UTF8_STRING"),
+            property,
+        ],
+    );
+    server.send_event(1, selection_request);
+    let change = server.wait_request(|r| {
+        r.client == 1 && r.opcode == fake_server::CHANGE_PROPERTY && r.u32_at(0) == requestor
+    });
+    assert_eq!(change.u32_at(4), property);
+    assert_eq!(&change.body[20..26], b"served");
+    let notify = server.wait_request(|r| r.client == 1 && r.opcode == fake_server::SEND_EVENT);
+    assert_eq!(notify.u32_at(0), requestor);
+
+    // The failure of the thread ends the event loop
+    server.disconnect(1);
+    let deadline = Instant::now() + Duration::from_secs(5);
+    let mut lost = false;
+    while !lost && Instant::now() < deadline {
+        event_loop.pump_events(Some(Duration::from_millis(10)), |event, _| {
+            lost |= matches!(event, Event::DisplayLost);
+        });
+    }
+    assert!(lost);
+    assert!(matches!(
+        event_loop.take_error(),
+        Some(OSError::DisplayLost)
+    ));
+}
+
+#[cfg(feature = "clipboard")]
+#[test]
+fn string_requests_get_latin1_text() {
+    use moving::clipboard::{self, mime};
+
+    let _serial = SERIAL.lock().unwrap();
+    let server = start_server();
+    let event_loop = EventLoopBuilder::new()
+        .with_backend(Backend::X11)
+        .with_clipboard_thread()
+        .build()
+        .unwrap();
+    clipboard::store(&event_loop, mime::TEXT_PLAIN_UTF_8, "é€".as_bytes()).unwrap();
+    let owner = server
+        .wait_request(|r| r.client == 1 && r.opcode == fake_server::SET_SELECTION_OWNER)
+        .u32_at(0);
+
+    let (requestor, property) = (0x7000_0001, server.atom("RECEIVED"));
+    let selection_request = fake_server::event(
+        fake_server::SELECTION_REQUEST,
+        0,
+        &[
+            0,
+            owner,
+            requestor,
+            server.atom("CLIPBOARD"),
+            server.atom("STRING"),
+            property,
+        ],
+    );
+    server.send_event(1, selection_request);
+    let change = server.wait_request(|r| {
+        r.client == 1 && r.opcode == fake_server::CHANGE_PROPERTY && r.u32_at(0) == requestor
+    });
+    assert_eq!(change.u32_at(8), server.atom("STRING"));
+    assert_eq!(change.u32_at(16), 2);
+    assert_eq!(&change.body[20..22], b"\xe9?");
+}
+
+#[cfg(feature = "clipboard")]
+#[test]
+fn incr_transfers_follow_the_requestor() {
+    use moving::clipboard::{self, mime};
+
+    const PROPERTY_CHANGE: u32 = 1 << 22;
+    const STRUCTURE_NOTIFY: u32 = 1 << 17;
+    // Larger than the chunks of the server
+    let data = (0..600).map(|i| i as u8).collect::<Vec<_>>();
+    let chunk = fake_server::MAX_REQUEST_LENGTH as usize * 4 - 24;
+
+    let _serial = SERIAL.lock().unwrap();
+    let server = start_server();
+    let event_loop = EventLoopBuilder::new()
+        .with_backend(Backend::X11)
+        .with_clipboard_thread()
+        .build()
+        .unwrap();
+    clipboard::store(&event_loop, mime::TEXT_PLAIN_UTF_8, &data).unwrap();
+    let owner = server
+        .wait_request(|r| r.client == 1 && r.opcode == fake_server::SET_SELECTION_OWNER)
+        .u32_at(0);
+    let property = server.atom("RECEIVED");
+    let request = |requestor| {
+        let selection_request = fake_server::event(
+            fake_server::SELECTION_REQUEST,
+            0,
+            &[
+                0,
+                owner,
+                requestor,
+                server.atom("CLIPBOARD"),
+                server.atom("UTF8_STRING"),
+                property,
+            ],
+        );
+        server.send_event(1, selection_request);
+    };
+    let next_change = |requestor| {
+        server.wait_request(|r| {
+            r.client == 1 && r.opcode == fake_server::CHANGE_PROPERTY && r.u32_at(0) == requestor
+        })
+    };
+    let event_mask = |requestor| {
+        server
+            .wait_request(|r| {
+                r.client == 1
+                    && r.opcode == fake_server::CHANGE_WINDOW_ATTRIBUTES
+                    && r.u32_at(0) == requestor
+            })
+            .u32_at(8)
+    };
+    let delete = |requestor| {
+        let property_notify = fake_server::event(
+            fake_server::PROPERTY_NOTIFY,
+            0,
+            &[requestor, property, 0, 1],
+        );
+        server.send_event(1, property_notify);
+    };
+
+    // The size comes first, each deletion of the property asks for the next chunk
+    let requestor = 0x7000_0001;
+    request(requestor);
+    assert_eq!(event_mask(requestor), PROPERTY_CHANGE | STRUCTURE_NOTIFY);
+    let incr = next_change(requestor);
+    assert_eq!(incr.u32_at(8), server.atom("INCR"));
+    assert_eq!(incr.u32_at(20), data.len() as u32);
+    let mut received = Vec::new();
+    loop {
+        delete(requestor);
+        let change = next_change(requestor);
+        let len = change.u32_at(16) as usize;
+        if len == 0 {
+            break;
+        }
+        assert!(len <= chunk);
+        received.extend_from_slice(&change.body[20..20 + len]);
+    }
+    assert_eq!(received, data);
+    // The requestor isn't listened to after the empty chunk
+    assert_eq!(event_mask(requestor), 0);
+
+    // The transfer to a destroyed requestor is dropped
+    let destroyed = 0x7000_0002;
+    request(destroyed);
+    next_change(destroyed);
+    let destroy_notify =
+        fake_server::event(fake_server::DESTROY_NOTIFY, 0, &[destroyed, destroyed]);
+    server.send_event(1, destroy_notify);
+    delete(destroyed);
+    // Requests are handled in order so the next transfer comes after what the deletion caused
+    request(requestor);
+    next_change(requestor);
+    assert!(server
+        .requests(|r| r.opcode == fake_server::CHANGE_PROPERTY && r.u32_at(0) == destroyed)
+        .is_empty());
+    // Nothing is sent to the window once it's gone
+    assert_eq!(event_mask(destroyed), PROPERTY_CHANGE | STRUCTURE_NOTIFY);
+    assert!(server
+        .requests(|r| r.opcode == fake_server::CHANGE_WINDOW_ATTRIBUTES && r.u32_at(0) == destroyed)
+        .is_empty());
+}
+
+/// Runs `future` on the current thread, parking it until the future is woken
+#[cfg(feature = "async")]
+fn block_on<F: std::future::Future>(future: F) -> F::Output {
+    use std::task::{Context, Poll, Wake, Waker};
+
+    struct Unparker(std::thread::Thread);
+
+    impl Wake for Unparker {
+        fn wake(self: Arc<Self>) {
+            self.0.unpark();
+        }
+    }
+
+    let waker = Waker::from(Arc::new(Unparker(std::thread::current())));
+    let mut cx = Context::from_waker(&waker);
+    let mut future = Box::pin(future);
+    loop {
+        if let Poll::Ready(output) = future.as_mut().poll(&mut cx) {
+            return output;
+        }
+        std::thread::park();
+    }
+}
+
+#[cfg(all(feature = "clipboard", feature = "async"))]
+#[test]
+fn async_loads_wait_for_the_owner() {
+    use moving::clipboard::{self, mime, Selection};
+
+    let _serial = SERIAL.lock().unwrap();
+    let server = start_server();
+    server.set_selection_owner(server.atom("CLIPBOARD"), 0x7000_0001);
+    let event_loop = EventLoopBuilder::new()
+        .with_backend(Backend::X11)
+        .build()
+        .unwrap();
+
+    // The owner answers after the task started to wait
+    let load = clipboard::load_async(&event_loop, Selection::Clipboard, mime::TEXT_PLAIN_UTF_8);
+    let data = std::thread::scope(|scope| {
+        scope.spawn(|| {
+            let convert = server.wait_request(|r| r.opcode == fake_server::CONVERT_SELECTION);
+            let (requestor, selection, target, property) = (
+                convert.u32_at(0),
+                convert.u32_at(4),
+                convert.u32_at(8),
+                convert.u32_at(12),
+            );
+            assert_eq!(target, server.atom("text/plain"));
+            std::thread::sleep(Duration::from_millis(20));
+            server.set_property(requestor, property, target, 8, b"moving");
+            let selection_notify = fake_server::event(
+                fake_server::SELECTION_NOTIFY,
+                0,
+                &[0, requestor, selection, target, property],
+            );
+            server.send_event(0, selection_notify);
+        });
+        block_on(load).unwrap()
+    });
+    assert_eq!(data.as_deref(), Some(&b"moving"[..]));
+    // The property was read and deleted
+    assert!(server
+        .requests(|r| r.opcode == fake_server::GET_PROPERTY && r.data == 1)
+        .iter()
+        .any(|r| r.u32_at(4) == server.atom("CLIPBOARD_RECEIVER")));
+}
+
+#[cfg(all(feature = "automation", feature = "windows"))]
+#[test]
+fn synthesized_input_comes_back_as_window_events() {
+    use moving::{
+        automation,
+        event::{ButtonState, MouseButton, WindowEvent},
+    };
+
+    let _serial = SERIAL.lock().unwrap();
+    let server = start_server();
+    server.set_pointer_window(0x7000_0001);
+    let event_loop = EventLoopBuilder::new()
+        .with_backend(Backend::X11)
+        .build()
+        .unwrap();
+
+    automation::move_pointer(&event_loop, 10, 20).unwrap();
+    automation::click(&event_loop, MouseButton::Right).unwrap();
+    automation::type_keys(&event_loop, &[38]).unwrap();
+    automation::sync(&event_loop).unwrap();
+    let mut received = Vec::new();
+    event_loop.pump_events(Some(Duration::from_secs(0)), |event, _| {
+        if let Event::WindowEvent { event, .. } = event {
+            received.push(event);
+        }
+    });
+    let button = |state| WindowEvent::MouseButton {
+        x: 10.0,
+        y: 20.0,
+        button: MouseButton::Right,
+        state,
+    };
+    let key = |state| WindowEvent::Key { keycode: 38, state };
+    assert_eq!(
+        received,
+        [
+            WindowEvent::MouseMove { x: 10.0, y: 20.0 },
+            button(ButtonState::Pressed),
+            button(ButtonState::Released),
+            key(ButtonState::Pressed),
+            key(ButtonState::Released),
+        ]
+    );
+}